@@ -0,0 +1,199 @@
+use std::{collections::VecDeque, num::NonZeroUsize};
+
+use crate::{
+    shared_context::SharedContext, shared_rw_context::SharedRwContext,
+    snapshot_context::SnapshotContext,
+};
+
+/// Decides how often a history takes a full snapshot of its context.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckpointInterval {
+    /// Take a snapshot after every `n` executed commands.
+    Commands(NonZeroUsize),
+    /// Take a snapshot once the summed `cost()` of the commands executed since the last snapshot
+    /// reaches the given amount.
+    Cost(NonZeroUsize),
+}
+
+/// Configuration for periodic context checkpoints.
+///
+/// # Fields
+///
+/// * `interval` - How often a snapshot is taken.
+/// * `weight` - How many history slots a single stored snapshot occupies. Snapshots count toward
+///   the history limit, so older commands are evicted earlier the more snapshots are held.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CheckpointConfig {
+    pub interval: CheckpointInterval,
+    pub weight: usize,
+}
+
+impl CheckpointConfig {
+    #[must_use]
+    pub fn new(interval: CheckpointInterval, weight: usize) -> Self {
+        Self { interval, weight }
+    }
+}
+
+/// A context that can be captured into a standalone snapshot and restored from it in place.
+///
+/// This is what [`ConcurrentCommandHistory`](crate::concurrent_command_history::ConcurrentCommandHistory)
+/// needs to checkpoint contexts that are only ever handed out by shared reference. Cloning a
+/// [`SharedContext`] only clones the handle, so its implementation deep-copies the wrapped value.
+pub trait Checkpoint {
+    /// Returns an independent copy of the current state.
+    #[must_use]
+    fn capture(&self) -> Self;
+
+    /// Overwrites the current state with the state held by `snapshot`.
+    fn restore(&self, snapshot: &Self);
+}
+
+impl<T: Clone> Checkpoint for SharedContext<T> {
+    fn capture(&self) -> Self {
+        SharedContext::new(self.lock().clone())
+    }
+
+    fn restore(&self, snapshot: &Self) {
+        let value = snapshot.lock().clone();
+        *self.lock() = value;
+    }
+}
+
+impl<T: Clone> Checkpoint for SharedRwContext<T> {
+    fn capture(&self) -> Self {
+        SharedRwContext::new(self.read().clone())
+    }
+
+    fn restore(&self, snapshot: &Self) {
+        let value = snapshot.read().clone();
+        *self.write() = value;
+    }
+}
+
+/// Snapshots of a [`SnapshotContext`] share the published value instead of copying it.
+impl<T> Checkpoint for SnapshotContext<T> {
+    fn capture(&self) -> Self {
+        SnapshotContext::from(self.load())
+    }
+
+    fn restore(&self, snapshot: &Self) {
+        self.replace(snapshot.load());
+    }
+}
+
+/// Bookkeeping for the snapshots held by a history.
+///
+/// Snapshots are keyed by absolute position: the number of commands applied since the history
+/// was created, which stays stable while the oldest commands are evicted.
+pub(crate) struct Checkpoints<S> {
+    config: CheckpointConfig,
+    snapshots: VecDeque<(usize, S)>,
+    since_last: usize,
+    capture: fn(&S) -> S,
+}
+
+impl<S> Checkpoints<S> {
+    pub(crate) fn new(config: CheckpointConfig, capture: fn(&S) -> S) -> Self {
+        Self {
+            config,
+            snapshots: VecDeque::new(),
+            since_last: 0,
+            capture,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    /// The number of history slots currently taken up by snapshots.
+    pub(crate) fn weight(&self) -> usize {
+        self.snapshots.len() * self.config.weight
+    }
+
+    pub(crate) fn capture(&self, ctx: &S) -> S {
+        (self.capture)(ctx)
+    }
+
+    /// Records a freshly executed command and returns whether a snapshot is due.
+    pub(crate) fn record(&mut self, cost: usize) -> bool {
+        let (amount, threshold) = match self.config.interval {
+            CheckpointInterval::Commands(every) => (1, every.get()),
+            CheckpointInterval::Cost(every) => (cost, every.get()),
+        };
+
+        self.since_last = self.since_last.saturating_add(amount);
+        if self.since_last >= threshold {
+            self.since_last = 0;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub(crate) fn insert(&mut self, position: usize, snapshot: S) {
+        self.truncate_after(position);
+        if self
+            .snapshots
+            .back()
+            .is_some_and(|(last, _)| *last == position)
+        {
+            self.snapshots.pop_back();
+        }
+
+        self.snapshots.push_back((position, snapshot));
+    }
+
+    /// Drops every snapshot taken after `position`, e.g. because the timeline branched there.
+    pub(crate) fn truncate_after(&mut self, position: usize) {
+        while self
+            .snapshots
+            .back()
+            .is_some_and(|(last, _)| *last > position)
+        {
+            self.snapshots.pop_back();
+        }
+    }
+
+    /// Drops every snapshot taken before `position`, e.g. because the commands leading away from
+    /// it were evicted.
+    pub(crate) fn prune_before(&mut self, position: usize) {
+        while self
+            .snapshots
+            .front()
+            .is_some_and(|(first, _)| *first < position)
+        {
+            self.snapshots.pop_front();
+        }
+    }
+
+    /// Re-keys the snapshots after the `count` steps leading away from `start` were merged into a
+    /// single one: the snapshots taken inside the merged range no longer match a reachable state
+    /// and are dropped, and the ones taken at or before `start` move up by `count - 1`.
+    pub(crate) fn merge(&mut self, start: usize, count: usize) {
+        let end = start + count;
+        self.snapshots
+            .retain(|(position, _)| *position <= start || *position >= end);
+
+        for (position, _) in &mut self.snapshots {
+            if *position <= start {
+                *position += count - 1;
+            }
+        }
+    }
+
+    pub(crate) fn pop_oldest(&mut self) -> bool {
+        self.snapshots.pop_front().is_some()
+    }
+
+    /// Returns the latest snapshot taken at or before `target`, but not before `lower`.
+    pub(crate) fn nearest(&self, lower: usize, target: usize) -> Option<(usize, &S)> {
+        self.snapshots
+            .iter()
+            .rev()
+            .find(|(position, _)| *position <= target)
+            .filter(|(position, _)| *position >= lower)
+            .map(|(position, snapshot)| (*position, snapshot))
+    }
+}
@@ -0,0 +1,52 @@
+use std::num::NonZeroUsize;
+
+use crate::history_entry::{EntryMetadata, HistoryEntry};
+
+/// How the oldest entries are merged once the history limit is reached, instead of being dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompactionMode {
+    /// Merge the oldest entries into one compound entry that undoes and redoes all of their
+    /// commands as a single step. Keeps every command, so it bounds the number of entries but not
+    /// the memory they use.
+    Compound,
+    /// Replace the oldest entries with the context states before and after them. Only two
+    /// snapshots are kept no matter how many commands were merged, so memory stays bounded.
+    Snapshot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Compaction {
+    pub(crate) mode: CompactionMode,
+    pub(crate) batch: usize,
+}
+
+impl Compaction {
+    /// Merging fewer than two entries would never shrink the stack, so smaller batches are raised.
+    pub(crate) fn new(mode: CompactionMode, batch: NonZeroUsize) -> Self {
+        Self {
+            mode,
+            batch: batch.get().max(2),
+        }
+    }
+}
+
+/// Builds the metadata of an entry merged from `entries`, given oldest first: tags are combined
+/// and the author is kept only if all entries agree on it.
+pub(crate) fn merged_metadata<C, S>(entries: &[HistoryEntry<C, S>]) -> EntryMetadata {
+    let mut metadata = EntryMetadata::default();
+
+    let mut authors = entries.iter().map(HistoryEntry::author);
+    if let Some(first) = authors.next() {
+        if authors.all(|author| author == first) {
+            metadata.author = first.map(str::to_owned);
+        }
+    }
+
+    for tag in entries.iter().flat_map(HistoryEntry::tags) {
+        if !metadata.tags.contains(tag) {
+            metadata.tags.push(tag.clone());
+        }
+    }
+
+    metadata
+}
@@ -1,21 +1,129 @@
 use std::{
+    borrow::Cow,
     collections::VecDeque,
+    mem,
     num::NonZeroUsize,
+    panic::{self, AssertUnwindSafe},
+    path::PathBuf,
     sync::{
-        atomic::{AtomicBool, AtomicUsize, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, OnceLock,
     },
+    thread::{self, ThreadId},
+    time::{Duration, Instant, SystemTime},
 };
 
-use parking_lot::RwLock;
+use crate::{
+    checkpoint::{Checkpoint, CheckpointConfig, Checkpoints},
+    compaction::{merged_metadata, Compaction, CompactionMode},
+    error::HistoryError,
+    history_entry::{EntryMetadata, EntryPayload, HistoryEntry},
+    invariant::Invariant,
+    mirror::{Mirror, MirrorDivergence, MirrorId},
+    panic_policy::{self, Failure, PanicPolicy, PanicStage},
+    replay::{replay_len, ReplayDivergence},
+    retention::{EntryInfo, RetentionPolicy},
+    scope::{self, Scope},
+    shared_context::{ContextLock, Versioned},
+    spill::{Spill, SpillStore},
+    sync::{self, Mutex, MutexGuard, RwLock},
+    tentative::{Tentative, Timer},
+    traits::{command::Command, command_history::CommandHistory},
+};
+
+/// The entry type stored by [`ConcurrentCommandHistory`].
+pub type ConcurrentEntry<C> = HistoryEntry<Arc<C>, Arc<<C as Command>::Context>>;
+
+type Stack<C> = VecDeque<ConcurrentEntry<C>>;
+/// A lifecycle hook waiting to be called on a command dropped by the running step.
+type Retired<C> = (Arc<C>, fn(&C));
+
+/// A command of the history running on `thread`.
+struct Frame<C> {
+    thread: ThreadId,
+    /// The commands issued through the history from inside the running command, or `None` while
+    /// an entry is undone, redone or replayed, since its children are replayed by the history.
+    children: Option<Vec<Arc<C>>>,
+}
+
+/// Marks the current thread as running a command of the history until dropped.
+struct Running<'a, C: Command + Send + Sync> {
+    history: &'a ConcurrentCommandHistory<C>,
+}
+
+impl<C: Command + Send + Sync> Running<'_, C> {
+    /// Takes the children recorded so far.
+    fn take_children(&self) -> Vec<Arc<C>> {
+        self.history
+            .with_frame(|frame| frame.children.take())
+            .flatten()
+            .unwrap_or_default()
+    }
+}
+
+impl<C: Command + Send + Sync> Drop for Running<'_, C> {
+    fn drop(&mut self) {
+        let thread = thread::current().id();
+        let mut frames = self.history.frames.lock();
+        if let Some(index) = frames.iter().rposition(|frame| frame.thread == thread) {
+            frames.remove(index);
+        }
+    }
+}
+
+/// Holds the step lock until dropped, and then runs the lifecycle hooks of the commands the step
+/// dropped, once the stacks are released.
+struct Step<'a, C: Command + Send + Sync> {
+    history: &'a ConcurrentCommandHistory<C>,
+    _guard: MutexGuard<'a, ()>,
+}
 
-use crate::traits::{command::Command, command_history::CommandHistory};
+impl<C: Command + Send + Sync> Drop for Step<'_, C> {
+    fn drop(&mut self) {
+        let retired = mem::take(&mut *self.history.retired.lock());
+        if retired.is_empty() {
+            return;
+        }
+
+        let _running = self.history.enter(false);
+        for (command, hook) in retired {
+            hook(&command);
+        }
+    }
+}
+
+/// How snapshot entries capture and restore the context, taken from its [`Checkpoint`] impl.
+struct SnapshotOps<T> {
+    capture: fn(&T) -> T,
+    restore: fn(&T, &T),
+}
 
 pub struct ConcurrentCommandHistory<C: Command + Send + Sync> {
-    undo: RwLock<VecDeque<Arc<C>>>,
-    redo: RwLock<VecDeque<Arc<C>>>,
+    /// Held for the whole of every step that changes the stacks, so commands run one at a time
+    /// while the stacks themselves are only locked to read or update them.
+    step: Mutex<()>,
+    undo: RwLock<Stack<C>>,
+    redo: RwLock<Stack<C>>,
     history_limit: AtomicUsize,
     clear_redo_on_execute: AtomicBool,
+    evicted: AtomicUsize,
+    checkpoints: Mutex<Option<Checkpoints<C::Context>>>,
+    retention: RwLock<Option<Box<dyn RetentionPolicy>>>,
+    compaction: RwLock<Option<Compaction>>,
+    snapshot_ops: OnceLock<SnapshotOps<C::Context>>,
+    version_of: OnceLock<fn(&C::Context) -> u64>,
+    lockable_by: OnceLock<fn(&C::Context, Instant) -> bool>,
+    pending: Mutex<Option<String>>,
+    retired: Mutex<Vec<Retired<C>>>,
+    timer: OnceLock<Arc<Timer<C>>>,
+    spill: Mutex<Option<SpillStore<C>>>,
+    spill_error: Mutex<Option<HistoryError>>,
+    scopes: Mutex<Vec<Scope<ConcurrentEntry<C>, C::Context>>>,
+    panic_policy: RwLock<PanicPolicy>,
+    invariant: RwLock<Option<Box<dyn Invariant<C::Context>>>>,
+    mirrors: Mutex<Vec<Mirror<C::Context>>>,
+    next_mirror: AtomicU64,
+    frames: Mutex<Vec<Frame<C>>>,
 }
 
 impl<C> ConcurrentCommandHistory<C>
@@ -27,493 +135,3372 @@ where
         let limit = history_limit.get();
 
         Arc::new(Self {
+            step: Mutex::new(()),
             undo: RwLock::new(VecDeque::with_capacity(limit)),
             redo: RwLock::new(VecDeque::with_capacity(limit)),
             history_limit: AtomicUsize::new(limit),
             clear_redo_on_execute: AtomicBool::new(clear_redo_on_execute),
+            evicted: AtomicUsize::new(0),
+            checkpoints: Mutex::new(None),
+            retention: RwLock::new(None),
+            compaction: RwLock::new(None),
+            snapshot_ops: OnceLock::new(),
+            version_of: OnceLock::new(),
+            lockable_by: OnceLock::new(),
+            pending: Mutex::new(None),
+            retired: Mutex::new(Vec::new()),
+            timer: OnceLock::new(),
+            spill: Mutex::new(None),
+            spill_error: Mutex::new(None),
+            scopes: Mutex::new(Vec::new()),
+            panic_policy: RwLock::new(PanicPolicy::default()),
+            invariant: RwLock::new(None),
+            mirrors: Mutex::new(Vec::new()),
+            next_mirror: AtomicU64::new(0),
+            frames: Mutex::new(Vec::new()),
         })
     }
 
+    /// Returns the undone-able commands, most recent first. Commands merged into compound entries
+    /// are listed individually, commands compacted into snapshots are not listed.
     pub fn undo_history(&self) -> Option<Vec<Arc<C>>> {
         let undo_lock = self.undo.read();
         if undo_lock.is_empty() {
             return None;
         }
 
-        Some(undo_lock.iter().cloned().collect())
+        Some(
+            undo_lock
+                .iter()
+                .flat_map(|entry| entry.commands().iter().rev().cloned())
+                .collect(),
+        )
     }
 
+    /// Returns the redo-able commands, next to be redone first. Commands merged into compound
+    /// entries are listed individually, commands compacted into snapshots are not listed.
     pub fn redo_history(&self) -> Option<Vec<Arc<C>>> {
         let redo_lock = self.redo.read();
         if redo_lock.is_empty() {
             return None;
         }
 
+        Some(
+            redo_lock
+                .iter()
+                .flat_map(|entry| entry.commands().iter().cloned())
+                .collect(),
+        )
+    }
+
+    /// Returns a copy of the undo stack entries, most recent first, including their metadata.
+    pub fn undo_entries(&self) -> Option<Vec<ConcurrentEntry<C>>> {
+        let undo_lock = self.undo.read();
+        if undo_lock.is_empty() {
+            return None;
+        }
+
+        Some(undo_lock.iter().cloned().collect())
+    }
+
+    /// Returns a copy of the redo stack entries, next to be redone first, including their metadata.
+    pub fn redo_entries(&self) -> Option<Vec<ConcurrentEntry<C>>> {
+        let redo_lock = self.redo.read();
+        if redo_lock.is_empty() {
+            return None;
+        }
+
         Some(redo_lock.iter().cloned().collect())
     }
 
-    fn push_undo(
+    /// Calls `f` with the undo entry at `index` (0 is the most recent) under the undo lock.
+    ///
+    /// # Returns
+    ///
+    /// `false` if there is no entry at `index`.
+    pub fn update_undo_entry<F>(&self, index: usize, f: F) -> bool
+    where
+        F: FnOnce(&mut ConcurrentEntry<C>),
+    {
+        self.undo.write().get_mut(index).map(f).is_some()
+    }
+
+    /// Calls `f` with the redo entry at `index` (0 is the next to be redone) under the redo lock.
+    ///
+    /// # Returns
+    ///
+    /// `false` if there is no entry at `index`.
+    pub fn update_redo_entry<F>(&self, index: usize, f: F) -> bool
+    where
+        F: FnOnce(&mut ConcurrentEntry<C>),
+    {
+        self.redo.write().get_mut(index).map(f).is_some()
+    }
+
+    /// Executes `command` if the history is not busy and the retention policy admits it, without
+    /// blocking.
+    ///
+    /// [`execute_command`](CommandHistory::execute_command) waits for the history and silently
+    /// drops refused commands, this reports them instead. Only the locks of the history are
+    /// covered unless [`enable_context_deadlines`](Self::enable_context_deadlines) was called, the
+    /// same holds for the other non-blocking and deadline-bounded operations.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`HistoryError::Busy`] if another thread is using the history or its context, and
+    /// [`HistoryError::Refused`] if the retention policy does not admit the command. The command
+    /// is not executed in either case.
+    ///
+    /// Returns [`HistoryError::Panicked`] if the command panicked and the [`PanicPolicy`] caught
+    /// it. The command is not recorded in that case.
+    ///
+    /// Returns [`HistoryError::InvariantViolated`] if the command broke the invariant. It is undone
+    /// and not recorded in that case.
+    pub fn try_execute_command(&self, command: C, ctx: &C::Context) -> Result<(), HistoryError> {
+        self.execute_until(command, ctx, EntryMetadata::default(), Some(Instant::now()))
+    }
+
+    /// Like [`try_execute_command`](Self::try_execute_command), but waits up to `timeout` for the
+    /// history to become available.
+    ///
+    /// # Errors
+    ///
+    /// The same as [`try_execute_command`](Self::try_execute_command).
+    pub fn execute_command_for(
         &self,
-        command: Arc<C>,
-        undo_lock: &mut parking_lot::RwLockWriteGuard<VecDeque<Arc<C>>>,
-    ) {
-        let limit = self.history_limit.load(Ordering::Relaxed);
-        while undo_lock.len() >= limit {
-            undo_lock.pop_back();
+        command: C,
+        ctx: &C::Context,
+        timeout: Duration,
+    ) -> Result<(), HistoryError> {
+        self.execute_until(
+            command,
+            ctx,
+            EntryMetadata::default(),
+            sync::deadline_after(timeout),
+        )
+    }
+
+    /// Executes `command` and records it together with `metadata`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`HistoryError::Refused`] if the retention policy does not admit the command. The
+    /// command is not executed in that case.
+    ///
+    /// Returns [`HistoryError::Panicked`] if the command panicked and the [`PanicPolicy`] caught
+    /// it. The command is not recorded in that case.
+    ///
+    /// Returns [`HistoryError::InvariantViolated`] if the command broke the invariant. It is undone
+    /// and not recorded in that case.
+    pub fn execute_with_metadata(
+        &self,
+        command: C,
+        ctx: &C::Context,
+        metadata: EntryMetadata,
+    ) -> Result<(), HistoryError> {
+        self.execute_until(command, ctx, metadata, None)
+    }
+
+    /// Executes `command` on `ctx` without recording it, and reverts it on a background thread
+    /// unless it is confirmed within `timeout`, like a "keep these settings?" dialog.
+    ///
+    /// The command is only recorded once [`Tentative::confirm`] is called. Reverting it,
+    /// explicitly, on expiry or by dropping the handle, undoes it on a clone of `ctx` and discards
+    /// it. The context therefore has to be a handle to shared state, such as a
+    /// [`SharedContext`](crate::shared_context::SharedContext); with a plain value, the revert
+    /// would only change the clone.
+    ///
+    /// The first call starts a timer thread that serves every tentative command of the history
+    /// and exits once the history is dropped.
+    ///
+    /// Until then, the history refuses to execute, undo or redo anything else with
+    /// [`HistoryError::Pending`], and to close scopes, so the command is always confirmed or
+    /// reverted on top of the state it was applied to. The infallible operations do nothing in
+    /// that case.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`HistoryError::Reentrant`] when called from inside a command, and
+    /// [`HistoryError::Pending`] while another tentative command is pending. Otherwise the same
+    /// as [`try_execute_command`](Self::try_execute_command), except that it waits for the
+    /// history; no handle is returned if the command was not executed.
+    pub fn execute_tentative(
+        self: &Arc<Self>,
+        command: C,
+        ctx: &C::Context,
+        timeout: Duration,
+    ) -> Result<Tentative<C>, HistoryError>
+    where
+        C: 'static,
+        C::Context: Clone + Send + Sync + 'static,
+    {
+        if self.is_reentrant() {
+            return Err(HistoryError::Reentrant);
         }
 
-        undo_lock.push_front(command);
+        let step = self.begin_step();
+        let applied = self.apply_locked(command, EntryMetadata::default(), ctx);
+        if let Ok(entry) = &applied {
+            *self.pending.lock() = Some(Self::describe(entry));
+        }
+        drop(step);
+
+        let entry = applied.map_err(|failure| failure.settle(self.panic_policy()))?;
+        let timer = self
+            .timer
+            .get_or_init(|| Timer::spawn(Arc::downgrade(self)));
+        Ok(Tentative::start(
+            Arc::clone(self),
+            Arc::clone(timer),
+            entry,
+            ctx.clone(),
+            timeout,
+        ))
     }
 
-    fn push_redo(
+    /// Executes `command`, giving up with [`HistoryError::Busy`] if the history is not available
+    /// by `deadline`. `None` waits as long as it takes.
+    fn execute_until(
         &self,
-        command: Arc<C>,
-        redo_lock: &mut parking_lot::RwLockWriteGuard<VecDeque<Arc<C>>>,
-    ) {
-        let limit = self.history_limit.load(Ordering::Relaxed);
-        while redo_lock.len() >= limit {
-            redo_lock.pop_back();
+        command: C,
+        ctx: &C::Context,
+        metadata: EntryMetadata,
+        deadline: Option<Instant>,
+    ) -> Result<(), HistoryError> {
+        if self.is_reentrant() {
+            self.execute_child(command, ctx);
+            return Ok(());
         }
 
-        redo_lock.push_front(command);
+        self.execute_unsettled(command, ctx, metadata, deadline)
+            .map_err(|failure| failure.settle(self.panic_policy()))
     }
 
-    pub fn set_clear_redo_on_execute(&self, clear: bool) {
-        self.clear_redo_on_execute.store(clear, Ordering::Relaxed);
+    fn execute_unsettled(
+        &self,
+        command: C,
+        ctx: &C::Context,
+        metadata: EntryMetadata,
+        deadline: Option<Instant>,
+    ) -> Result<(), Failure> {
+        // Holding the step lock while the command runs keeps the recorded order the same as the
+        // order in which commands were applied to the context.
+        let _step = self.lock_step(deadline)?;
+        self.wait_for_context(ctx, deadline)?;
+        self.execute_locked(command, metadata, ctx)
     }
-}
 
-impl<C> CommandHistory<C> for ConcurrentCommandHistory<C>
-where
-    C: Command + Send + Sync,
-{
-    fn execute_command(&self, command: C, ctx: &C::Context) {
-        let command = Arc::new(command);
-        command.execute(ctx);
+    /// Sets the policy consulted after every push, in addition to the history limit, and applies
+    /// it right away.
+    pub fn set_retention_policy<P: RetentionPolicy + 'static>(&self, policy: P) {
+        *self.retention.write() = Some(Box::new(policy));
+        self.enforce_retention();
+    }
 
-        let mut undo = self.undo.write();
-        self.push_undo(command, &mut undo);
+    pub fn clear_retention_policy(&self) {
+        *self.retention.write() = None;
+    }
 
-        if self.clear_redo_on_execute.load(Ordering::Relaxed) {
-            self.redo.write().clear();
+    /// Applies the retention policy to both stacks now, e.g. to drop entries that aged out while
+    /// the history was idle.
+    ///
+    /// # Returns
+    ///
+    /// The number of evicted entries, always 0 while a scope is open or when called from inside a
+    /// command.
+    pub fn enforce_retention(&self) -> usize {
+        if self.is_reentrant() {
+            return 0;
         }
-    }
 
-    fn undo(&self, ctx: &C::Context) {
+        let _step = self.begin_step();
         let mut undo = self.undo.write();
-        if let Some(command) = undo.pop_front() {
-            command.undo(ctx);
+        if self.in_scope() {
+            return 0;
+        }
 
-            let mut redo = self.redo.write();
-            self.push_redo(command, &mut redo);
+        let undo_excess = self.retention_excess(&undo);
+        {
+            let mut checkpoints = self.checkpoints.lock();
+            for _ in 0..undo_excess {
+                self.evict_oldest_undo(&mut undo, &mut checkpoints);
+            }
         }
-    }
 
-    fn redo(&self, ctx: &C::Context) {
         let mut redo = self.redo.write();
-        if let Some(command) = redo.pop_front() {
-            command.redo(ctx);
+        let redo_excess = self.retention_excess(&redo);
+        let keep = redo.len() - redo_excess;
+        self.trim_redo(keep, undo.len(), &mut redo);
 
-            let mut undo = self.undo.write();
-            self.push_undo(command, &mut undo);
+        undo_excess + redo_excess
+    }
+
+    /// Re-executes the undo history onto `ctx` in chronological order (oldest first) using `redo`.
+    ///
+    /// The undo history is snapshotted first, so the history locks are not held while commands
+    /// run and the history itself is left untouched. Compacted snapshot entries are replayed by
+    /// restoring the state after them, as a single step covering all merged commands.
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - The baseline context to replay onto.
+    /// * `until` - Stop after this many commands. `None` replays the whole undo history. A
+    ///   snapshot entry that would cross `until` is not applied, so fewer commands may be
+    ///   replayed.
+    ///
+    /// # Returns
+    ///
+    /// The number of commands that were replayed.
+    pub fn replay(&self, ctx: &C::Context, until: Option<usize>) -> usize {
+        self.replay_with(ctx, until, |_, _, _| true)
+            .unwrap_or_default()
+    }
+
+    /// Like [`replay`](Self::replay), but calls `compare` after every replayed command and stops
+    /// at the first step it rejects.
+    ///
+    /// `compare` receives the chronological position of the command, the command itself and the
+    /// context after the command was re-applied, and returns `true` when the state is as expected.
+    /// For a compacted snapshot entry it receives `None` and the position of the last merged
+    /// command, after the state following the entry was restored.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`ReplayDivergence`] describing the first command after which `compare` returned
+    /// `false`. The context is left in the diverged state.
+    pub fn replay_with<F>(
+        &self,
+        ctx: &C::Context,
+        until: Option<usize>,
+        mut compare: F,
+    ) -> Result<usize, ReplayDivergence>
+    where
+        F: FnMut(usize, Option<&C>, &C::Context) -> bool,
+    {
+        let entries: Vec<_> = self.undo.read().iter().rev().cloned().collect();
+        let _running = self.enter(false);
+        let total = entries.iter().map(HistoryEntry::command_count).sum();
+        let count = replay_len(total, until);
+
+        let mut position = 0;
+        for entry in &entries {
+            if position >= count {
+                break;
+            }
+
+            if let EntryPayload::Snapshot { after, merged, .. } = entry.payload() {
+                if position + merged > count {
+                    break;
+                }
+
+                self.restore_snapshot(ctx, after);
+                position += merged;
+
+                if !compare(position - 1, None, ctx) {
+                    return Err(ReplayDivergence {
+                        position: position - 1,
+                        description: entry.group_description(),
+                    });
+                }
+                continue;
+            }
+
+            for command in entry.commands().iter().take(count - position) {
+                command.redo(ctx);
+
+                if !compare(position, Some(command), ctx) {
+                    return Err(ReplayDivergence {
+                        position,
+                        description: command.description().into_owned(),
+                    });
+                }
+
+                position += 1;
+            }
         }
+
+        Ok(position)
     }
 
-    fn set_history_limit(&self, limit: NonZeroUsize) {
-        assert!(limit.get() > 0);
-        let limit = limit.get();
+    /// Merges the oldest entries into compound entries once the history limit is reached, instead
+    /// of dropping them.
+    ///
+    /// # Arguments
+    ///
+    /// * `batch` - How many of the oldest entries are merged at a time. Values below 2 are raised
+    ///   to 2.
+    pub fn enable_compaction(&self, batch: NonZeroUsize) {
+        *self.compaction.write() = Some(Compaction::new(CompactionMode::Compound, batch));
+    }
 
-        self.history_limit.store(limit, Ordering::Release);
+    /// Replaces the oldest entries with snapshots of the context before and after them once the
+    /// history limit is reached, so the oldest state stays reachable with bounded memory.
+    ///
+    /// Building a snapshot undoes the undo stack on a captured copy of the context, so commands
+    /// must not have side effects outside their context.
+    ///
+    /// [`set_history_limit`](CommandHistory::set_history_limit) has no context to build
+    /// snapshots from, so after lowering the limit the undo stack stays above it until the next
+    /// command is executed or redone.
+    ///
+    /// # Arguments
+    ///
+    /// * `batch` - How many of the oldest entries are merged at a time. Values below 2 are raised
+    ///   to 2.
+    pub fn enable_snapshot_compaction(&self, batch: NonZeroUsize)
+    where
+        C::Context: Checkpoint,
+    {
+        let _ = self.snapshot_ops.set(SnapshotOps {
+            capture: C::Context::capture,
+            restore: C::Context::restore,
+        });
+        *self.compaction.write() = Some(Compaction::new(CompactionMode::Snapshot, batch));
+    }
 
-        let mut undo = self.undo.write();
-        while undo.len() > limit {
-            undo.pop_back();
+    /// Goes back to dropping the oldest entries. Entries that were already compacted are kept.
+    pub fn disable_compaction(&self) {
+        *self.compaction.write() = None;
+    }
+
+    /// Opens a child scope, e.g. for the edits made inside a modal dialog.
+    ///
+    /// Until the scope is committed or aborted, every operation on the history works on a fresh
+    /// undo/redo stack of its own, so undo only reaches the edits made inside the scope. Scopes
+    /// can be nested and are shared by all threads using the history. The scope is unbounded: the
+    /// history limit, checkpoints, compaction and retention policy are suspended until it is
+    /// closed. Limits and policies set inside the scope are not applied to it and are discarded
+    /// when it closes.
+    ///
+    /// # Returns
+    ///
+    /// `false` when called from inside a command, in which case no scope is opened.
+    pub fn begin_scope(&self) -> bool {
+        if self.is_reentrant() {
+            return false;
         }
 
+        let _step = self.begin_step();
+        let mut undo = self.undo.write();
         let mut redo = self.redo.write();
-        while redo.len() > limit {
-            redo.pop_back();
+
+        self.scopes.lock().push(Scope {
+            undo: mem::take(&mut *undo),
+            redo: mem::take(&mut *redo),
+            history_limit: self.history_limit.swap(usize::MAX, Ordering::AcqRel),
+            checkpoints: self.checkpoints.lock().take(),
+            retention: self.retention.write().take(),
+        });
+        true
+    }
+
+    /// Returns how many scopes are currently open.
+    pub fn scope_depth(&self) -> usize {
+        self.scopes.lock().len()
+    }
+
+    fn in_scope(&self) -> bool {
+        !self.scopes.lock().is_empty()
+    }
+
+    /// Closes the innermost scope and records everything it did as a single entry on the parent,
+    /// as if it had been executed there. The scope's redo stack is dropped.
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - The context the scope's commands were applied to.
+    ///
+    /// # Returns
+    ///
+    /// `false` if no scope is open, if a tentative command is pending, or when called from inside a
+    /// command.
+    pub fn commit_scope(&self, ctx: &C::Context) -> bool {
+        if self.is_reentrant() {
+            return false;
+        }
+
+        let _step = self.begin_step();
+        if self.ensure_settled().is_err() {
+            return false;
+        }
+        let child = self.close_scope(&mut self.undo.write(), &mut self.redo.write());
+        let Some(child) = child else {
+            return false;
+        };
+
+        if let Some(entry) = scope::fold(child) {
+            self.record_applied(entry, ctx);
         }
+
+        true
     }
 
-    fn batch_execute(&self, commands: Vec<C>, ctx: &C::Context) {
-        let mut undo = self.undo.write();
-        for command in commands {
-            let command = Arc::new(command);
-            command.execute(ctx);
+    /// Closes the innermost scope and reverts everything it did, leaving the parent as it was when
+    /// the scope was opened.
+    ///
+    /// # Returns
+    ///
+    /// `false` if no scope is open, if a tentative command is pending, or when called from inside a
+    /// command.
+    pub fn abort_scope(&self, ctx: &C::Context) -> bool {
+        if self.is_reentrant() {
+            return false;
+        }
 
-            self.push_undo(command, &mut undo);
+        let _step = self.begin_step();
+        if self.ensure_settled().is_err() {
+            return false;
         }
+        let child = self.close_scope(&mut self.undo.write(), &mut self.redo.write());
+        let Some(child) = child else {
+            return false;
+        };
 
-        if self.clear_redo_on_execute.load(Ordering::Relaxed) {
-            self.redo.write().clear();
+        let running = self.enter(false);
+        for entry in &child {
+            self.undo_payload(entry.payload(), ctx);
+            self.mirror_step(PanicStage::Undo, entry);
+            self.notify(entry, C::on_discarded);
         }
+        drop(running);
+        self.stamp_top(ctx, &mut self.undo.write());
+
+        true
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::{
-        borrow::Cow, cmp::min, hint::black_box, num::NonZero, panic::AssertUnwindSafe, thread, time,
-    };
+    /// Restores the parent state and returns the undo stack of the closed scope. The redo stack of
+    /// the scope is discarded.
+    fn close_scope(&self, undo: &mut Stack<C>, redo: &mut Stack<C>) -> Option<Stack<C>> {
+        let parent = self.scopes.lock().pop()?;
 
-    use rand::Rng;
+        for entry in mem::replace(redo, parent.redo) {
+            self.notify(&entry, C::on_discarded);
+        }
+        self.history_limit
+            .store(parent.history_limit, Ordering::Release);
+        *self.checkpoints.lock() = parent.checkpoints;
+        *self.retention.write() = parent.retention;
+        Some(mem::replace(undo, parent.undo))
+    }
 
-    use crate::shared_context::SharedContext;
+    /// Writes entries evicted from memory to the file at `path` instead of dropping them, and
+    /// loads them back when [`try_undo`](Self::try_undo) reaches them.
+    ///
+    /// The history limit and retention policy now decide how many entries stay in memory, and
+    /// compaction is suspended while spilling. The file is created or truncated now and removed
+    /// when spilling is disabled or the history is dropped. Replay, `go_to` and the history
+    /// listings only see the entries held in memory.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`HistoryError::SpillIo`] if the file cannot be created. Any previous spill file is
+    /// kept in that case.
+    pub fn enable_spill(&self, path: impl Into<PathBuf>) -> Result<(), HistoryError>
+    where
+        C: Spill,
+    {
+        let store = SpillStore::create(path.into())?;
+        *self.spill.lock() = Some(store);
+        Ok(())
+    }
 
-    use super::*;
+    /// Stops spilling and drops every spilled entry together with the file. The lifecycle hooks
+    /// of spilled commands are not called, since they only exist in the file.
+    pub fn disable_spill(&self) {
+        *self.spill.lock() = None;
+        *self.spill_error.lock() = None;
+    }
 
-    #[derive(Debug)]
-    enum TestOperation {
-        Increment(i32),
-        Decrement(i32),
+    /// Returns how many entries are currently held in the spill file.
+    pub fn spilled_len(&self) -> usize {
+        self.spill.lock().as_ref().map_or(0, SpillStore::len)
     }
 
-    struct TestArcCommand {
-        operation: TestOperation,
+    /// Returns the error of the last failed write to the spill file, if any.
+    ///
+    /// Writes happen while entries are evicted, which cannot report errors. A failed write drops
+    /// the entry and every entry spilled before it.
+    pub fn take_spill_error(&self) -> Option<HistoryError> {
+        self.spill_error.lock().take()
     }
 
-    struct TestArcContext {
-        value: i32,
+    /// Undoes the last command, loading it back from the spill file first if the in-memory undo
+    /// stack is empty. Gives up right away if another thread is using the history or, with
+    /// [`enable_context_deadlines`](Self::enable_context_deadlines), its context.
+    ///
+    /// [`undo`](CommandHistory::undo) does the same but waits for the history and ignores spill
+    /// errors.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`HistoryError::Busy`] if another thread is using the history, in which case
+    /// nothing changes.
+    ///
+    /// Returns [`HistoryError::SpillFileMissing`], [`HistoryError::SpillIo`] or
+    /// [`HistoryError::SpillCorrupted`] if the spilled entry cannot be read back. Nothing changes
+    /// in that case, so the call can be retried once the file is restored, or the spilled entries
+    /// given up with [`disable_spill`](Self::disable_spill).
+    ///
+    /// Returns [`HistoryError::Panicked`] if the command panicked and the [`PanicPolicy`] caught
+    /// it.
+    ///
+    /// Returns [`HistoryError::InvariantViolated`] if undoing broke the invariant. The command is
+    /// redone and stays on the undo stack in that case.
+    pub fn try_undo(&self, ctx: &C::Context) -> Result<(), HistoryError> {
+        self.undo_until(ctx, Some(Instant::now()))
     }
 
-    impl Command for TestArcCommand {
-        type Context = SharedContext<TestArcContext>;
-        fn execute(&self, ctx: &Self::Context) {
-            let mut ctx = ctx.lock();
-            match self.operation {
-                TestOperation::Increment(value) => ctx.value += value,
-                TestOperation::Decrement(value) => ctx.value -= value,
+    /// Like [`try_undo`](Self::try_undo), but waits up to `timeout` for the history to become
+    /// available.
+    ///
+    /// # Errors
+    ///
+    /// The same as [`try_undo`](Self::try_undo).
+    pub fn undo_for(&self, ctx: &C::Context, timeout: Duration) -> Result<(), HistoryError> {
+        self.undo_until(ctx, sync::deadline_after(timeout))
+    }
+
+    /// Redoes the last undone command if the history is not busy, without blocking.
+    ///
+    /// [`redo`](CommandHistory::redo) waits for the history and ignores caught panics.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`HistoryError::Busy`] if another thread is using the history, in which case
+    /// nothing changes.
+    ///
+    /// Returns [`HistoryError::Panicked`] if the command panicked and the [`PanicPolicy`] caught
+    /// it.
+    ///
+    /// Returns [`HistoryError::InvariantViolated`] if redoing broke the invariant. The command is
+    /// undone again and stays on the redo stack in that case.
+    pub fn try_redo(&self, ctx: &C::Context) -> Result<(), HistoryError> {
+        self.redo_until(ctx, Some(Instant::now()))
+    }
+
+    /// Like [`try_redo`](Self::try_redo), but waits up to `timeout` for the history to become
+    /// available.
+    ///
+    /// # Errors
+    ///
+    /// The same as [`try_redo`](Self::try_redo).
+    pub fn redo_for(&self, ctx: &C::Context, timeout: Duration) -> Result<(), HistoryError> {
+        self.redo_until(ctx, sync::deadline_after(timeout))
+    }
+
+    /// Makes the non-blocking and deadline-bounded operations, such as
+    /// [`try_undo`](Self::try_undo) or [`execute_command_for`](Self::execute_command_for), wait
+    /// for the context lock as well, so a UI thread never blocks behind another thread holding a
+    /// [`SharedContext`](crate::shared_context::SharedContext).
+    ///
+    /// Once no other step is running, these operations check that the context can be locked by
+    /// the same deadline and give up with [`HistoryError::Busy`] otherwise. The context is not
+    /// kept locked while the commands run, since they lock it themselves, so a thread that locks
+    /// it directly in between can still make them wait. Other steps of the history cannot, since
+    /// they wait for this one.
+    pub fn enable_context_deadlines(&self)
+    where
+        C::Context: ContextLock,
+    {
+        let _ = self.lockable_by.set(C::Context::lockable_by);
+    }
+
+    /// Gives up with [`HistoryError::Busy`] if context deadlines are enabled and the context
+    /// cannot be locked by `deadline`. `None` never gives up.
+    fn wait_for_context(
+        &self,
+        ctx: &C::Context,
+        deadline: Option<Instant>,
+    ) -> Result<(), HistoryError> {
+        match (self.lockable_by.get(), deadline) {
+            (Some(lockable_by), Some(deadline)) if !lockable_by(ctx, deadline) => {
+                Err(HistoryError::Busy)
             }
+            _ => Ok(()),
         }
+    }
 
-        fn undo(&self, ctx: &Self::Context) {
-            let mut ctx = ctx.lock();
-            match self.operation {
-                TestOperation::Increment(value) => ctx.value -= value,
-                TestOperation::Decrement(value) => ctx.value += value,
-            }
+    /// Records the context version on every entry as it becomes the next one to undo, which is
+    /// what [`undo_if_unchanged`](Self::undo_if_unchanged) compares against.
+    ///
+    /// Entries recorded before this call, and entries loaded back from the spill file, carry no
+    /// version until an undo or redo makes them the next entry to undo.
+    pub fn enable_version_checks(&self)
+    where
+        C::Context: Versioned,
+    {
+        let _ = self.version_of.set(C::Context::version);
+    }
+
+    /// Undoes the last command only if the context did not change since the history last touched
+    /// it, so an inverse computed for the old state is never applied to a state another thread
+    /// has moved on from.
+    ///
+    /// Requires [`enable_version_checks`](Self::enable_version_checks). No other step can run
+    /// between the check and the undo, but the context is not locked, so commands that change it
+    /// outside the history can still slip in between.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`HistoryError::Conflict`] if the context version differs from the one recorded
+    /// for the entry, or if the entry carries none. Nothing changes in that case.
+    ///
+    /// Returns [`HistoryError::Reentrant`] when called from inside a command, and
+    /// [`HistoryError::Panicked`] if the command panicked and the [`PanicPolicy`] caught it.
+    pub fn undo_if_unchanged(&self, ctx: &C::Context) -> Result<(), HistoryError>
+    where
+        C::Context: Versioned,
+    {
+        if self.is_reentrant() {
+            return Err(HistoryError::Reentrant);
         }
 
-        fn description(&self) -> Cow<'_, str> {
-            match self.operation {
-                TestOperation::Increment(value) => {
-                    Cow::Owned(format!("TestArcCommand: Increment({value})"))
-                }
-                TestOperation::Decrement(value) => {
-                    Cow::Owned(format!("TestArcCommand: Decrement({value})"))
-                }
+        let step = self.begin_step();
+        if let Some(entry) = self.undo.read().front() {
+            let current = ctx.version();
+            if entry.context_version() != Some(current) {
+                return Err(HistoryError::Conflict {
+                    description: Self::describe(entry),
+                    recorded: entry.context_version(),
+                    current,
+                });
             }
         }
+
+        let undone = self.undo_locked(ctx);
+        drop(step);
+        undone.map_err(|caught| caught.settle(self.panic_policy()))
     }
 
-    #[test]
-    fn test_arc_command() {
-        let history = ConcurrentCommandHistory::new(NonZeroUsize::new(5).unwrap(), true);
-        let ctx = SharedContext::new(TestArcContext { value: 0 });
-        let command = TestArcCommand {
-            operation: TestOperation::Increment(1),
-        };
+    fn undo_until(&self, ctx: &C::Context, deadline: Option<Instant>) -> Result<(), HistoryError> {
+        if self.is_reentrant() {
+            return Err(HistoryError::Reentrant);
+        }
 
-        history.execute_command(command, &ctx);
-        assert_eq!(ctx.lock().value, 1);
+        self.undo_unsettled(ctx, deadline)
+            .map_err(|failure| failure.settle(self.panic_policy()))
+    }
 
-        history.undo(&ctx);
-        assert_eq!(ctx.lock().value, 0);
+    fn redo_until(&self, ctx: &C::Context, deadline: Option<Instant>) -> Result<(), HistoryError> {
+        if self.is_reentrant() {
+            return Err(HistoryError::Reentrant);
+        }
 
-        history.redo(&ctx);
-        assert_eq!(ctx.lock().value, 1);
+        self.redo_unsettled(ctx, deadline)
+            .map_err(|failure| failure.settle(self.panic_policy()))
     }
 
-    #[test]
-    fn test_arc_max_size() {
-        let history = ConcurrentCommandHistory::new(NonZero::new(2).unwrap(), true);
-        let ctx = SharedContext::new(TestArcContext { value: 0 });
-        let command1 = TestArcCommand {
-            operation: TestOperation::Increment(1),
-        };
-        let command2 = TestArcCommand {
-            operation: TestOperation::Increment(2),
-        };
-        let command3 = TestArcCommand {
-            operation: TestOperation::Increment(3),
-        };
+    /// Fails with [`HistoryError::Pending`] while a tentative command waits for confirmation.
+    fn ensure_settled(&self) -> Result<(), HistoryError> {
+        match self.pending.lock().as_ref() {
+            Some(description) => Err(HistoryError::Pending {
+                description: description.clone(),
+            }),
+            None => Ok(()),
+        }
+    }
 
-        history.execute_command(command1, &ctx);
-        assert_eq!(ctx.lock().value, 1);
-        assert_eq!(history.undo.read().len(), 1);
-        assert!(history.redo.read().is_empty());
+    /// Takes the step lock, waiting for the running step to finish.
+    fn begin_step(&self) -> Step<'_, C> {
+        Step {
+            history: self,
+            _guard: self.step.lock(),
+        }
+    }
 
-        history.execute_command(command2, &ctx);
-        assert_eq!(ctx.lock().value, 3);
-        assert_eq!(history.undo.read().len(), 2);
-        assert!(history.redo.read().is_empty());
+    /// Takes the step lock, giving up with [`HistoryError::Busy`] if it is not available by
+    /// `deadline`. `None` waits as long as it takes.
+    fn lock_step(&self, deadline: Option<Instant>) -> Result<Step<'_, C>, HistoryError> {
+        let guard = sync::lock_until(&self.step, deadline).ok_or(HistoryError::Busy)?;
+        Ok(Step {
+            history: self,
+            _guard: guard,
+        })
+    }
 
-        history.execute_command(command3, &ctx);
+    /// Returns whether a command of this history is running on any thread, so other steps have to
+    /// wait for it.
+    pub fn is_busy(&self) -> bool {
+        !self.frames.lock().is_empty()
+    }
 
-        assert_eq!(ctx.lock().value, 6);
-        assert_eq!(history.undo.read().len(), 2);
-        assert!(history.redo.read().is_empty());
+    /// Returns whether the current thread is inside a command of this history.
+    ///
+    /// Commands executed through the history from there are run right away and recorded as
+    /// children of the running command, in the same entry, so they are undone and redone together
+    /// with it. While an entry is undone, redone or replayed, such calls are ignored, because the
+    /// history replays the recorded children itself.
+    ///
+    /// Reading the history and updating the metadata of its entries work as usual from there.
+    /// Undoing, redoing, moving through the history, opening or closing scopes and tentative
+    /// commands are refused,
+    /// and [`enforce_retention`](Self::enforce_retention) does nothing. A new history limit is
+    /// stored but only enforced by the next step. The same applies to the lifecycle hooks of
+    /// [`Command`], which run once the step that dropped their command is done.
+    pub fn is_reentrant(&self) -> bool {
+        self.with_frame(|_| ()).is_some()
     }
 
-    #[test]
-    fn test_arc_batch() {
-        let size = rand::thread_rng().gen_range(10..40);
-        let history = ConcurrentCommandHistory::new(NonZeroUsize::new(35).unwrap(), true);
-        let ctx = SharedContext::new(TestArcContext { value: 0 });
+    /// Runs `f` on the innermost frame of the current thread, if it is inside a command.
+    fn with_frame<R>(&self, f: impl FnOnce(&mut Frame<C>) -> R) -> Option<R> {
+        let thread = thread::current().id();
+        self.frames
+            .lock()
+            .iter_mut()
+            .rev()
+            .find(|frame| frame.thread == thread)
+            .map(f)
+    }
 
-        let mut expected_sum = 0;
-        let commands: Vec<_> = (0..size)
-            .map(|i| {
-                let value = rand::thread_rng().gen_range(1..10);
+    /// Marks the current thread as running a command until the returned guard is dropped.
+    ///
+    /// Commands issued through the history meanwhile are recorded if `record` is set, and ignored
+    /// otherwise.
+    fn enter(&self, record: bool) -> Running<'_, C> {
+        self.frames.lock().push(Frame {
+            thread: thread::current().id(),
+            children: record.then(Vec::new),
+        });
+        Running { history: self }
+    }
 
-                let increment = i % 2 == 0;
-                if increment {
-                    expected_sum += value;
-                    TestArcCommand {
-                        operation: TestOperation::Increment(value),
-                    }
-                } else {
-                    expected_sum -= value;
-                    TestArcCommand {
-                        operation: TestOperation::Decrement(value),
-                    }
-                }
+    /// Runs `command` as a child of the command running on the current thread.
+    fn execute_child(&self, command: C, ctx: &C::Context) {
+        let command = Arc::new(command);
+        let index = self.with_frame(|frame| {
+            frame.children.as_mut().map(|children| {
+                children.push(Arc::clone(&command));
+                children.len() - 1
             })
-            .collect();
-
-        history.batch_execute(commands, &ctx);
+        });
+        let Some(Some(index)) = index else {
+            return;
+        };
 
-        assert_eq!(ctx.lock().value, expected_sum);
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| command.execute(ctx))) {
+            // The parent may catch the panic itself, so the failed child must not be recorded.
+            self.with_frame(|frame| {
+                if let Some(children) = frame.children.as_mut() {
+                    children.truncate(index);
+                }
+            });
+            panic::resume_unwind(payload);
+        }
+    }
+
+    /// Checks `invariant` on the context after every execute, undo and redo. A step that breaks it
+    /// is reverted right away, before any other step can run, and reported as
+    /// [`HistoryError::InvariantViolated`].
+    ///
+    /// The context is not checked when the invariant is set, and neither are scopes, replays or
+    /// the checkpoints restored by [`go_to`](Self::go_to).
+    pub fn set_invariant<I: Invariant<C::Context> + 'static>(&self, invariant: I) {
+        *self.invariant.write() = Some(Box::new(invariant));
+    }
+
+    pub fn clear_invariant(&self) {
+        *self.invariant.write() = None;
+    }
+
+    /// Registers `mirror` as a secondary context that receives every execute, undo and redo of
+    /// this history, in the same order as the primary context and before any other step can run.
+    /// Aborted scopes and `go_to` are mirrored as well, replays are not.
+    ///
+    /// The mirror has to be in the same state as the primary context when it is added. Executed
+    /// and redone entries are applied to it with [`Command::redo`], so commands that remember
+    /// the outcome of their first execution replay it on the mirror.
+    ///
+    /// A mirror that panics or breaks the [`Invariant`] while applying a step is considered
+    /// diverged: it stops receiving steps, and [`mirror_divergence`](Self::mirror_divergence)
+    /// reports the step. The primary context and the stacks are not affected.
+    pub fn add_mirror(&self, mirror: C::Context) -> MirrorId {
+        let id = MirrorId(self.next_mirror.fetch_add(1, Ordering::Relaxed));
+        self.mirrors.lock().push(Mirror {
+            id,
+            ctx: mirror,
+            divergence: None,
+        });
+        id
+    }
+
+    /// Stops mirroring to the mirror `id` and hands it back.
+    pub fn remove_mirror(&self, id: MirrorId) -> Option<C::Context> {
+        let mut mirrors = self.mirrors.lock();
+        let index = mirrors.iter().position(|mirror| mirror.id == id)?;
+        Some(mirrors.remove(index).ctx)
+    }
+
+    /// Returns the step at which the mirror `id` diverged, if it did.
+    pub fn mirror_divergence(&self, id: MirrorId) -> Option<MirrorDivergence> {
+        self.mirrors
+            .lock()
+            .iter()
+            .find(|mirror| mirror.id == id)
+            .and_then(|mirror| mirror.divergence.clone())
+    }
+
+    /// Lets a diverged mirror receive steps again, once it was brought back in sync with the
+    /// primary context.
+    ///
+    /// # Returns
+    ///
+    /// `false` if there is no mirror `id` or it had not diverged.
+    pub fn resume_mirror(&self, id: MirrorId) -> bool {
+        self.mirrors
+            .lock()
+            .iter_mut()
+            .find(|mirror| mirror.id == id)
+            .and_then(|mirror| mirror.divergence.take())
+            .is_some()
+    }
+
+    /// Applies `entry` to every mirror that is still in sync and records the mirrors that
+    /// diverge.
+    fn mirror_step(&self, stage: PanicStage, entry: &ConcurrentEntry<C>) {
+        let mut mirrors = self.mirrors.lock();
+        let invariant = self.invariant.read();
+        let running = self.enter(false);
+
+        for mirror in mirrors
+            .iter_mut()
+            .filter(|mirror| mirror.divergence.is_none())
+        {
+            let applied = panic_policy::catch(
+                stage,
+                || Self::describe(entry),
+                || match stage {
+                    PanicStage::Undo => self.undo_payload(entry.payload(), &mirror.ctx),
+                    PanicStage::Execute | PanicStage::Redo => {
+                        self.redo_payload(entry.payload(), &mirror.ctx);
+                    }
+                },
+            );
+
+            let rejected = match applied {
+                Ok(()) => invariant
+                    .as_ref()
+                    .and_then(|invariant| invariant.check(&mirror.ctx).err()),
+                Err(caught) => Some(caught.message()),
+            };
+
+            mirror.divergence = rejected.map(|reason| MirrorDivergence {
+                mirror: mirror.id,
+                stage,
+                description: Self::describe(entry),
+                reason,
+            });
+        }
+        drop(running);
+    }
+
+    fn check_invariant(
+        &self,
+        entry: &ConcurrentEntry<C>,
+        ctx: &C::Context,
+    ) -> Result<(), HistoryError> {
+        let invariant = self.invariant.read();
+        let Some(invariant) = invariant.as_ref() else {
+            return Ok(());
+        };
+
+        invariant
+            .check(ctx)
+            .map_err(|violation| HistoryError::InvariantViolated {
+                description: Self::describe(entry),
+                violation,
+            })
+    }
+
+    /// Sets what happens when a command panics while it is executed, undone or redone.
+    pub fn set_panic_policy(&self, policy: PanicPolicy) {
+        *self.panic_policy.write() = policy;
+    }
+
+    pub fn panic_policy(&self) -> PanicPolicy {
+        *self.panic_policy.read()
+    }
+
+    /// Undoes the last command without applying the panic policy to a caught panic, so the caller
+    /// can do so once the locks are released.
+    fn undo_unsettled(&self, ctx: &C::Context, deadline: Option<Instant>) -> Result<(), Failure> {
+        let _step = self.lock_step(deadline)?;
+        self.wait_for_context(ctx, deadline)?;
+        self.ensure_settled()?;
+
+        let mut undo = self.undo.write();
+        if undo.is_empty() {
+            let loaded = match self.spill.lock().as_mut() {
+                Some(spill) => spill.pop()?,
+                None => None,
+            };
+
+            if let Some(entry) = loaded {
+                undo.push_front(entry);
+                let _ = self
+                    .evicted
+                    .fetch_update(Ordering::AcqRel, Ordering::Acquire, |evicted| {
+                        Some(evicted.saturating_sub(1))
+                    });
+            }
+        }
+        drop(undo);
+
+        self.undo_locked(ctx)
+    }
+
+    fn redo_unsettled(&self, ctx: &C::Context, deadline: Option<Instant>) -> Result<(), Failure> {
+        let _step = self.lock_step(deadline)?;
+        self.wait_for_context(ctx, deadline)?;
+        self.redo_locked(ctx)
+    }
+
+    /// Enables periodic snapshots of the context, which lets [`go_to`](Self::go_to) restore the
+    /// nearest snapshot and replay only the remaining commands instead of undoing step by step.
+    ///
+    /// Snapshots count toward the history limit with the configured weight. Any previously held
+    /// snapshots are discarded.
+    pub fn enable_checkpoints(&self, config: CheckpointConfig)
+    where
+        C::Context: Checkpoint,
+    {
+        *self.checkpoints.lock() = Some(Checkpoints::new(config, C::Context::capture));
+    }
+
+    pub fn disable_checkpoints(&self) {
+        *self.checkpoints.lock() = None;
+    }
+
+    pub fn checkpoint_count(&self) -> usize {
+        self.checkpoints.lock().as_ref().map_or(0, Checkpoints::len)
+    }
+
+    /// Returns the current position in the history, i.e. the number of entries that can be undone.
+    pub fn position(&self) -> usize {
+        self.undo.read().len()
+    }
+
+    /// Moves the history to `position`, where 0 is the oldest state that can still be reached and
+    /// `position() + redo_history().len()` is the newest.
+    ///
+    /// When a checkpoint is closer to `position` than the current state, the context is restored
+    /// from it and only the commands between the checkpoint and `position` are redone. Other steps
+    /// wait until the whole move is done.
+    ///
+    /// # Returns
+    ///
+    /// `false` if `position` lies outside the reachable range, in which case nothing changes, or
+    /// if a command panicked and the [`PanicPolicy`] caught it or a step broke the invariant, in
+    /// which case the move stops there.
+    pub fn go_to(&self, position: usize, ctx: &C::Context) -> bool
+    where
+        C::Context: Checkpoint,
+    {
+        if self.is_reentrant() {
+            return false;
+        }
+
+        self.go_to_unsettled(position, ctx)
+            .unwrap_or_else(|failure| {
+                failure.settle(self.panic_policy());
+                false
+            })
+    }
+
+    fn go_to_unsettled(&self, position: usize, ctx: &C::Context) -> Result<bool, Failure>
+    where
+        C::Context: Checkpoint,
+    {
+        let _step = self.begin_step();
+        self.ensure_settled()?;
+
+        let mut current = self.position();
+        if position > current + self.redo.read().len() {
+            return Ok(false);
+        }
+
+        let evicted = self.evicted.load(Ordering::Acquire);
+        let restored = self.checkpoints.lock().as_ref().and_then(|checkpoints| {
+            checkpoints
+                .nearest(evicted, evicted + position)
+                .map(|(checkpoint, snapshot)| (checkpoint - evicted, snapshot))
+                .filter(|(relative, _)| position - relative < current.abs_diff(position))
+                .map(|(relative, snapshot)| {
+                    ctx.restore(snapshot);
+                    for mirror in self.mirrors.lock().iter() {
+                        mirror.ctx.restore(snapshot);
+                    }
+                    relative
+                })
+        });
+
+        if let Some(relative) = restored {
+            self.shift(
+                current,
+                relative,
+                &mut self.undo.write(),
+                &mut self.redo.write(),
+            );
+            current = relative;
+        }
+
+        if position < current {
+            for _ in position..current {
+                self.undo_locked(ctx)?;
+            }
+        } else {
+            for _ in current..position {
+                self.redo_locked(ctx)?;
+            }
+        }
+        self.stamp_top(ctx, &mut self.undo.write());
+        self.compact_undo(ctx);
+
+        Ok(true)
+    }
+
+    /// Moves entries between the stacks without running them, so the position becomes `target`.
+    fn shift(&self, current: usize, target: usize, undo: &mut Stack<C>, redo: &mut Stack<C>) {
+        if target < current {
+            for _ in target..current {
+                if let Some(mut entry) = undo.pop_front() {
+                    entry.touch();
+                    self.push_redo(entry, undo.len(), redo);
+                }
+            }
+        } else {
+            for _ in current..target {
+                if let Some(mut entry) = redo.pop_front() {
+                    entry.touch();
+                    self.push_undo(entry, None, undo);
+                }
+            }
+        }
+    }
+
+    /// Records the current context version on the entry that is undone next, if versions are
+    /// checked.
+    fn stamp_top(&self, ctx: &C::Context, undo: &mut Stack<C>) {
+        if let (Some(version_of), Some(entry)) = (self.version_of.get(), undo.front_mut()) {
+            entry.stamp(version_of(ctx));
+        }
+    }
+
+    /// Undoes the newest entry. The entry is taken off the undo stack while it runs, so the stacks
+    /// stay available to the command.
+    fn undo_locked(&self, ctx: &C::Context) -> Result<(), Failure> {
+        self.ensure_settled()?;
+        let Some(mut entry) = self.undo.write().pop_front() else {
+            return Ok(());
+        };
+
+        let running = self.enter(false);
+        let undone = panic_policy::catch(
+            PanicStage::Undo,
+            || Self::describe(&entry),
+            || self.undo_payload(entry.payload(), ctx),
+        );
+        drop(running);
+
+        if let Err(caught) = undone {
+            let mut undo = self.undo.write();
+            match self.panic_policy() {
+                PanicPolicy::Propagate | PanicPolicy::Restore => undo.push_front(entry),
+                PanicPolicy::Discard => {
+                    self.notify(&entry, C::on_discarded);
+                    self.discard_redo(undo.len(), &mut self.redo.write());
+                }
+            }
+            return Err(caught.into());
+        }
+
+        if let Err(error) = self.check_invariant(&entry, ctx) {
+            let running = self.enter(false);
+            self.redo_payload(entry.payload(), ctx);
+            drop(running);
+            let mut undo = self.undo.write();
+            undo.push_front(entry);
+            self.stamp_top(ctx, &mut undo);
+            return Err(error.into());
+        }
+        self.mirror_step(PanicStage::Undo, &entry);
+        entry.touch();
+
+        let mut undo = self.undo.write();
+        self.push_redo(entry, undo.len(), &mut self.redo.write());
+        self.stamp_top(ctx, &mut undo);
+        Ok(())
+    }
+
+    /// Redoes the next entry, which is taken off the redo stack while it runs.
+    fn redo_locked(&self, ctx: &C::Context) -> Result<(), Failure> {
+        self.ensure_settled()?;
+        let Some(mut entry) = self.redo.write().pop_front() else {
+            return Ok(());
+        };
+
+        let running = self.enter(false);
+        let redone = panic_policy::catch(
+            PanicStage::Redo,
+            || Self::describe(&entry),
+            || self.redo_payload(entry.payload(), ctx),
+        );
+        drop(running);
+
+        if let Err(caught) = redone {
+            let undo = self.undo.write();
+            let mut redo = self.redo.write();
+            match self.panic_policy() {
+                PanicPolicy::Propagate | PanicPolicy::Restore => redo.push_front(entry),
+                PanicPolicy::Discard => {
+                    self.notify(&entry, C::on_discarded);
+                    self.discard_redo(undo.len(), &mut redo);
+                }
+            }
+            return Err(caught.into());
+        }
+
+        if let Err(error) = self.check_invariant(&entry, ctx) {
+            let running = self.enter(false);
+            self.undo_payload(entry.payload(), ctx);
+            drop(running);
+            self.redo.write().push_front(entry);
+            return Err(error.into());
+        }
+        self.mirror_step(PanicStage::Redo, &entry);
+        entry.touch();
+
+        self.push_undo(entry, Some(ctx), &mut self.undo.write());
+        self.compact_undo(ctx);
+        Ok(())
+    }
+
+    pub(crate) fn describe(entry: &ConcurrentEntry<C>) -> String {
+        entry.command().map_or_else(
+            || entry.group_description(),
+            |command| command.description().into_owned(),
+        )
+    }
+
+    fn restore_snapshot(&self, ctx: &C::Context, snapshot: &C::Context) {
+        let ops = self
+            .snapshot_ops
+            .get()
+            .expect("snapshot entries only exist once snapshot compaction is enabled");
+        (ops.restore)(ctx, snapshot);
+    }
+
+    fn undo_payload(&self, payload: &EntryPayload<Arc<C>, Arc<C::Context>>, ctx: &C::Context) {
+        match payload {
+            EntryPayload::Command(command) => command.undo(ctx),
+            EntryPayload::Compound(commands) => {
+                for command in commands.iter().rev() {
+                    command.undo(ctx);
+                }
+            }
+            EntryPayload::Snapshot { before, .. } => self.restore_snapshot(ctx, before),
+        }
+    }
+
+    fn redo_payload(&self, payload: &EntryPayload<Arc<C>, Arc<C::Context>>, ctx: &C::Context) {
+        match payload {
+            EntryPayload::Command(command) => command.redo(ctx),
+            EntryPayload::Compound(commands) => {
+                for command in commands {
+                    command.redo(ctx);
+                }
+            }
+            EntryPayload::Snapshot { after, .. } => self.restore_snapshot(ctx, after),
+        }
+    }
+
+    fn evict_oldest_undo(
+        &self,
+        undo: &mut Stack<C>,
+        checkpoints: &mut Option<Checkpoints<C::Context>>,
+    ) {
+        if let Some(entry) = undo.pop_back() {
+            let mut spill = self.spill.lock();
+            let spilled = spill
+                .as_mut()
+                .is_some_and(|spill| match spill.push(&entry) {
+                    Ok(()) => true,
+                    Err(error) => {
+                        // Everything spilled so far leads up to this entry and is unreachable without it.
+                        spill.clear();
+                        *self.spill_error.lock() = Some(error);
+                        false
+                    }
+                });
+            drop(spill);
+
+            if !spilled {
+                self.notify(&entry, C::on_evicted);
+            }
+
+            self.advance_base(1, checkpoints);
+        }
+    }
+
+    /// Records that `count` steps were removed from the oldest end of the undo stack.
+    fn advance_base(&self, count: usize, checkpoints: &mut Option<Checkpoints<C::Context>>) {
+        let evicted = self.evicted.fetch_add(count, Ordering::AcqRel) + count;
+
+        if let Some(checkpoints) = checkpoints.as_mut() {
+            checkpoints.prune_before(evicted);
+        }
+    }
+
+    /// Records that the `count` entries following the `skipped` oldest ones were merged into a
+    /// single entry.
+    fn merge_base(
+        &self,
+        (skipped, count): (usize, usize),
+        checkpoints: &mut Option<Checkpoints<C::Context>>,
+    ) {
+        let start = self.evicted.fetch_add(count - 1, Ordering::AcqRel) + skipped;
+
+        if let Some(checkpoints) = checkpoints.as_mut() {
+            checkpoints.merge(start, count);
+        }
+    }
+
+    /// Brings the undo stack and the held snapshots within `limit`, compacting the oldest entries
+    /// when compaction is enabled and evicting them otherwise.
+    ///
+    /// # Returns
+    ///
+    /// The batch size if the oldest entries have to be compacted into a snapshot first, which
+    /// [`compact_undo`](Self::compact_undo) does once the stacks are released.
+    fn shrink_undo(
+        &self,
+        limit: usize,
+        undo: &mut Stack<C>,
+        checkpoints: &mut Option<Checkpoints<C::Context>>,
+    ) -> Option<usize> {
+        let compaction = if self.spill.lock().is_some() {
+            None
+        } else {
+            *self.compaction.read()
+        };
+
+        while undo.len() + checkpoints.as_ref().map_or(0, Checkpoints::weight) > limit {
+            if undo.len() > 1 {
+                let compacted = match compaction {
+                    Some(compaction) => match compaction.mode {
+                        CompactionMode::Compound => Self::compact_compound(compaction.batch, undo),
+                        CompactionMode::Snapshot => return Some(compaction.batch),
+                    },
+                    None => None,
+                };
+
+                if let Some(merged) = compacted {
+                    self.merge_base(merged, checkpoints);
+                } else {
+                    self.evict_oldest_undo(undo, checkpoints);
+                }
+            } else if !checkpoints.as_mut().is_some_and(Checkpoints::pop_oldest) {
+                break;
+            }
+        }
+
+        None
+    }
+
+    /// Enforces the history limit and then the retention policy on the undo stack.
+    ///
+    /// # Returns
+    ///
+    /// The batch size if the oldest entries have to be compacted into a snapshot first. The
+    /// retention policy is applied once that is done.
+    fn fit_undo(&self, undo: &mut Stack<C>) -> Option<usize> {
+        let limit = self.history_limit.load(Ordering::Relaxed);
+        let mut checkpoints = self.checkpoints.lock();
+        if let Some(batch) = self.shrink_undo(limit, undo, &mut checkpoints) {
+            return Some(batch);
+        }
+
+        for _ in 0..self.retention_excess(undo) {
+            self.evict_oldest_undo(undo, &mut checkpoints);
+        }
+        None
+    }
+
+    /// Compacts the oldest entries into snapshots until the undo stack is within the history
+    /// limit, then applies the retention policy.
+    ///
+    /// `ctx` has to be the state at the top of the undo stack. The stacks are not held while the
+    /// compacted commands are undone on a copy of it, which is safe since the step lock is.
+    fn compact_undo(&self, ctx: &C::Context) {
+        loop {
+            let (batch, entries) = {
+                let mut undo = self.undo.write();
+                if self.in_scope() {
+                    return;
+                }
+                let Some(batch) = self.fit_undo(&mut undo) else {
+                    return;
+                };
+                (batch, undo.clone())
+            };
+
+            let snapshot = self.capture_snapshot(batch, ctx, &entries);
+            let mut undo = self.undo.write();
+            let mut checkpoints = self.checkpoints.lock();
+            match snapshot {
+                Some((before, after, count)) => {
+                    let merged = self.install_snapshot(before, after, count, &mut undo);
+                    self.merge_base(merged, &mut checkpoints);
+                }
+                None => self.evict_oldest_undo(&mut undo, &mut checkpoints),
+            }
+        }
+    }
+
+    /// Takes the oldest `count` entries off the undo stack, oldest first.
+    fn take_oldest(count: usize, undo: &mut Stack<C>) -> Vec<ConcurrentEntry<C>> {
+        let mut entries = Vec::with_capacity(count);
+        for _ in 0..count {
+            entries.extend(undo.pop_back());
+        }
+
+        entries
+    }
+
+    /// Merges the oldest entries into one compound entry and returns how many of the oldest
+    /// entries were skipped and how many were merged.
+    fn compact_compound(batch: usize, undo: &mut Stack<C>) -> Option<(usize, usize)> {
+        let barrier = undo
+            .back()
+            .is_some_and(|entry| matches!(entry.payload(), EntryPayload::Snapshot { .. }));
+        let count = batch.min(undo.len() - usize::from(barrier));
+        if count < 2 {
+            return None;
+        }
+
+        let snapshot = if barrier { undo.pop_back() } else { None };
+        let entries = Self::take_oldest(count, undo);
+        let metadata = merged_metadata(&entries);
+        let executed_at = entries[0].executed_at();
+
+        let commands = entries
+            .into_iter()
+            .flat_map(|entry| match entry.into_payload() {
+                EntryPayload::Command(command) => vec![command],
+                EntryPayload::Compound(commands) => commands,
+                EntryPayload::Snapshot { .. } => unreachable!("snapshots are never merged"),
+            })
+            .collect();
+
+        let merged = HistoryEntry::from_payload(EntryPayload::Compound(commands), metadata)
+            .with_executed_at(executed_at);
+        undo.push_back(merged);
+        undo.extend(snapshot);
+
+        Some((usize::from(barrier), count))
+    }
+
+    /// Undoes `entries` on a copy of `ctx` and returns the states before and after the oldest
+    /// `batch` of them, together with how many entries that covers.
+    fn capture_snapshot(
+        &self,
+        batch: usize,
+        ctx: &C::Context,
+        entries: &Stack<C>,
+    ) -> Option<(C::Context, C::Context, usize)> {
+        let ops = self.snapshot_ops.get()?;
+
+        let count = batch.min(entries.len());
+        let keep = entries.len() - count;
+
+        let state = (ops.capture)(ctx);
+        let mut after = None;
+        let running = self.enter(false);
+        for (index, entry) in entries.iter().enumerate() {
+            if index == keep {
+                after = Some((ops.capture)(&state));
+            }
+
+            self.undo_payload(entry.payload(), &state);
+        }
+        drop(running);
+
+        Some((state, after?, count))
+    }
+
+    /// Replaces the oldest `count` entries with a snapshot entry and returns how many of the
+    /// oldest entries were skipped and how many were merged.
+    fn install_snapshot(
+        &self,
+        before: C::Context,
+        after: C::Context,
+        count: usize,
+        undo: &mut Stack<C>,
+    ) -> (usize, usize) {
+        let entries = Self::take_oldest(count, undo);
+        let metadata = merged_metadata(&entries);
+        let executed_at = entries[0].executed_at();
+        let merged = entries.iter().map(HistoryEntry::command_count).sum();
+        for entry in &entries {
+            self.notify(entry, C::on_evicted);
+        }
+
+        let snapshot = EntryPayload::Snapshot {
+            before: Arc::new(before),
+            after: Arc::new(after),
+            merged,
+        };
+        undo.push_back(
+            HistoryEntry::from_payload(snapshot, metadata).with_executed_at(executed_at),
+        );
+
+        (0, count)
+    }
+
+    /// Evicts the furthest redo entries until at most `limit` are left.
+    fn trim_redo(&self, limit: usize, undo_len: usize, redo: &mut Stack<C>) {
+        while redo.len() > limit {
+            if let Some(entry) = redo.pop_back() {
+                self.notify(&entry, C::on_evicted);
+            }
+        }
+
+        let end = self.evicted.load(Ordering::Acquire) + undo_len + redo.len();
+        if let Some(checkpoints) = self.checkpoints.lock().as_mut() {
+            checkpoints.truncate_after(end);
+        }
+    }
+
+    /// Drops every redo entry, since it can no longer be reached from the current state.
+    fn discard_redo(&self, undo_len: usize, redo: &mut Stack<C>) {
+        for entry in redo.drain(..) {
+            self.notify(&entry, C::on_discarded);
+        }
+        self.trim_redo(0, undo_len, redo);
+    }
+
+    /// Calls `hook` on every command held by `entry` once the running step ends.
+    fn notify(&self, entry: &ConcurrentEntry<C>, hook: fn(&C)) {
+        self.retired.lock().extend(
+            entry
+                .commands()
+                .iter()
+                .map(|command| (Arc::clone(command), hook)),
+        );
+    }
+
+    fn checkpoint_after_execute(&self, cost: usize, ctx: &C::Context, undo: &mut Stack<C>) {
+        let scoped = self.in_scope();
+        let mut checkpoints = self.checkpoints.lock();
+        let position = self.evicted.load(Ordering::Acquire) + undo.len();
+
+        if let Some(store) = checkpoints.as_mut() {
+            store.truncate_after(position - 1);
+
+            if store.record(cost) {
+                let snapshot = store.capture(ctx);
+                store.insert(position, snapshot);
+
+                if !scoped {
+                    let limit = self.history_limit.load(Ordering::Relaxed);
+                    self.shrink_undo(limit, undo, &mut checkpoints);
+                }
+            }
+        }
+    }
+
+    fn entry_info(entry: &ConcurrentEntry<C>) -> EntryInfo<'_> {
+        let (cost, description) = match entry.command() {
+            Some(command) => (command.cost(), command.description()),
+            None => (
+                entry
+                    .commands()
+                    .iter()
+                    .map(|command| command.cost())
+                    .sum::<usize>()
+                    .max(1),
+                Cow::Owned(entry.group_description()),
+            ),
+        };
+
+        EntryInfo {
+            cost,
+            description,
+            executed_at: entry.executed_at(),
+            tags: entry.tags(),
+        }
+    }
+
+    fn entry_infos(stack: &Stack<C>) -> Vec<EntryInfo<'_>> {
+        stack.iter().map(Self::entry_info).collect()
+    }
+
+    /// Returns how much of a shared budget `entry` takes up: 1, or its cost if `by_cost` is set.
+    fn weight(entry: &ConcurrentEntry<C>, by_cost: bool) -> usize {
+        if by_cost {
+            Self::entry_info(entry).cost
+        } else {
+            1
+        }
+    }
+
+    /// Returns how much of a shared budget this history takes up: its number of entries, or their
+    /// summed cost if `by_cost` is set.
+    pub(crate) fn usage(&self, by_cost: bool) -> usize {
+        let undo = self.undo.read();
+        let redo = self.redo.read();
+        undo.iter()
+            .chain(redo.iter())
+            .map(|entry| Self::weight(entry, by_cost))
+            .sum()
+    }
+
+    /// Drops the oldest undo entry, or the furthest redo entry once the undo stack is empty.
+    ///
+    /// # Returns
+    ///
+    /// How much of a shared budget the dropped entry took up, as counted by
+    /// [`usage`](Self::usage), or `None` if both stacks are empty or the call comes from inside a
+    /// command.
+    pub(crate) fn evict_oldest(&self, by_cost: bool) -> Option<usize> {
+        if self.is_reentrant() {
+            return None;
+        }
+
+        let _step = self.begin_step();
+        let mut undo = self.undo.write();
+        if let Some(entry) = undo.back() {
+            let weight = Self::weight(entry, by_cost);
+            self.evict_oldest_undo(&mut undo, &mut self.checkpoints.lock());
+            return Some(weight);
+        }
+
+        let mut redo = self.redo.write();
+        let weight = Self::weight(redo.back()?, by_cost);
+        let keep = redo.len() - 1;
+        self.trim_redo(keep, undo.len(), &mut redo);
+        Some(weight)
+    }
+
+    fn retention_excess(&self, stack: &Stack<C>) -> usize {
+        self.retention.read().as_ref().map_or(0, |retention| {
+            retention.excess(&Self::entry_infos(stack)).min(stack.len())
+        })
+    }
+
+    /// Pushes `entry` onto the undo stack and enforces the history limit and retention policy.
+    /// Snapshot compaction is left to [`compact_undo`](Self::compact_undo).
+    ///
+    /// `ctx` has to be the state after `entry`, or `None` if the context does not match the stack.
+    fn push_undo(&self, entry: ConcurrentEntry<C>, ctx: Option<&C::Context>, undo: &mut Stack<C>) {
+        undo.push_front(entry);
+        if let Some(ctx) = ctx {
+            self.stamp_top(ctx, undo);
+        }
+        if !self.in_scope() {
+            self.fit_undo(undo);
+        }
+    }
+
+    fn push_redo(&self, entry: ConcurrentEntry<C>, undo_len: usize, redo: &mut Stack<C>) {
+        redo.push_front(entry);
+
+        let limit = self.history_limit.load(Ordering::Relaxed);
+        let excess = if self.in_scope() {
+            0
+        } else {
+            self.retention_excess(redo)
+        };
+        self.trim_redo(limit.min(redo.len() - excess), undo_len, redo);
+    }
+
+    fn execute_locked(
+        &self,
+        command: C,
+        metadata: EntryMetadata,
+        ctx: &C::Context,
+    ) -> Result<(), Failure> {
+        let entry = self.apply_locked(command, metadata, ctx)?;
+        self.record_applied(entry, ctx);
+        Ok(())
+    }
+
+    /// Executes `command` on `ctx` and its mirrors and returns the entry to record, without
+    /// recording it. The stacks are not locked while the command runs.
+    fn apply_locked(
+        &self,
+        command: C,
+        metadata: EntryMetadata,
+        ctx: &C::Context,
+    ) -> Result<ConcurrentEntry<C>, Failure> {
+        self.ensure_settled()?;
+        let limit = self.history_limit.load(Ordering::Relaxed).saturating_sub(
+            self.checkpoints
+                .lock()
+                .as_ref()
+                .map_or(0, Checkpoints::weight),
+        );
+        let scoped = self.in_scope();
+        if let Some(retention) = self.retention.read().as_ref().filter(|_| !scoped) {
+            let candidate = EntryInfo {
+                cost: command.cost(),
+                description: command.description(),
+                executed_at: SystemTime::now(),
+                tags: &metadata.tags,
+            };
+
+            if !retention.admits(&Self::entry_infos(&self.undo.read()), &candidate, limit) {
+                return Err(HistoryError::Refused {
+                    description: candidate.description.into_owned(),
+                }
+                .into());
+            }
+        }
+
+        let command = Arc::new(command);
+        let running = self.enter(true);
+        let executed = panic_policy::catch(
+            PanicStage::Execute,
+            || command.description().into_owned(),
+            || command.execute(ctx),
+        );
+        let children = running.take_children();
+        drop(running);
+
+        if let Err(caught) = executed {
+            command.on_discarded();
+            for child in &children {
+                child.on_discarded();
+            }
+            return Err(caught.into());
+        }
+
+        let entry = if children.is_empty() {
+            HistoryEntry::with_metadata(command, metadata)
+        } else {
+            let mut commands = vec![command];
+            commands.extend(children);
+            HistoryEntry::from_payload(EntryPayload::Compound(commands), metadata)
+        };
+
+        if let Err(error) = self.check_invariant(&entry, ctx) {
+            let running = self.enter(false);
+            self.undo_payload(entry.payload(), ctx);
+            drop(running);
+            self.notify(&entry, C::on_discarded);
+            return Err(error.into());
+        }
+
+        self.mirror_step(PanicStage::Execute, &entry);
+        Ok(entry)
+    }
+
+    /// Pushes an entry returned by [`apply_locked`](Self::apply_locked) onto the undo stack, and
+    /// drops the redo stack if executing clears it.
+    fn record_applied(&self, entry: ConcurrentEntry<C>, ctx: &C::Context) {
+        let cost = entry.commands().iter().map(|command| command.cost()).sum();
+        let mut undo = self.undo.write();
+        self.push_undo(entry, Some(ctx), &mut undo);
+        self.checkpoint_after_execute(cost, ctx, &mut undo);
+        if self.clear_redo_on_execute.load(Ordering::Relaxed) {
+            self.discard_redo(undo.len(), &mut self.redo.write());
+        }
+        drop(undo);
+
+        self.compact_undo(ctx);
+    }
+
+    /// Records a tentative entry once it is confirmed, as the newest entry.
+    pub(crate) fn confirm_tentative(&self, entry: ConcurrentEntry<C>, ctx: &C::Context) {
+        let _step = self.begin_step();
+        *self.pending.lock() = None;
+        self.record_applied(entry, ctx);
+    }
+
+    /// Undoes a tentative entry on `ctx` and the mirrors and discards it. If undoing it breaks
+    /// the invariant, it is redone and recorded instead, as if it had been confirmed.
+    pub(crate) fn revert_tentative(
+        &self,
+        entry: ConcurrentEntry<C>,
+        ctx: &C::Context,
+    ) -> Result<(), HistoryError> {
+        let step = self.begin_step();
+        *self.pending.lock() = None;
+        let running = self.enter(false);
+        let reverted = panic_policy::catch(
+            PanicStage::Undo,
+            || Self::describe(&entry),
+            || self.undo_payload(entry.payload(), ctx),
+        );
+        drop(running);
+
+        if reverted.is_ok() {
+            if let Err(error) = self.check_invariant(&entry, ctx) {
+                let running = self.enter(false);
+                self.redo_payload(entry.payload(), ctx);
+                drop(running);
+                self.record_applied(entry, ctx);
+                return Err(error);
+            }
+            self.mirror_step(PanicStage::Undo, &entry);
+            self.stamp_top(ctx, &mut self.undo.write());
+        }
+        self.notify(&entry, C::on_discarded);
+        drop(step);
+        reverted.map_err(|caught| caught.settle(self.panic_policy()))
+    }
+
+    pub fn set_clear_redo_on_execute(&self, clear: bool) {
+        self.clear_redo_on_execute.store(clear, Ordering::Relaxed);
+    }
+}
+
+impl<C: Command + Send + Sync> Drop for ConcurrentCommandHistory<C> {
+    fn drop(&mut self) {
+        if let Some(timer) = self.timer.get() {
+            timer.close();
+        }
+
+        let scopes = self.scopes.get_mut();
+        let parents = scopes
+            .iter()
+            .flat_map(|scope| scope.undo.iter().chain(&scope.redo));
+        let (undo, redo) = (self.undo.get_mut(), self.redo.get_mut());
+        for command in undo
+            .iter()
+            .chain(redo.iter())
+            .chain(parents)
+            .flat_map(HistoryEntry::commands)
+        {
+            command.on_finalized();
+        }
+    }
+}
+
+impl<C> CommandHistory<C> for ConcurrentCommandHistory<C>
+where
+    C: Command + Send + Sync,
+{
+    fn execute_command(&self, command: C, ctx: &C::Context) {
+        let _ = self.execute_with_metadata(command, ctx, EntryMetadata::default());
+    }
+
+    fn undo(&self, ctx: &C::Context) {
+        let _ = self.undo_until(ctx, None);
+    }
+
+    fn redo(&self, ctx: &C::Context) {
+        let _ = self.redo_until(ctx, None);
+    }
+
+    fn set_history_limit(&self, limit: NonZeroUsize) {
+        assert!(limit.get() > 0);
+        let limit = limit.get();
+
+        self.history_limit.store(limit, Ordering::Release);
+        if self.is_reentrant() {
+            return;
+        }
+
+        let _step = self.begin_step();
+        let mut undo = self.undo.write();
+        if !self.in_scope() {
+            self.shrink_undo(limit, &mut undo, &mut self.checkpoints.lock());
+        }
+
+        let mut redo = self.redo.write();
+        self.trim_redo(limit, undo.len(), &mut redo);
+    }
+
+    fn batch_execute(&self, commands: Vec<C>, ctx: &C::Context) {
+        if self.is_reentrant() {
+            for command in commands {
+                self.execute_child(command, ctx);
+            }
+            return;
+        }
+
+        let step = self.begin_step();
+        let mut panicked = None;
+        for command in commands {
+            let executed = self.execute_locked(command, EntryMetadata::default(), ctx);
+            if let Err(Failure::Panic(caught)) = executed {
+                // The rest of the batch was meant to run after the panicking command.
+                panicked = Some(caught);
+                break;
+            }
+        }
+        drop(step);
+
+        if let Some(caught) = panicked {
+            caught.settle(self.panic_policy());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        borrow::Cow,
+        cmp::min,
+        hint::black_box,
+        num::NonZero,
+        panic::AssertUnwindSafe,
+        sync::{Barrier, Weak},
+        thread, time,
+    };
+
+    use rand::Rng;
+
+    use crate::{
+        checkpoint::CheckpointInterval,
+        retention::{CategoryQuota, MaxCount, RefuseWhenFull},
+        shared_context::SharedContext,
+    };
+
+    use super::*;
+
+    #[derive(Debug)]
+    enum TestOperation {
+        Increment(i32),
+        Decrement(i32),
+    }
+
+    struct TestArcCommand {
+        operation: TestOperation,
+    }
+
+    #[derive(Clone)]
+    struct TestArcContext {
+        value: i32,
+    }
+
+    impl Command for TestArcCommand {
+        type Context = SharedContext<TestArcContext>;
+        fn execute(&self, ctx: &Self::Context) {
+            let mut ctx = ctx.lock();
+            match self.operation {
+                TestOperation::Increment(value) => ctx.value += value,
+                TestOperation::Decrement(value) => ctx.value -= value,
+            }
+        }
+
+        fn undo(&self, ctx: &Self::Context) {
+            let mut ctx = ctx.lock();
+            match self.operation {
+                TestOperation::Increment(value) => ctx.value -= value,
+                TestOperation::Decrement(value) => ctx.value += value,
+            }
+        }
+
+        fn description(&self) -> Cow<'_, str> {
+            match self.operation {
+                TestOperation::Increment(value) => {
+                    Cow::Owned(format!("TestArcCommand: Increment({value})"))
+                }
+                TestOperation::Decrement(value) => {
+                    Cow::Owned(format!("TestArcCommand: Decrement({value})"))
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_arc_command() {
+        let history = ConcurrentCommandHistory::new(NonZeroUsize::new(5).unwrap(), true);
+        let ctx = SharedContext::new(TestArcContext { value: 0 });
+        let command = TestArcCommand {
+            operation: TestOperation::Increment(1),
+        };
+
+        history.execute_command(command, &ctx);
+        assert_eq!(ctx.lock().value, 1);
+
+        history.undo(&ctx);
+        assert_eq!(ctx.lock().value, 0);
+
+        history.redo(&ctx);
+        assert_eq!(ctx.lock().value, 1);
+    }
+
+    #[test]
+    fn test_arc_max_size() {
+        let history = ConcurrentCommandHistory::new(NonZero::new(2).unwrap(), true);
+        let ctx = SharedContext::new(TestArcContext { value: 0 });
+        let command1 = TestArcCommand {
+            operation: TestOperation::Increment(1),
+        };
+        let command2 = TestArcCommand {
+            operation: TestOperation::Increment(2),
+        };
+        let command3 = TestArcCommand {
+            operation: TestOperation::Increment(3),
+        };
+
+        history.execute_command(command1, &ctx);
+        assert_eq!(ctx.lock().value, 1);
+        assert_eq!(history.undo.read().len(), 1);
+        assert!(history.redo.read().is_empty());
+
+        history.execute_command(command2, &ctx);
+        assert_eq!(ctx.lock().value, 3);
+        assert_eq!(history.undo.read().len(), 2);
+        assert!(history.redo.read().is_empty());
+
+        history.execute_command(command3, &ctx);
+
+        assert_eq!(ctx.lock().value, 6);
+        assert_eq!(history.undo.read().len(), 2);
+        assert!(history.redo.read().is_empty());
+    }
+
+    #[test]
+    fn test_arc_batch() {
+        let size = rand::thread_rng().gen_range(10..40);
+        let history = ConcurrentCommandHistory::new(NonZeroUsize::new(35).unwrap(), true);
+        let ctx = SharedContext::new(TestArcContext { value: 0 });
+
+        let mut expected_sum = 0;
+        let commands: Vec<_> = (0..size)
+            .map(|i| {
+                let value = rand::thread_rng().gen_range(1..10);
+
+                let increment = i % 2 == 0;
+                if increment {
+                    expected_sum += value;
+                    TestArcCommand {
+                        operation: TestOperation::Increment(value),
+                    }
+                } else {
+                    expected_sum -= value;
+                    TestArcCommand {
+                        operation: TestOperation::Decrement(value),
+                    }
+                }
+            })
+            .collect();
+
+        history.batch_execute(commands, &ctx);
+
+        assert_eq!(ctx.lock().value, expected_sum);
         assert_eq!(history.undo.read().len(), min(size, 35));
     }
 
     #[test]
-    fn test_arc_set_history_limit() {
-        let history = ConcurrentCommandHistory::new(NonZero::new(2).unwrap(), true);
-        let ctx = SharedContext::new(TestArcContext { value: 0 });
-        let command1 = TestArcCommand {
-            operation: TestOperation::Increment(1),
-        };
-        let command2 = TestArcCommand {
-            operation: TestOperation::Increment(2),
-        };
-        let command3 = TestArcCommand {
-            operation: TestOperation::Increment(3),
+    fn test_arc_set_history_limit() {
+        let history = ConcurrentCommandHistory::new(NonZero::new(2).unwrap(), true);
+        let ctx = SharedContext::new(TestArcContext { value: 0 });
+        let command1 = TestArcCommand {
+            operation: TestOperation::Increment(1),
+        };
+        let command2 = TestArcCommand {
+            operation: TestOperation::Increment(2),
+        };
+        let command3 = TestArcCommand {
+            operation: TestOperation::Increment(3),
+        };
+
+        history.execute_command(command1, &ctx);
+        assert_eq!(ctx.lock().value, 1);
+        assert_eq!(history.undo.read().len(), 1);
+        assert!(history.redo.read().is_empty());
+
+        history.execute_command(command2, &ctx);
+        assert_eq!(ctx.lock().value, 3);
+        assert_eq!(history.undo.read().len(), 2);
+        assert!(history.redo.read().is_empty());
+
+        history.execute_command(command3, &ctx);
+        assert_eq!(ctx.lock().value, 6);
+        assert_eq!(history.undo.read().len(), 2);
+        assert!(history.redo.read().is_empty());
+
+        history.set_history_limit(NonZero::new(1).unwrap());
+        assert_eq!(history.undo.read().len(), 1);
+        assert!(history.redo.read().is_empty());
+    }
+
+    #[test]
+    fn test_arc_concurrent_rng() {
+        let size = rand::thread_rng().gen_range(5..40);
+        let history = ConcurrentCommandHistory::new(NonZero::new(size).unwrap(), true);
+        let ctx = SharedContext::new(TestArcContext { value: 0 });
+
+        let mut handles = Vec::new();
+        let count = rand::thread_rng().gen_range(10..80);
+
+        for _ in 0..count {
+            let value = rand::thread_rng().gen_range(0..20);
+            let command = TestArcCommand {
+                operation: TestOperation::Increment(value),
+            };
+            let history_clone = history.clone();
+            let ctx_clone = ctx.clone();
+
+            handles.push(black_box(thread::spawn(move || {
+                thread::sleep(time::Duration::from_millis(
+                    rand::thread_rng().gen_range(0..250),
+                ));
+                history_clone.execute_command(command, &ctx_clone);
+                value
+            })));
+        }
+
+        let mut sum = 0;
+        for handle in handles {
+            let result = handle.join();
+            assert!(result.is_ok(), "Thread panicked during execution");
+            sum += result.expect("Thread should return a value");
+        }
+
+        assert_eq!(ctx.lock().value, sum);
+        assert_eq!(history.undo.read().len(), min(size, count));
+        assert!(history.redo.read().is_empty());
+
+        let mut handles = Vec::new();
+        for _ in 0..size {
+            let history_clone = Arc::clone(&history);
+            let ctx_clone = ctx.clone();
+
+            handles.push(black_box(thread::spawn(move || {
+                thread::sleep(time::Duration::from_millis(
+                    rand::thread_rng().gen_range(0..200),
+                ));
+                history_clone.undo(&ctx_clone);
+                thread::sleep(time::Duration::from_millis(
+                    rand::thread_rng().gen_range(0..200),
+                ));
+                history_clone.redo(&ctx_clone);
+            })));
+        }
+
+        for handle in handles {
+            assert!(handle.join().is_ok(), "Thread panicked during execution");
+        }
+
+        assert_eq!(ctx.lock().value, sum);
+        assert_eq!(
+            history.undo.read().len(),
+            min(size, count),
+            "Undo stack size after execution"
+        );
+        assert!(
+            history.redo.read().is_empty(),
+            "Redo stack should be empty after execution"
+        );
+    }
+
+    #[test]
+    fn test_command_clears_redo() {
+        let history = ConcurrentCommandHistory::new(NonZero::new(5).unwrap(), true);
+        let ctx = SharedContext::new(TestArcContext { value: 0 });
+        let command = TestArcCommand {
+            operation: TestOperation::Increment(1),
+        };
+        let command2 = TestArcCommand {
+            operation: TestOperation::Increment(1),
+        };
+
+        history.execute_command(command, &ctx);
+        assert_eq!(ctx.lock().value, 1);
+        assert_eq!(history.undo.read().len(), 1);
+        assert!(history.redo.read().is_empty());
+
+        history.undo(&ctx);
+        assert_eq!(ctx.lock().value, 0);
+        assert!(history.undo.read().is_empty());
+        assert_eq!(history.redo.read().len(), 1);
+
+        history.execute_command(command2, &ctx);
+        assert_eq!(ctx.lock().value, 1);
+        assert_eq!(history.undo.read().len(), 1);
+        assert!(history.redo.read().is_empty());
+    }
+
+    #[test]
+    fn test_command_dont_clears_redo() {
+        let history = ConcurrentCommandHistory::new(NonZero::new(5).unwrap(), false);
+        let ctx = SharedContext::new(TestArcContext { value: 0 });
+        let command = TestArcCommand {
+            operation: TestOperation::Increment(1),
+        };
+        let command2 = TestArcCommand {
+            operation: TestOperation::Increment(1),
+        };
+
+        history.execute_command(command, &ctx);
+        assert_eq!(ctx.lock().value, 1);
+        assert_eq!(history.undo.read().len(), 1);
+        assert!(history.redo.read().is_empty());
+
+        history.undo(&ctx);
+        assert_eq!(ctx.lock().value, 0);
+        assert!(history.undo.read().is_empty());
+        assert_eq!(history.redo.read().len(), 1);
+
+        history.execute_command(command2, &ctx);
+        assert_eq!(ctx.lock().value, 1);
+        assert_eq!(history.undo.read().len(), 1);
+        assert_eq!(history.redo.read().len(), 1);
+    }
+
+    #[test]
+    fn test_panic_safety() {
+        let history = ConcurrentCommandHistory::new(NonZero::new(85).unwrap(), true);
+        let ctx = SharedContext::new(TestArcContext { value: 0 });
+        let command = TestArcCommand {
+            operation: TestOperation::Increment(1),
+        };
+
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            history.execute_command(command, &ctx);
+            panic!("Simulated panic");
+        }));
+
+        assert!(result.is_err()); // Ensure panic was caught
+        assert_eq!(ctx.lock().value, 1); // Ensure state is consistent
+    }
+
+    #[test]
+    fn test_undo_history() {
+        let history = ConcurrentCommandHistory::new(NonZeroUsize::new(5).unwrap(), true);
+        let ctx = SharedContext::new(TestArcContext { value: 0 });
+        let command1 = TestArcCommand {
+            operation: TestOperation::Increment(1),
+        };
+        let command2 = TestArcCommand {
+            operation: TestOperation::Increment(2),
+        };
+
+        history.execute_command(command1, &ctx);
+        history.execute_command(command2, &ctx);
+
+        let undo_history = history.undo_history().unwrap();
+        assert_eq!(undo_history.len(), 2);
+        assert_eq!(
+            undo_history[0].description(),
+            "TestArcCommand: Increment(2)"
+        );
+        assert_eq!(
+            undo_history[1].description(),
+            "TestArcCommand: Increment(1)"
+        );
+    }
+
+    #[test]
+    fn test_redo_history() {
+        let history = ConcurrentCommandHistory::new(NonZeroUsize::new(5).unwrap(), true);
+        let ctx = SharedContext::new(TestArcContext { value: 0 });
+        let command1 = TestArcCommand {
+            operation: TestOperation::Increment(1),
+        };
+        let command2 = TestArcCommand {
+            operation: TestOperation::Increment(2),
+        };
+
+        history.execute_command(command1, &ctx);
+        history.execute_command(command2, &ctx);
+        history.undo(&ctx);
+        history.undo(&ctx);
+
+        let redo_history = history.redo_history().unwrap();
+        assert_eq!(redo_history.len(), 2);
+        assert_eq!(
+            redo_history[0].description(),
+            "TestArcCommand: Increment(1)"
+        );
+        assert_eq!(
+            redo_history[1].description(),
+            "TestArcCommand: Increment(2)"
+        );
+    }
+
+    #[test]
+    fn test_replay() {
+        let history = ConcurrentCommandHistory::new(NonZeroUsize::new(5).unwrap(), true);
+        let ctx = SharedContext::new(TestArcContext { value: 0 });
+
+        history.execute_command(
+            TestArcCommand {
+                operation: TestOperation::Increment(5),
+            },
+            &ctx,
+        );
+        history.execute_command(
+            TestArcCommand {
+                operation: TestOperation::Decrement(2),
+            },
+            &ctx,
+        );
+
+        let fresh = SharedContext::new(TestArcContext { value: 0 });
+        assert_eq!(history.replay(&fresh, None), 2);
+        assert_eq!(fresh.lock().value, ctx.lock().value);
+
+        let partial = SharedContext::new(TestArcContext { value: 0 });
+        assert_eq!(history.replay(&partial, Some(1)), 1);
+        assert_eq!(partial.lock().value, 5);
+        assert_eq!(history.undo.read().len(), 2);
+    }
+
+    #[test]
+    fn test_replay_with_divergence() {
+        let history = ConcurrentCommandHistory::new(NonZeroUsize::new(5).unwrap(), true);
+        let ctx = SharedContext::new(TestArcContext { value: 0 });
+
+        for value in 1..=3 {
+            history.execute_command(
+                TestArcCommand {
+                    operation: TestOperation::Increment(value),
+                },
+                &ctx,
+            );
+        }
+
+        let fresh = SharedContext::new(TestArcContext { value: 0 });
+        let divergence = history
+            .replay_with(&fresh, None, |position, _, ctx| {
+                ctx.lock().value < 3 || position == 0
+            })
+            .unwrap_err();
+
+        assert_eq!(divergence.position, 1);
+        assert_eq!(divergence.description, "TestArcCommand: Increment(2)");
+        assert_eq!(fresh.lock().value, 3);
+    }
+
+    struct CountingCommand {
+        value: i32,
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl Command for CountingCommand {
+        type Context = SharedContext<TestArcContext>;
+        fn execute(&self, ctx: &Self::Context) {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            ctx.lock().value += self.value;
+        }
+
+        fn undo(&self, ctx: &Self::Context) {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            ctx.lock().value -= self.value;
+        }
+    }
+
+    #[test]
+    fn test_go_to_restores_nearest_checkpoint() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let history = ConcurrentCommandHistory::new(NonZeroUsize::new(100).unwrap(), true);
+        history.enable_checkpoints(CheckpointConfig::new(
+            CheckpointInterval::Commands(NonZeroUsize::new(10).unwrap()),
+            0,
+        ));
+        let ctx = SharedContext::new(TestArcContext { value: 0 });
+
+        let commands = (0..50)
+            .map(|_| CountingCommand {
+                value: 1,
+                calls: Arc::clone(&calls),
+            })
+            .collect();
+        history.batch_execute(commands, &ctx);
+        assert_eq!(history.checkpoint_count(), 5);
+
+        calls.store(0, Ordering::Relaxed);
+        assert!(history.go_to(13, &ctx));
+        assert_eq!(ctx.lock().value, 13);
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+        assert_eq!(history.position(), 13);
+        assert_eq!(history.redo.read().len(), 37);
+
+        calls.store(0, Ordering::Relaxed);
+        assert!(history.go_to(50, &ctx));
+        assert_eq!(ctx.lock().value, 50);
+        assert_eq!(calls.load(Ordering::Relaxed), 0);
+
+        assert!(!history.go_to(51, &ctx));
+    }
+
+    #[test]
+    fn test_go_to_after_compaction() {
+        for snapshots in [false, true] {
+            let history = ConcurrentCommandHistory::new(NonZeroUsize::new(4).unwrap(), true);
+            let batch = NonZeroUsize::new(3).unwrap();
+            if snapshots {
+                history.enable_snapshot_compaction(batch);
+            } else {
+                history.enable_compaction(batch);
+            }
+            history.enable_checkpoints(CheckpointConfig::new(
+                CheckpointInterval::Commands(NonZeroUsize::new(1).unwrap()),
+                0,
+            ));
+            let ctx = SharedContext::new(TestArcContext { value: 0 });
+
+            for value in 1..=6 {
+                history.execute_command(increment(value), &ctx);
+            }
+            assert_eq!(history.position(), 4);
+            assert_eq!(history.checkpoint_count(), 4);
+
+            for (position, expected) in [(0, 0), (2, 10), (1, 6), (4, 21), (3, 15)] {
+                assert!(history.go_to(position, &ctx));
+                assert_eq!(ctx.lock().value, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_checkpoints_count_toward_limit() {
+        let history = ConcurrentCommandHistory::new(NonZeroUsize::new(10).unwrap(), true);
+        history.enable_checkpoints(CheckpointConfig::new(
+            CheckpointInterval::Commands(NonZeroUsize::new(3).unwrap()),
+            2,
+        ));
+        let ctx = SharedContext::new(TestArcContext { value: 0 });
+
+        for _ in 0..9 {
+            history.execute_command(
+                TestArcCommand {
+                    operation: TestOperation::Increment(1),
+                },
+                &ctx,
+            );
+        }
+
+        assert_eq!(history.checkpoint_count(), 2);
+        assert_eq!(history.undo.read().len(), 5);
+
+        assert!(history.go_to(0, &ctx));
+        assert_eq!(ctx.lock().value, 4);
+        assert!(history.go_to(2, &ctx));
+        assert_eq!(ctx.lock().value, 6);
+        assert!(history.go_to(5, &ctx));
+        assert_eq!(ctx.lock().value, 9);
+    }
+
+    #[test]
+    fn test_entry_metadata_survives_undo_redo() {
+        let history = ConcurrentCommandHistory::new(NonZeroUsize::new(5).unwrap(), true);
+        let ctx = SharedContext::new(TestArcContext { value: 0 });
+
+        history
+            .execute_with_metadata(
+                TestArcCommand {
+                    operation: TestOperation::Increment(4),
+                },
+                &ctx,
+                EntryMetadata::new().author("bob").tag("import"),
+            )
+            .unwrap();
+
+        assert!(history.update_undo_entry(0, |entry| entry.set_note(Some("bulk".to_owned()))));
+        assert!(!history.update_undo_entry(1, |entry| entry.add_tag("missing")));
+
+        history.undo(&ctx);
+        let entry = history.redo_entries().unwrap().remove(0);
+        assert_eq!(entry.author(), Some("bob"));
+        assert_eq!(entry.note(), Some("bulk"));
+        assert!(entry.last_moved_at().is_some());
+
+        history.redo(&ctx);
+        let entry = history.undo_entries().unwrap().remove(0);
+        assert_eq!(entry.tags(), ["import"]);
+        assert_eq!(
+            entry.command().unwrap().description(),
+            "TestArcCommand: Increment(4)"
+        );
+        assert_eq!(ctx.lock().value, 4);
+    }
+
+    #[test]
+    fn test_retention_policy() {
+        let history = ConcurrentCommandHistory::new(NonZeroUsize::new(10).unwrap(), true);
+        history.set_retention_policy(CategoryQuota::by_prefix().quota("TestArcCommand", 3));
+        let ctx = SharedContext::new(TestArcContext { value: 0 });
+
+        for value in 1..=5 {
+            history.execute_command(
+                TestArcCommand {
+                    operation: TestOperation::Increment(value),
+                },
+                &ctx,
+            );
+        }
+
+        assert_eq!(ctx.lock().value, 15);
+        assert_eq!(history.undo.read().len(), 3);
+
+        history.set_retention_policy(RefuseWhenFull(MaxCount(3)));
+        let refused = history.try_execute_command(
+            TestArcCommand {
+                operation: TestOperation::Increment(6),
+            },
+            &ctx,
+        );
+        assert!(matches!(refused, Err(HistoryError::Refused { .. })));
+        assert_eq!(ctx.lock().value, 15);
+
+        history.set_retention_policy(RefuseWhenFull(MaxCount(10)));
+        history.set_history_limit(NonZeroUsize::new(3).unwrap());
+        assert!(history.try_execute_command(increment(6), &ctx).is_err());
+        assert_eq!((history.position(), ctx.lock().value), (3, 15));
+
+        history.undo(&ctx);
+        history.set_retention_policy(MaxCount(1));
+        assert_eq!(history.undo.read().len(), 1);
+        assert_eq!(history.redo.read().len(), 1);
+        assert_eq!(history.enforce_retention(), 0);
+    }
+
+    fn increment(value: i32) -> TestArcCommand {
+        TestArcCommand {
+            operation: TestOperation::Increment(value),
+        }
+    }
+
+    #[test]
+    fn test_compound_compaction() {
+        let history = ConcurrentCommandHistory::new(NonZeroUsize::new(3).unwrap(), true);
+        history.enable_compaction(NonZeroUsize::new(2).unwrap());
+        let ctx = SharedContext::new(TestArcContext { value: 0 });
+
+        for value in 1..=5 {
+            history.execute_command(increment(value), &ctx);
+        }
+
+        assert_eq!(history.undo.read().len(), 3);
+        assert_eq!(history.undo_history().unwrap().len(), 5);
+        assert_eq!(
+            history.undo_entries().unwrap()[2].command_count(),
+            3,
+            "the oldest entry should hold the first three commands"
+        );
+
+        history.undo(&ctx);
+        history.undo(&ctx);
+        assert_eq!(ctx.lock().value, 6);
+        history.undo(&ctx);
+        assert_eq!(ctx.lock().value, 0);
+
+        history.redo(&ctx);
+        assert_eq!(ctx.lock().value, 6);
+        assert_eq!(
+            history.replay(&SharedContext::new(TestArcContext { value: 0 }), Some(2)),
+            2
+        );
+    }
+
+    #[test]
+    fn test_snapshot_compaction() {
+        let history = ConcurrentCommandHistory::new(NonZeroUsize::new(3).unwrap(), true);
+        history.enable_snapshot_compaction(NonZeroUsize::new(2).unwrap());
+        let ctx = SharedContext::new(TestArcContext { value: 0 });
+
+        for value in 1..=6 {
+            history.execute_command(increment(value), &ctx);
+        }
+
+        assert_eq!(history.undo.read().len(), 3);
+        assert!(history.undo_history().unwrap().len() < 6);
+
+        let replayed = SharedContext::new(TestArcContext { value: 0 });
+        assert_eq!(history.replay(&replayed, None), 6);
+        assert_eq!(replayed.lock().value, 21);
+
+        let partial = SharedContext::new(TestArcContext { value: 0 });
+        assert_eq!(history.replay(&partial, Some(1)), 0);
+        assert_eq!(partial.lock().value, 0);
+
+        let mut steps = Vec::new();
+        let fresh = SharedContext::new(TestArcContext { value: 0 });
+        let divergence = history
+            .replay_with(&fresh, None, |position, command, ctx| {
+                steps.push((position, command.is_some(), ctx.lock().value));
+                ctx.lock().value < 10
+            })
+            .unwrap_err();
+        assert_eq!(steps, [(3, false, 10)]);
+        assert_eq!(divergence.position, 3);
+
+        for _ in 0..3 {
+            history.undo(&ctx);
+        }
+        assert_eq!(ctx.lock().value, 0);
+
+        for _ in 0..3 {
+            history.redo(&ctx);
+        }
+        assert_eq!(ctx.lock().value, 21);
+    }
+
+    impl Spill for TestArcCommand {
+        fn encode(&self, out: &mut Vec<u8>) {
+            let (kind, value) = match self.operation {
+                TestOperation::Increment(value) => (0, value),
+                TestOperation::Decrement(value) => (1, value),
+            };
+            out.push(kind);
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+
+        fn decode(bytes: &[u8]) -> Option<Self> {
+            let (kind, value) = bytes.split_first()?;
+            let value = i32::from_le_bytes(value.try_into().ok()?);
+            let operation = match kind {
+                0 => TestOperation::Increment(value),
+                1 => TestOperation::Decrement(value),
+                _ => return None,
+            };
+
+            Some(TestArcCommand { operation })
+        }
+    }
+
+    #[test]
+    fn test_spill_and_load_back() {
+        let path = std::env::temp_dir().join(format!(
+            "command_history_concurrent_{}.spill",
+            std::process::id()
+        ));
+        let history = ConcurrentCommandHistory::new(NonZeroUsize::new(2).unwrap(), true);
+        history.enable_spill(&path).unwrap();
+        let ctx = SharedContext::new(TestArcContext { value: 0 });
+
+        for value in 1..=5 {
+            history.execute_command(increment(value), &ctx);
+        }
+        history.execute_command(
+            TestArcCommand {
+                operation: TestOperation::Decrement(5),
+            },
+            &ctx,
+        );
+
+        assert_eq!(history.undo.read().len(), 2);
+        assert_eq!(history.spilled_len(), 4);
+
+        std::fs::remove_file(&path).unwrap();
+        history.undo(&ctx);
+        history.undo(&ctx);
+        assert!(matches!(
+            history.try_undo(&ctx),
+            Err(HistoryError::SpillFileMissing { .. })
+        ));
+        assert_eq!(ctx.lock().value, 10);
+
+        history.disable_spill();
+        history.enable_spill(&path).unwrap();
+        history.redo(&ctx);
+        history.redo(&ctx);
+        history.execute_command(increment(1), &ctx);
+        assert_eq!(ctx.lock().value, 11);
+        assert_eq!(history.spilled_len(), 1);
+
+        for _ in 0..4 {
+            history.try_undo(&ctx).unwrap();
+        }
+        assert_eq!(ctx.lock().value, 10);
+        assert_eq!(history.redo_history().unwrap().len(), 2);
+        assert!(history.take_spill_error().is_none());
+    }
+
+    #[test]
+    fn test_scope_commit_and_abort() {
+        let history = ConcurrentCommandHistory::new(NonZeroUsize::new(2).unwrap(), true);
+        let ctx = SharedContext::new(TestArcContext { value: 0 });
+        history.execute_command(increment(1), &ctx);
+
+        history.begin_scope();
+        for value in [10, 20, 30] {
+            history.execute_command(increment(value), &ctx);
+        }
+        assert_eq!(history.position(), 3);
+        assert!(history.commit_scope(&ctx));
+        assert_eq!(history.position(), 2);
+
+        history.begin_scope();
+        history.execute_command(increment(100), &ctx);
+        history.execute_command(increment(200), &ctx);
+        assert!(history.abort_scope(&ctx));
+        assert!(!history.abort_scope(&ctx));
+        assert_eq!(ctx.lock().value, 61);
+
+        history.undo(&ctx);
+        assert_eq!(ctx.lock().value, 1);
+        assert_eq!(history.redo_history().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_scopes_suspend_compaction() {
+        let history = ConcurrentCommandHistory::new(NonZeroUsize::new(10).unwrap(), true);
+        history.enable_snapshot_compaction(NonZeroUsize::new(2).unwrap());
+        let ctx = SharedContext::new(TestArcContext { value: 0 });
+
+        history.begin_scope();
+        history.set_history_limit(NonZeroUsize::new(2).unwrap());
+        history.set_retention_policy(MaxCount(1));
+        for value in 1..=5 {
+            history.execute_command(increment(value), &ctx);
+        }
+        assert_eq!(history.position(), 5);
+        assert!(history.commit_scope(&ctx));
+
+        assert_eq!((history.position(), ctx.lock().value), (1, 15));
+        history.undo(&ctx);
+        assert_eq!(ctx.lock().value, 0);
+    }
+
+    struct Fragile {
+        value: i32,
+        panics_on: Option<PanicStage>,
+    }
+
+    impl Command for Fragile {
+        type Context = SharedContext<Vec<i32>>;
+
+        fn execute(&self, ctx: &Self::Context) {
+            self.check(PanicStage::Execute);
+            ctx.lock().push(self.value);
+        }
+
+        fn undo(&self, ctx: &Self::Context) {
+            self.check(PanicStage::Undo);
+            ctx.lock().pop();
+        }
+
+        fn redo(&self, ctx: &Self::Context) {
+            self.check(PanicStage::Redo);
+            ctx.lock().push(self.value);
+        }
+    }
+
+    impl Fragile {
+        fn check(&self, stage: PanicStage) {
+            assert!(self.panics_on != Some(stage), "{stage} failed");
+        }
+    }
+
+    fn fragile(value: i32, panics_on: Option<PanicStage>) -> Fragile {
+        Fragile { value, panics_on }
+    }
+
+    #[test]
+    fn test_panic_policy_keeps_stacks_consistent() {
+        for policy in [
+            PanicPolicy::Propagate,
+            PanicPolicy::Restore,
+            PanicPolicy::Discard,
+        ] {
+            let history = ConcurrentCommandHistory::new(NonZeroUsize::new(10).unwrap(), true);
+            history.set_panic_policy(policy);
+            let ctx = SharedContext::new(Vec::new());
+            let caught = |result: std::thread::Result<Result<(), HistoryError>>, stage| {
+                if policy == PanicPolicy::Propagate {
+                    assert!(result.is_err());
+                } else {
+                    let error = result.unwrap().unwrap_err();
+                    assert!(matches!(error, HistoryError::Panicked { stage: s, .. } if s == stage));
+                }
+            };
+
+            history.execute_command(fragile(1, Some(PanicStage::Redo)), &ctx);
+            history.execute_command(fragile(2, Some(PanicStage::Undo)), &ctx);
+            caught(
+                std::panic::catch_unwind(AssertUnwindSafe(|| {
+                    history.try_execute_command(fragile(3, Some(PanicStage::Execute)), &ctx)
+                })),
+                PanicStage::Execute,
+            );
+            assert_eq!(history.position(), 2);
+
+            caught(
+                std::panic::catch_unwind(AssertUnwindSafe(|| history.try_undo(&ctx))),
+                PanicStage::Undo,
+            );
+            let after_undo = if policy == PanicPolicy::Discard { 1 } else { 2 };
+            assert_eq!(history.position(), after_undo);
+            assert_eq!(*ctx.lock(), [1, 2]);
+
+            if policy == PanicPolicy::Discard {
+                history.undo(&ctx);
+                caught(
+                    std::panic::catch_unwind(AssertUnwindSafe(|| history.try_redo(&ctx))),
+                    PanicStage::Redo,
+                );
+                assert_eq!(history.position(), 0);
+                assert!(history.redo_history().is_none());
+                assert_eq!(*ctx.lock(), [1]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_panic_in_batch_stops_the_batch() {
+        let history = ConcurrentCommandHistory::new(NonZeroUsize::new(10).unwrap(), true);
+        history.set_panic_policy(PanicPolicy::Restore);
+        let ctx = SharedContext::new(Vec::new());
+
+        history.batch_execute(
+            vec![
+                fragile(1, None),
+                fragile(2, Some(PanicStage::Execute)),
+                fragile(3, None),
+            ],
+            &ctx,
+        );
+        assert_eq!(*ctx.lock(), [1]);
+        assert_eq!(history.position(), 1);
+
+        history.set_panic_policy(PanicPolicy::Propagate);
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            history.batch_execute(vec![fragile(4, Some(PanicStage::Execute))], &ctx);
+        }));
+        assert!(result.is_err());
+        history.execute_command(fragile(5, None), &ctx);
+        assert_eq!(history.position(), 2);
+    }
+
+    enum Edit {
+        Push(i32),
+        /// Pushes its value and then issues a child command through the history.
+        Echo(i32, Weak<ConcurrentCommandHistory<Edit>>),
+        /// Meets the test thread at the barrier before and after holding the history.
+        Wait(Arc<Barrier>),
+        /// Pushes the position of the history it runs in.
+        Peek(Weak<ConcurrentCommandHistory<Edit>>),
+        /// Reads and reconfigures the history it runs in, and tries to open and close scopes.
+        Meddle(Weak<ConcurrentCommandHistory<Edit>>),
+    }
+
+    impl Command for Edit {
+        type Context = SharedContext<Vec<i32>>;
+
+        fn execute(&self, ctx: &Self::Context) {
+            match self {
+                Edit::Push(value) => ctx.lock().push(*value),
+                Edit::Echo(value, history) => {
+                    ctx.lock().push(*value);
+
+                    let history = history.upgrade().unwrap();
+                    assert!(history.is_reentrant() && history.is_busy());
+                    assert_eq!(history.try_undo(ctx), Err(HistoryError::Reentrant));
+                    history.execute_command(Edit::Push(value * 10), ctx);
+                }
+                Edit::Wait(barrier) => {
+                    barrier.wait();
+                    barrier.wait();
+                }
+                Edit::Peek(history) => {
+                    let position = history.upgrade().unwrap().position();
+                    ctx.lock().push(i32::try_from(position).unwrap());
+                }
+                Edit::Meddle(history) => {
+                    let history = history.upgrade().unwrap();
+                    assert_eq!(history.undo_history().unwrap().len(), 1);
+                    assert_eq!(history.undo_entries().unwrap().len(), 1);
+                    assert!(history.redo_history().is_none() && history.redo_entries().is_none());
+                    assert!(history.update_undo_entry(0, |entry| entry.add_tag("seen")));
+                    assert_eq!(history.scope_depth(), 0);
+
+                    assert!(!history.begin_scope());
+                    assert!(!history.commit_scope(ctx));
+                    assert!(!history.abort_scope(ctx));
+                    assert_eq!(history.enforce_retention(), 0);
+                    history.set_history_limit(NonZeroUsize::new(2).unwrap());
+                    assert_eq!(history.position(), 1);
+                }
+            }
+        }
+
+        fn undo(&self, ctx: &Self::Context) {
+            ctx.lock().pop();
+        }
+    }
+
+    #[test]
+    fn test_reentrant_children_share_the_parent_entry() {
+        let history = ConcurrentCommandHistory::new(NonZeroUsize::new(5).unwrap(), true);
+        let ctx = SharedContext::new(Vec::new());
+        let echo = |value| Edit::Echo(value, Arc::downgrade(&history));
+
+        history.execute_command(Edit::Push(1), &ctx);
+        history.execute_command(echo(2), &ctx);
+        assert_eq!(*ctx.lock(), [1, 2, 20]);
+        assert_eq!(history.position(), 2);
+        assert!(!history.is_reentrant() && !history.is_busy());
+
+        history.undo(&ctx);
+        assert_eq!(*ctx.lock(), [1]);
+        history.redo(&ctx);
+        assert_eq!(*ctx.lock(), [1, 2, 20]);
+
+        history.batch_execute(vec![echo(3), Edit::Push(4)], &ctx);
+        assert_eq!(*ctx.lock(), [1, 2, 20, 3, 30, 4]);
+        assert_eq!(history.position(), 4);
+        assert_eq!(history.replay(&SharedContext::default(), None), 6);
+    }
+
+    #[test]
+    fn test_commands_can_read_the_history() {
+        let history = ConcurrentCommandHistory::new(NonZeroUsize::new(5).unwrap(), true);
+        let ctx = SharedContext::new(Vec::new());
+        let peek = || Edit::Peek(Arc::downgrade(&history));
+
+        history.execute_command(Edit::Push(1), &ctx);
+        history.execute_command(peek(), &ctx);
+        assert_eq!(*ctx.lock(), [1, 1]);
+
+        history.undo(&ctx);
+        history.redo(&ctx);
+        assert_eq!(*ctx.lock(), [1, 1]);
+
+        history.batch_execute(vec![peek(), peek()], &ctx);
+        assert_eq!(*ctx.lock(), [1, 1, 2, 3]);
+        assert_eq!(history.position(), 4);
+    }
+
+    #[test]
+    fn test_history_can_be_used_from_inside_a_command() {
+        let history = ConcurrentCommandHistory::new(NonZeroUsize::new(5).unwrap(), true);
+        let ctx = SharedContext::new(Vec::new());
+
+        history.execute_command(Edit::Push(1), &ctx);
+        history.execute_command(Edit::Meddle(Arc::downgrade(&history)), &ctx);
+        assert_eq!(history.scope_depth(), 0);
+        assert_eq!(history.undo_entries().unwrap()[1].tags(), ["seen"]);
+
+        history.execute_command(Edit::Push(2), &ctx);
+        history.execute_command(Edit::Push(3), &ctx);
+        assert_eq!(history.position(), 2);
+    }
+
+    #[test]
+    fn test_busy_history_gives_up() {
+        let history = ConcurrentCommandHistory::new(NonZeroUsize::new(5).unwrap(), true);
+        let ctx = SharedContext::new(Vec::new());
+        history.execute_command(Edit::Push(1), &ctx);
+
+        let barrier = Arc::new(Barrier::new(2));
+        let worker = {
+            let (history, ctx, barrier) = (Arc::clone(&history), ctx.clone(), Arc::clone(&barrier));
+            thread::spawn(move || history.execute_command(Edit::Wait(barrier), &ctx))
         };
 
-        history.execute_command(command1, &ctx);
-        assert_eq!(ctx.lock().value, 1);
-        assert_eq!(history.undo.read().len(), 1);
-        assert!(history.redo.read().is_empty());
+        barrier.wait();
+        assert!(history.is_busy() && !history.is_reentrant());
+        assert_eq!(history.try_undo(&ctx), Err(HistoryError::Busy));
+        assert_eq!(history.try_redo(&ctx), Err(HistoryError::Busy));
+        assert_eq!(
+            history.undo_for(&ctx, time::Duration::from_millis(10)),
+            Err(HistoryError::Busy)
+        );
+        assert_eq!(
+            history.try_execute_command(Edit::Push(2), &ctx),
+            Err(HistoryError::Busy)
+        );
+        barrier.wait();
+        worker.join().unwrap();
+
+        assert_eq!(history.position(), 2);
+        history
+            .execute_command_for(Edit::Push(3), &ctx, time::Duration::from_secs(5))
+            .unwrap();
+        history.undo_for(&ctx, time::Duration::MAX).unwrap();
+        history.try_redo(&ctx).unwrap();
+        assert_eq!(*ctx.lock(), [1, 3]);
+    }
 
-        history.execute_command(command2, &ctx);
-        assert_eq!(ctx.lock().value, 3);
-        assert_eq!(history.undo.read().len(), 2);
-        assert!(history.redo.read().is_empty());
+    #[test]
+    fn test_locked_context_gives_up() {
+        let history = ConcurrentCommandHistory::new(NonZeroUsize::new(5).unwrap(), true);
+        let ctx = SharedContext::new(Vec::new());
+        history.enable_context_deadlines();
+        history.execute_command(Edit::Push(1), &ctx);
+        history.execute_command(Edit::Push(2), &ctx);
+        history.undo(&ctx);
 
-        history.execute_command(command3, &ctx);
-        assert_eq!(ctx.lock().value, 6);
-        assert_eq!(history.undo.read().len(), 2);
-        assert!(history.redo.read().is_empty());
+        let guard = ctx.lock();
+        assert_eq!(history.try_undo(&ctx), Err(HistoryError::Busy));
+        assert_eq!(history.try_redo(&ctx), Err(HistoryError::Busy));
+        assert_eq!(
+            history.execute_command_for(Edit::Push(3), &ctx, time::Duration::from_millis(10)),
+            Err(HistoryError::Busy)
+        );
+        drop(guard);
 
-        history.set_history_limit(NonZero::new(1).unwrap());
-        assert_eq!(history.undo.read().len(), 1);
-        assert!(history.redo.read().is_empty());
+        assert_eq!(
+            (history.position(), history.redo_history().unwrap().len()),
+            (1, 1)
+        );
+        history.try_redo(&ctx).unwrap();
+        history
+            .execute_command_for(Edit::Push(3), &ctx, time::Duration::from_secs(5))
+            .unwrap();
+        history.undo_for(&ctx, time::Duration::MAX).unwrap();
+        assert_eq!(*ctx.lock(), [1, 2]);
     }
 
     #[test]
-    fn test_arc_concurrent_rng() {
-        let size = rand::thread_rng().gen_range(5..40);
-        let history = ConcurrentCommandHistory::new(NonZero::new(size).unwrap(), true);
+    fn test_undo_if_unchanged_detects_outside_changes() {
+        let history = ConcurrentCommandHistory::new(NonZeroUsize::new(5).unwrap(), true);
         let ctx = SharedContext::new(TestArcContext { value: 0 });
+        history.enable_version_checks();
 
-        let mut handles = Vec::new();
-        let count = rand::thread_rng().gen_range(10..80);
+        history.execute_command(increment(1), &ctx);
+        history.execute_command(increment(2), &ctx);
+        history.undo_if_unchanged(&ctx).unwrap();
+        assert_eq!(ctx.lock().value, 1);
 
-        for _ in 0..count {
-            let value = rand::thread_rng().gen_range(0..20);
-            let command = TestArcCommand {
-                operation: TestOperation::Increment(value),
-            };
-            let history_clone = history.clone();
-            let ctx_clone = ctx.clone();
+        ctx.lock().value = 10;
+        assert_eq!(
+            history.undo_if_unchanged(&ctx),
+            Err(HistoryError::Conflict {
+                description: String::from("TestArcCommand: Increment(1)"),
+                recorded: Some(ctx.version() - 1),
+                current: ctx.version(),
+            })
+        );
+        assert_eq!((history.position(), ctx.lock().value), (1, 10));
 
-            handles.push(black_box(thread::spawn(move || {
-                thread::sleep(time::Duration::from_millis(
-                    rand::thread_rng().gen_range(0..250),
-                ));
-                history_clone.execute_command(command, &ctx_clone);
-                value
-            })));
-        }
+        history.redo(&ctx);
+        history.undo_if_unchanged(&ctx).unwrap();
+        history.undo_if_unchanged(&ctx).unwrap();
+        assert_eq!((history.position(), ctx.lock().value), (0, 9));
+    }
 
-        let mut sum = 0;
-        for handle in handles {
-            let result = handle.join();
-            assert!(result.is_ok(), "Thread panicked during execution");
-            sum += result.expect("Thread should return a value");
-        }
+    /// Logs which lifecycle hooks were called on it.
+    struct Tracked {
+        value: i32,
+        log: Arc<Mutex<Vec<(i32, &'static str)>>>,
+    }
 
-        assert_eq!(ctx.lock().value, sum);
-        assert_eq!(history.undo.read().len(), min(size, count));
-        assert!(history.redo.read().is_empty());
+    impl Command for Tracked {
+        type Context = SharedContext<Vec<i32>>;
 
-        let mut handles = Vec::new();
-        for _ in 0..size {
-            let history_clone = Arc::clone(&history);
-            let ctx_clone = ctx.clone();
+        fn execute(&self, ctx: &Self::Context) {
+            assert!(self.value >= 0, "negative value");
+            ctx.lock().push(self.value);
+        }
 
-            handles.push(black_box(thread::spawn(move || {
-                thread::sleep(time::Duration::from_millis(
-                    rand::thread_rng().gen_range(0..200),
-                ));
-                history_clone.undo(&ctx_clone);
-                thread::sleep(time::Duration::from_millis(
-                    rand::thread_rng().gen_range(0..200),
-                ));
-                history_clone.redo(&ctx_clone);
-            })));
+        fn undo(&self, ctx: &Self::Context) {
+            ctx.lock().pop();
         }
 
-        for handle in handles {
-            assert!(handle.join().is_ok(), "Thread panicked during execution");
+        fn on_evicted(&self) {
+            self.log.lock().push((self.value, "evicted"));
         }
 
-        assert_eq!(ctx.lock().value, sum);
-        assert_eq!(
-            history.undo.read().len(),
-            min(size, count),
-            "Undo stack size after execution"
-        );
-        assert!(
-            history.redo.read().is_empty(),
-            "Redo stack should be empty after execution"
-        );
+        fn on_discarded(&self) {
+            self.log.lock().push((self.value, "discarded"));
+        }
+
+        fn on_finalized(&self) {
+            self.log.lock().push((self.value, "finalized"));
+        }
     }
 
     #[test]
-    fn test_command_clears_redo() {
-        let history = ConcurrentCommandHistory::new(NonZero::new(5).unwrap(), true);
-        let ctx = SharedContext::new(TestArcContext { value: 0 });
-        let command = TestArcCommand {
-            operation: TestOperation::Increment(1),
-        };
-        let command2 = TestArcCommand {
-            operation: TestOperation::Increment(1),
+    fn test_lifecycle_hooks() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let tracked = |value| Tracked {
+            value,
+            log: Arc::clone(&log),
         };
+        let history = ConcurrentCommandHistory::new(NonZeroUsize::new(2).unwrap(), true);
+        let ctx = SharedContext::new(Vec::new());
 
-        history.execute_command(command, &ctx);
-        assert_eq!(ctx.lock().value, 1);
-        assert_eq!(history.undo.read().len(), 1);
-        assert!(history.redo.read().is_empty());
+        history.batch_execute((1..=3).map(tracked).collect(), &ctx);
+        assert_eq!(*log.lock(), [(1, "evicted")]);
 
         history.undo(&ctx);
-        assert_eq!(ctx.lock().value, 0);
-        assert!(history.undo.read().is_empty());
-        assert_eq!(history.redo.read().len(), 1);
+        history.undo(&ctx);
+        history.execute_command(tracked(4), &ctx);
+        assert_eq!(log.lock()[1..], [(2, "discarded"), (3, "discarded")]);
 
-        history.execute_command(command2, &ctx);
-        assert_eq!(ctx.lock().value, 1);
-        assert_eq!(history.undo.read().len(), 1);
-        assert!(history.redo.read().is_empty());
+        drop(history);
+        assert_eq!(log.lock()[3..], [(4, "finalized")]);
+        assert_eq!(*ctx.lock(), [1, 4]);
     }
 
     #[test]
-    fn test_command_dont_clears_redo() {
-        let history = ConcurrentCommandHistory::new(NonZero::new(5).unwrap(), false);
-        let ctx = SharedContext::new(TestArcContext { value: 0 });
-        let command = TestArcCommand {
-            operation: TestOperation::Increment(1),
-        };
-        let command2 = TestArcCommand {
-            operation: TestOperation::Increment(1),
+    fn test_discarded_hook_when_execute_panics() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let tracked = |value| Tracked {
+            value,
+            log: Arc::clone(&log),
         };
+        let history = ConcurrentCommandHistory::new(NonZeroUsize::new(10).unwrap(), true);
+        history.set_panic_policy(PanicPolicy::Discard);
+        let ctx = SharedContext::new(Vec::new());
+
+        assert!(matches!(
+            history.try_execute_command(tracked(-1), &ctx),
+            Err(HistoryError::Panicked { .. })
+        ));
+        history.batch_execute(vec![tracked(2), tracked(-3), tracked(4)], &ctx);
+        assert_eq!(*log.lock(), [(-1, "discarded"), (-3, "discarded")]);
+        assert_eq!((history.position(), ctx.lock().clone()), (1, vec![2]));
+    }
 
-        history.execute_command(command, &ctx);
-        assert_eq!(ctx.lock().value, 1);
-        assert_eq!(history.undo.read().len(), 1);
-        assert!(history.redo.read().is_empty());
-
-        history.undo(&ctx);
-        assert_eq!(ctx.lock().value, 0);
-        assert!(history.undo.read().is_empty());
-        assert_eq!(history.redo.read().len(), 1);
+    #[test]
+    fn test_discarded_hook_when_execute_breaks_the_invariant() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let history = ConcurrentCommandHistory::new(NonZeroUsize::new(10).unwrap(), true);
+        history.set_invariant(|ctx: &SharedContext<Vec<i32>>| {
+            if ctx.lock().len() > 1 {
+                Err(String::from("more than one value"))
+            } else {
+                Ok(())
+            }
+        });
+        let ctx = SharedContext::new(Vec::new());
+        let tracked = |value| Tracked {
+            value,
+            log: Arc::clone(&log),
+        };
 
-        history.execute_command(command2, &ctx);
-        assert_eq!(ctx.lock().value, 1);
-        assert_eq!(history.undo.read().len(), 1);
-        assert_eq!(history.redo.read().len(), 1);
+        history.execute_command(tracked(1), &ctx);
+        assert!(matches!(
+            history.try_execute_command(tracked(2), &ctx),
+            Err(HistoryError::InvariantViolated { .. })
+        ));
+        assert_eq!(*log.lock(), [(2, "discarded")]);
+        assert_eq!(*ctx.lock(), [1]);
     }
 
     #[test]
-    fn test_panic_safety() {
-        let history = ConcurrentCommandHistory::new(NonZero::new(85).unwrap(), true);
+    fn test_invariant_reverts_the_breaking_step() {
+        let history = ConcurrentCommandHistory::new(NonZeroUsize::new(10).unwrap(), true);
         let ctx = SharedContext::new(TestArcContext { value: 0 });
-        let command = TestArcCommand {
-            operation: TestOperation::Increment(1),
-        };
+        history.set_invariant(|ctx: &SharedContext<TestArcContext>| {
+            let value = ctx.lock().value;
+            if value < 0 {
+                Err(format!("value is {value}"))
+            } else {
+                Ok(())
+            }
+        });
 
-        let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
-            history.execute_command(command, &ctx);
-            panic!("Simulated panic");
-        }));
+        history.execute_command(increment(5), &ctx);
+        let decrement = TestArcCommand {
+            operation: TestOperation::Decrement(10),
+        };
+        assert_eq!(
+            history.try_execute_command(decrement, &ctx),
+            Err(HistoryError::InvariantViolated {
+                description: String::from("TestArcCommand: Decrement(10)"),
+                violation: String::from("value is -5"),
+            })
+        );
 
-        assert!(result.is_err()); // Ensure panic was caught
-        assert_eq!(ctx.lock().value, 1); // Ensure state is consistent
+        ctx.lock().value = 2;
+        assert!(matches!(
+            history.try_undo(&ctx),
+            Err(HistoryError::InvariantViolated { .. })
+        ));
+        assert!(!history.go_to(0, &ctx));
+        assert_eq!((history.position(), ctx.lock().value), (1, 2));
     }
 
     #[test]
-    fn test_undo_history() {
-        let history = ConcurrentCommandHistory::new(NonZeroUsize::new(5).unwrap(), true);
+    fn test_mirror_follows_history_until_it_diverges() {
+        let history = ConcurrentCommandHistory::new(NonZeroUsize::new(10).unwrap(), true);
         let ctx = SharedContext::new(TestArcContext { value: 0 });
-        let command1 = TestArcCommand {
-            operation: TestOperation::Increment(1),
-        };
-        let command2 = TestArcCommand {
-            operation: TestOperation::Increment(2),
-        };
+        let mirror = SharedContext::new(TestArcContext { value: 0 });
+        let id = history.add_mirror(mirror.clone());
+        history.set_invariant(|ctx: &SharedContext<TestArcContext>| {
+            let value = ctx.lock().value;
+            if value < 0 {
+                Err(format!("value is {value}"))
+            } else {
+                Ok(())
+            }
+        });
 
-        history.execute_command(command1, &ctx);
-        history.execute_command(command2, &ctx);
+        history.execute_command(increment(5), &ctx);
+        history.execute_command(increment(2), &ctx);
+        history.undo(&ctx);
+        assert_eq!(mirror.lock().value, 5);
+        history.redo(&ctx);
+        assert_eq!(mirror.lock().value, 7);
 
-        let undo_history = history.undo_history().unwrap();
-        assert_eq!(undo_history.len(), 2);
+        mirror.lock().value = 1;
+        let decrement = TestArcCommand {
+            operation: TestOperation::Decrement(3),
+        };
+        history.execute_command(decrement, &ctx);
+        assert_eq!(ctx.lock().value, 4);
         assert_eq!(
-            undo_history[0].description(),
-            "TestArcCommand: Increment(2)"
+            history.mirror_divergence(id),
+            Some(MirrorDivergence {
+                mirror: id,
+                stage: PanicStage::Execute,
+                description: String::from("TestArcCommand: Decrement(3)"),
+                reason: String::from("value is -2"),
+            })
         );
+
+        history.undo(&ctx);
+        assert_eq!(mirror.lock().value, -2);
+
+        mirror.lock().value = ctx.lock().value;
+        assert!(history.resume_mirror(id));
+        assert!(!history.resume_mirror(id));
+        history.undo(&ctx);
+        assert_eq!(mirror.lock().value, 5);
+        assert!(history.remove_mirror(id).is_some());
+        assert!(history.mirror_divergence(id).is_none());
+    }
+
+    #[test]
+    fn test_tentative_command_is_kept_only_once_confirmed() {
+        let history = ConcurrentCommandHistory::new(NonZeroUsize::new(10).unwrap(), true);
+        let ctx = SharedContext::new(TestArcContext { value: 0 });
+        let long = time::Duration::from_secs(30);
+
+        let tentative = history.execute_tentative(increment(5), &ctx, long).unwrap();
+        assert!(tentative.is_pending());
+        assert_eq!((history.position(), ctx.lock().value), (0, 5));
+        tentative.confirm().unwrap();
+        assert_eq!((history.position(), ctx.lock().value), (1, 5));
+
+        let tentative = history.execute_tentative(increment(2), &ctx, long).unwrap();
+        tentative.revert().unwrap();
+        assert_eq!((history.position(), ctx.lock().value), (1, 5));
+
+        let tentative = history
+            .execute_tentative(increment(3), &ctx, time::Duration::from_millis(10))
+            .unwrap();
+        while tentative.is_pending() {
+            thread::sleep(time::Duration::from_millis(1));
+        }
+        assert_eq!(ctx.lock().value, 5);
         assert_eq!(
-            undo_history[1].description(),
-            "TestArcCommand: Increment(1)"
+            tentative.confirm(),
+            Err(HistoryError::Expired {
+                description: String::from("TestArcCommand: Increment(3)"),
+            })
         );
+        assert_eq!(history.position(), 1);
     }
 
     #[test]
-    fn test_redo_history() {
-        let history = ConcurrentCommandHistory::new(NonZeroUsize::new(5).unwrap(), true);
+    fn test_pending_tentative_command_blocks_other_steps() {
+        let history = ConcurrentCommandHistory::new(NonZeroUsize::new(10).unwrap(), true);
         let ctx = SharedContext::new(TestArcContext { value: 0 });
-        let command1 = TestArcCommand {
-            operation: TestOperation::Increment(1),
-        };
-        let command2 = TestArcCommand {
-            operation: TestOperation::Increment(2),
+        let long = time::Duration::from_secs(30);
+        history.execute_command(increment(1), &ctx);
+
+        let tentative = history.execute_tentative(increment(5), &ctx, long).unwrap();
+        let pending = HistoryError::Pending {
+            description: String::from("TestArcCommand: Increment(5)"),
         };
+        assert_eq!(
+            history.try_execute_command(increment(2), &ctx),
+            Err(pending.clone())
+        );
+        assert_eq!(history.try_undo(&ctx), Err(pending.clone()));
+        assert_eq!(
+            history.execute_tentative(increment(2), &ctx, long).err(),
+            Some(pending)
+        );
+        history.undo(&ctx);
+        assert_eq!((history.position(), ctx.lock().value), (1, 6));
 
-        history.execute_command(command1, &ctx);
-        history.execute_command(command2, &ctx);
+        tentative.confirm().unwrap();
         history.undo(&ctx);
         history.undo(&ctx);
+        assert_eq!((history.position(), ctx.lock().value), (0, 0));
+    }
 
-        let redo_history = history.redo_history().unwrap();
-        assert_eq!(redo_history.len(), 2);
-        assert_eq!(
-            redo_history[0].description(),
-            "TestArcCommand: Increment(1)"
-        );
+    #[test]
+    fn test_pending_tentative_command_keeps_the_scope_open() {
+        let history = ConcurrentCommandHistory::new(NonZeroUsize::new(10).unwrap(), true);
+        let ctx = SharedContext::new(TestArcContext { value: 0 });
+
+        history.begin_scope();
+        history.execute_command(increment(1), &ctx);
+        let tentative = history
+            .execute_tentative(increment(5), &ctx, time::Duration::from_secs(30))
+            .unwrap();
+        assert!(!history.commit_scope(&ctx));
+        assert!(!history.abort_scope(&ctx));
+        assert_eq!((history.scope_depth(), history.position()), (1, 1));
+
+        tentative.revert().unwrap();
+        assert!(history.commit_scope(&ctx));
+        assert_eq!((history.scope_depth(), history.position()), (0, 1));
+        assert_eq!(ctx.lock().value, 1);
+    }
+
+    #[test]
+    fn test_one_timer_expires_every_tentative_command() {
+        let history = ConcurrentCommandHistory::new(NonZeroUsize::new(10).unwrap(), true);
+        let ctx = SharedContext::new(TestArcContext { value: 0 });
+
+        for value in 1..=3 {
+            let tentative = history
+                .execute_tentative(increment(value), &ctx, time::Duration::from_millis(5))
+                .unwrap();
+            assert_eq!(ctx.lock().value, value);
+            while tentative.is_pending() {
+                thread::sleep(time::Duration::from_millis(1));
+            }
+            assert_eq!(ctx.lock().value, 0);
+        }
+
+        let weak = Arc::downgrade(&history);
+        drop(history);
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn test_tentative_command_is_kept_when_reverting_breaks_the_invariant() {
+        let history = ConcurrentCommandHistory::new(NonZeroUsize::new(10).unwrap(), true);
+        let ctx = SharedContext::new(TestArcContext { value: 0 });
+        history.set_invariant(|ctx: &SharedContext<TestArcContext>| {
+            let value = ctx.lock().value;
+            if value < 0 {
+                Err(format!("value is {value}"))
+            } else {
+                Ok(())
+            }
+        });
+
+        let tentative = history
+            .execute_tentative(increment(5), &ctx, time::Duration::from_secs(30))
+            .unwrap();
+        ctx.lock().value = 2;
         assert_eq!(
-            redo_history[1].description(),
-            "TestArcCommand: Increment(2)"
+            tentative.revert(),
+            Err(HistoryError::InvariantViolated {
+                description: String::from("TestArcCommand: Increment(5)"),
+                violation: String::from("value is -3"),
+            })
         );
+        assert_eq!((history.position(), ctx.lock().value), (1, 2));
+        history.undo(&ctx);
+        assert_eq!(ctx.lock().value, 2);
+    }
+
+    #[test]
+    fn test_dropped_tentative_command_is_reverted() {
+        let history = ConcurrentCommandHistory::new(NonZeroUsize::new(10).unwrap(), true);
+        let ctx = SharedContext::new(TestArcContext { value: 0 });
+
+        let tentative = history
+            .execute_tentative(increment(5), &ctx, time::Duration::from_secs(30))
+            .unwrap();
+        drop(tentative);
+        assert_eq!((history.position(), ctx.lock().value), (0, 0));
+        history.execute_command(increment(2), &ctx);
+        assert_eq!(ctx.lock().value, 2);
+
+        // The timer lets go of the history instead of sleeping until the deadline.
+        let deadline = time::Instant::now() + time::Duration::from_secs(5);
+        while Arc::strong_count(&history) > 1 && time::Instant::now() < deadline {
+            thread::sleep(time::Duration::from_millis(1));
+        }
+        assert_eq!(Arc::strong_count(&history), 1);
     }
 }
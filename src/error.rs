@@ -0,0 +1,118 @@
+use std::{
+    fmt, io,
+    path::{Path, PathBuf},
+};
+
+use crate::panic_policy::PanicStage;
+
+/// Errors reported by the fallible history operations.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum HistoryError {
+    /// The retention policy refused to admit the command, so it was not executed.
+    Refused { description: String },
+    /// The spill file disappeared while it still held entries.
+    SpillFileMissing { path: PathBuf },
+    /// Reading or writing the spill file failed.
+    SpillIo { path: PathBuf, message: String },
+    /// A spilled entry could not be read back because the file content is damaged.
+    SpillCorrupted { path: PathBuf, reason: String },
+    /// The history or context was locked by another thread for longer than the caller was
+    /// willing to wait.
+    Busy,
+    /// The history was asked to undo or redo from inside one of its own commands.
+    Reentrant,
+    /// The context changed since the entry was recorded, so its undo might no longer apply.
+    /// `recorded` is `None` if the entry carries no version.
+    Conflict {
+        description: String,
+        recorded: Option<u64>,
+        current: u64,
+    },
+    /// A step broke the [`Invariant`](crate::invariant::Invariant) of the history and was
+    /// reverted.
+    InvariantViolated {
+        description: String,
+        violation: String,
+    },
+    /// A tentative command was reverted because it was not confirmed in time.
+    Expired { description: String },
+    /// A tentative command is waiting to be confirmed or reverted, and no other step may be
+    /// applied on top of it until then.
+    Pending { description: String },
+    /// A command panicked and the [`PanicPolicy`](crate::panic_policy::PanicPolicy) caught it.
+    Panicked {
+        stage: PanicStage,
+        description: String,
+        message: String,
+    },
+}
+
+impl HistoryError {
+    pub(crate) fn spill_io(path: &Path, error: &io::Error) -> Self {
+        if error.kind() == io::ErrorKind::NotFound {
+            Self::SpillFileMissing {
+                path: path.to_path_buf(),
+            }
+        } else {
+            Self::SpillIo {
+                path: path.to_path_buf(),
+                message: error.to_string(),
+            }
+        }
+    }
+}
+
+impl fmt::Display for HistoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Refused { description } => {
+                write!(f, "history is full, refused command ({description})")
+            }
+            Self::SpillFileMissing { path } => {
+                write!(f, "spill file {} is missing", path.display())
+            }
+            Self::SpillIo { path, message } => {
+                write!(f, "spill file {} failed: {message}", path.display())
+            }
+            Self::SpillCorrupted { path, reason } => {
+                write!(f, "spill file {} is corrupted: {reason}", path.display())
+            }
+            Self::Busy => f.write_str("history is busy"),
+            Self::Reentrant => f.write_str("cannot undo or redo from inside a running command"),
+            Self::Conflict {
+                description,
+                recorded: Some(recorded),
+                current,
+            } => write!(
+                f,
+                "context changed from version {recorded} to {current} since {description} was recorded"
+            ),
+            Self::Conflict {
+                description,
+                recorded: None,
+                ..
+            } => write!(f, "no context version was recorded for {description}"),
+            Self::InvariantViolated {
+                description,
+                violation,
+            } => write!(f, "invariant violated by {description}: {violation}"),
+            Self::Expired { description } => {
+                write!(f, "{description} expired before it was confirmed")
+            }
+            Self::Pending { description } => {
+                write!(f, "{description} is waiting to be confirmed or reverted")
+            }
+            Self::Panicked {
+                stage,
+                description,
+                message,
+            } => write!(
+                f,
+                "command panicked during {stage} ({description}): {message}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for HistoryError {}
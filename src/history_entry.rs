@@ -0,0 +1,218 @@
+use std::time::SystemTime;
+
+/// Metadata attached to a command when it is executed through a history.
+///
+/// # Fields
+///
+/// * `author` - Who issued the command, if known.
+/// * `tags` - Free-form labels used to group or filter entries.
+/// * `note` - A free-form user annotation.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EntryMetadata {
+    pub author: Option<String>,
+    pub tags: Vec<String>,
+    pub note: Option<String>,
+}
+
+impl EntryMetadata {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[must_use]
+    pub fn author(mut self, author: impl Into<String>) -> Self {
+        self.author = Some(author.into());
+        self
+    }
+
+    #[must_use]
+    pub fn tag(mut self, tag: impl Into<String>) -> Self {
+        self.tags.push(tag.into());
+        self
+    }
+
+    #[must_use]
+    pub fn note(mut self, note: impl Into<String>) -> Self {
+        self.note = Some(note.into());
+        self
+    }
+}
+
+/// What a single undo or redo step of a [`HistoryEntry`] applies.
+#[derive(Debug, Clone)]
+pub enum EntryPayload<C, S> {
+    /// A single executed command.
+    Command(C),
+    /// Several commands applied in order and undone in reverse order as one step.
+    Compound(Vec<C>),
+    /// Commands that were compacted away and replaced by the context states before and after
+    /// them. Undo restores `before`, redo restores `after`.
+    Snapshot { before: S, after: S, merged: usize },
+}
+
+/// A command stored in a history together with its bookkeeping.
+///
+/// Entries keep their metadata while they move between the undo and redo stacks, so the
+/// execution time, author, tags and note survive any number of undos and redos.
+///
+/// # Type Parameters
+///
+/// * `C` - The stored command type. [`ConcurrentCommandHistory`](crate::concurrent_command_history::ConcurrentCommandHistory)
+///   stores `Arc<C>`.
+/// * `S` - The context snapshot type held by compacted entries.
+#[derive(Debug, Clone)]
+pub struct HistoryEntry<C, S> {
+    payload: EntryPayload<C, S>,
+    executed_at: SystemTime,
+    last_moved_at: Option<SystemTime>,
+    metadata: EntryMetadata,
+    context_version: Option<u64>,
+}
+
+impl<C, S> HistoryEntry<C, S> {
+    #[must_use]
+    pub fn new(command: C) -> Self {
+        Self::with_metadata(command, EntryMetadata::default())
+    }
+
+    #[must_use]
+    pub fn with_metadata(command: C, metadata: EntryMetadata) -> Self {
+        Self::from_payload(EntryPayload::Command(command), metadata)
+    }
+
+    #[must_use]
+    pub fn from_payload(payload: EntryPayload<C, S>, metadata: EntryMetadata) -> Self {
+        Self {
+            payload,
+            executed_at: SystemTime::now(),
+            last_moved_at: None,
+            metadata,
+            context_version: None,
+        }
+    }
+
+    #[must_use]
+    pub fn payload(&self) -> &EntryPayload<C, S> {
+        &self.payload
+    }
+
+    #[must_use]
+    pub fn into_payload(self) -> EntryPayload<C, S> {
+        self.payload
+    }
+
+    /// Returns the command if this entry holds exactly one.
+    #[must_use]
+    pub fn command(&self) -> Option<&C> {
+        match &self.payload {
+            EntryPayload::Command(command) => Some(command),
+            _ => None,
+        }
+    }
+
+    /// Returns the commands held by this entry in execution order. Snapshot entries hold none.
+    #[must_use]
+    pub fn commands(&self) -> &[C] {
+        match &self.payload {
+            EntryPayload::Command(command) => std::slice::from_ref(command),
+            EntryPayload::Compound(commands) => commands,
+            EntryPayload::Snapshot { .. } => &[],
+        }
+    }
+
+    /// Returns the number of executed commands this entry stands for.
+    #[must_use]
+    pub fn command_count(&self) -> usize {
+        match &self.payload {
+            EntryPayload::Command(_) => 1,
+            EntryPayload::Compound(commands) => commands.len(),
+            EntryPayload::Snapshot { merged, .. } => *merged,
+        }
+    }
+
+    /// Returns when the command was first executed.
+    #[must_use]
+    pub fn executed_at(&self) -> SystemTime {
+        self.executed_at
+    }
+
+    /// Returns when the command was last undone or redone, or `None` if it never was.
+    #[must_use]
+    pub fn last_moved_at(&self) -> Option<SystemTime> {
+        self.last_moved_at
+    }
+
+    #[must_use]
+    pub fn metadata(&self) -> &EntryMetadata {
+        &self.metadata
+    }
+
+    #[must_use]
+    pub fn author(&self) -> Option<&str> {
+        self.metadata.author.as_deref()
+    }
+
+    #[must_use]
+    pub fn tags(&self) -> &[String] {
+        &self.metadata.tags
+    }
+
+    #[must_use]
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.metadata.tags.iter().any(|t| t == tag)
+    }
+
+    #[must_use]
+    pub fn note(&self) -> Option<&str> {
+        self.metadata.note.as_deref()
+    }
+
+    pub fn set_author(&mut self, author: Option<String>) {
+        self.metadata.author = author;
+    }
+
+    pub fn add_tag(&mut self, tag: impl Into<String>) {
+        self.metadata.tags.push(tag.into());
+    }
+
+    /// Removes every occurrence of `tag` and returns whether any was present.
+    pub fn remove_tag(&mut self, tag: &str) -> bool {
+        let before = self.metadata.tags.len();
+        self.metadata.tags.retain(|t| t != tag);
+        before != self.metadata.tags.len()
+    }
+
+    pub fn set_note(&mut self, note: Option<String>) {
+        self.metadata.note = note;
+    }
+
+    /// Returns the version the context had when this entry last became the next one to undo, if
+    /// the history checks versions, see
+    /// [`enable_version_checks`](crate::concurrent_command_history::ConcurrentCommandHistory::enable_version_checks).
+    #[must_use]
+    pub fn context_version(&self) -> Option<u64> {
+        self.context_version
+    }
+
+    pub(crate) fn stamp(&mut self, version: u64) {
+        self.context_version = Some(version);
+    }
+
+    pub(crate) fn touch(&mut self) {
+        self.last_moved_at = Some(SystemTime::now());
+    }
+
+    pub(crate) fn with_executed_at(mut self, executed_at: SystemTime) -> Self {
+        self.executed_at = executed_at;
+        self
+    }
+
+    /// Returns the description used for entries that do not hold exactly one command.
+    pub(crate) fn group_description(&self) -> String {
+        match &self.payload {
+            EntryPayload::Snapshot { merged, .. } => format!("Snapshot of {merged} commands"),
+            _ => format!("Compound of {} commands", self.command_count()),
+        }
+    }
+}
@@ -0,0 +1,535 @@
+use std::{collections::HashMap, collections::VecDeque, hash::Hash, num::NonZeroUsize, sync::Arc};
+
+use crate::{
+    concurrent_command_history::ConcurrentCommandHistory,
+    simple_command_history::SimpleCommandHistory,
+    sync::{Mutex, RwLock},
+    traits::{
+        command::Command, command_history::CommandHistory, mutable_command::MutableCommand,
+        mutable_command_history::MutableCommandHistory,
+    },
+};
+
+/// The budget shared by every history in a registry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegistryBudget {
+    /// At most this many entries across all undo and redo stacks.
+    Entries(usize),
+    /// At most this much summed `cost()` across all undo and redo stacks.
+    Cost(usize),
+}
+
+impl RegistryBudget {
+    fn by_cost(self) -> bool {
+        matches!(self, Self::Cost(_))
+    }
+
+    fn max(self) -> usize {
+        match self {
+            Self::Entries(max) | Self::Cost(max) => max,
+        }
+    }
+}
+
+/// Marks `key` as the most recently used one.
+fn touch<K: Eq + Clone>(recency: &mut VecDeque<K>, key: &K) {
+    if let Some(index) = recency.iter().position(|used| used == key) {
+        recency.remove(index);
+    }
+
+    recency.push_front(key.clone());
+}
+
+/// Keeps one [`SimpleCommandHistory`] per document and enforces a budget across all of them.
+///
+/// When the budget is exceeded, the oldest entries of the least recently used document are
+/// evicted first. Going through [`execute_command`](Self::execute_command), [`undo`](Self::undo)
+/// and [`redo`](Self::redo) enforces the budget automatically; after changing a history obtained
+/// from [`open`](Self::open) or [`get_mut`](Self::get_mut), call
+/// [`enforce_budget`](Self::enforce_budget).
+///
+/// # Type Parameters
+///
+/// * `K` - The document key, e.g. a tab id or a path.
+/// * `C` - The command type shared by all documents.
+pub struct HistoryRegistry<K, C: MutableCommand> {
+    histories: HashMap<K, SimpleCommandHistory<C>>,
+    recency: VecDeque<K>,
+    budget: RegistryBudget,
+    history_limit: usize,
+    clear_redo_on_execute: bool,
+}
+
+impl<K, C> HistoryRegistry<K, C>
+where
+    K: Eq + Hash + Clone,
+    C: MutableCommand,
+{
+    /// Creates an empty registry.
+    ///
+    /// # Arguments
+    ///
+    /// * `budget` - The budget shared by all documents.
+    /// * `history_limit` - The limit of every history created by the registry.
+    /// * `clear_redo_on_execute` - Passed on to every history created by the registry.
+    #[must_use]
+    pub fn new(budget: RegistryBudget, history_limit: usize, clear_redo_on_execute: bool) -> Self {
+        Self {
+            histories: HashMap::new(),
+            recency: VecDeque::new(),
+            budget,
+            history_limit,
+            clear_redo_on_execute,
+        }
+    }
+
+    /// Returns the history of `key`, creating it if the document has none yet, and marks it as
+    /// the most recently used.
+    pub fn open(&mut self, key: K) -> &mut SimpleCommandHistory<C> {
+        touch(&mut self.recency, &key);
+
+        let (limit, clear_redo) = (self.history_limit, self.clear_redo_on_execute);
+        self.histories
+            .entry(key)
+            .or_insert_with(|| SimpleCommandHistory::new(limit, clear_redo))
+    }
+
+    /// Returns the history of `key` without marking it as used.
+    #[must_use]
+    pub fn get(&self, key: &K) -> Option<&SimpleCommandHistory<C>> {
+        self.histories.get(key)
+    }
+
+    /// Returns the history of `key` and marks it as the most recently used.
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut SimpleCommandHistory<C>> {
+        let history = self.histories.get_mut(key)?;
+        touch(&mut self.recency, key);
+        Some(history)
+    }
+
+    /// Removes the history of `key` and hands it back.
+    pub fn close(&mut self, key: &K) -> Option<SimpleCommandHistory<C>> {
+        self.recency.retain(|used| used != key);
+        self.histories.remove(key)
+    }
+
+    #[must_use]
+    pub fn contains(&self, key: &K) -> bool {
+        self.histories.contains_key(key)
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.histories.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.histories.is_empty()
+    }
+
+    /// Returns the open document keys, most recently used first.
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.recency.iter()
+    }
+
+    #[must_use]
+    pub fn budget(&self) -> RegistryBudget {
+        self.budget
+    }
+
+    /// Replaces the shared budget and enforces it right away.
+    pub fn set_budget(&mut self, budget: RegistryBudget) {
+        self.budget = budget;
+        self.enforce_budget();
+    }
+
+    /// Returns how much of the budget all documents currently take up.
+    #[must_use]
+    pub fn usage(&self) -> usize {
+        let by_cost = self.budget.by_cost();
+        self.histories
+            .values()
+            .map(|history| history.usage(by_cost))
+            .sum()
+    }
+
+    /// Executes `command` in the history of `key`, opening it if needed, then enforces the budget.
+    pub fn execute_command(&mut self, key: K, command: C, ctx: &mut C::Context) {
+        self.open(key).execute_command(command, ctx);
+        self.enforce_budget();
+    }
+
+    /// Undoes the last command of `key`. Does nothing if the document has no history.
+    pub fn undo(&mut self, key: &K, ctx: &mut C::Context) {
+        if let Some(history) = self.get_mut(key) {
+            history.undo(ctx);
+            self.enforce_budget();
+        }
+    }
+
+    /// Redoes the next command of `key`. Does nothing if the document has no history.
+    pub fn redo(&mut self, key: &K, ctx: &mut C::Context) {
+        if let Some(history) = self.get_mut(key) {
+            history.redo(ctx);
+            self.enforce_budget();
+        }
+    }
+
+    /// Evicts entries, starting with the least recently used document, until the budget holds.
+    ///
+    /// # Returns
+    ///
+    /// The number of evicted entries.
+    pub fn enforce_budget(&mut self) -> usize {
+        let by_cost = self.budget.by_cost();
+        let mut usage = self.usage();
+        let mut evicted = 0;
+
+        for key in self.recency.iter().rev() {
+            let Some(history) = self.histories.get_mut(key) else {
+                continue;
+            };
+
+            while usage > self.budget.max() {
+                let Some(weight) = history.evict_oldest(by_cost) else {
+                    break;
+                };
+
+                usage -= weight;
+                evicted += 1;
+            }
+        }
+
+        evicted
+    }
+}
+
+/// The thread-safe counterpart of [`HistoryRegistry`], keeping one [`ConcurrentCommandHistory`]
+/// per document.
+///
+/// Histories are handed out as `Arc`s, so a closed document stays usable by whoever still holds
+/// it, but no longer counts toward the budget.
+pub struct ConcurrentHistoryRegistry<K, C: Command + Send + Sync> {
+    histories: RwLock<HashMap<K, Arc<ConcurrentCommandHistory<C>>>>,
+    recency: Mutex<VecDeque<K>>,
+    budget: RwLock<RegistryBudget>,
+    history_limit: NonZeroUsize,
+    clear_redo_on_execute: bool,
+}
+
+impl<K, C> ConcurrentHistoryRegistry<K, C>
+where
+    K: Eq + Hash + Clone,
+    C: Command + Send + Sync,
+{
+    /// Creates an empty registry.
+    ///
+    /// # Arguments
+    ///
+    /// * `budget` - The budget shared by all documents.
+    /// * `history_limit` - The limit of every history created by the registry.
+    /// * `clear_redo_on_execute` - Passed on to every history created by the registry.
+    #[must_use]
+    pub fn new(
+        budget: RegistryBudget,
+        history_limit: NonZeroUsize,
+        clear_redo_on_execute: bool,
+    ) -> Self {
+        Self {
+            histories: RwLock::new(HashMap::new()),
+            recency: Mutex::new(VecDeque::new()),
+            budget: RwLock::new(budget),
+            history_limit,
+            clear_redo_on_execute,
+        }
+    }
+
+    /// Returns the history of `key`, creating it if the document has none yet, and marks it as
+    /// the most recently used.
+    ///
+    /// The recency list is only updated while `histories` is locked, so a concurrent
+    /// [`close`](Self::close) cannot leave an open document out of it.
+    pub fn open(&self, key: K) -> Arc<ConcurrentCommandHistory<C>> {
+        if let Some(history) = self.get(&key) {
+            return history;
+        }
+
+        let mut histories = self.histories.write();
+        touch(&mut self.recency.lock(), &key);
+        Arc::clone(histories.entry(key).or_insert_with(|| {
+            ConcurrentCommandHistory::new(self.history_limit, self.clear_redo_on_execute)
+        }))
+    }
+
+    /// Returns the history of `key` and marks it as the most recently used.
+    pub fn get(&self, key: &K) -> Option<Arc<ConcurrentCommandHistory<C>>> {
+        let histories = self.histories.read();
+        let history = Arc::clone(histories.get(key)?);
+        touch(&mut self.recency.lock(), key);
+        Some(history)
+    }
+
+    /// Removes the history of `key` and hands it back.
+    pub fn close(&self, key: &K) -> Option<Arc<ConcurrentCommandHistory<C>>> {
+        let mut histories = self.histories.write();
+        let history = histories.remove(key);
+        self.recency.lock().retain(|used| used != key);
+        history
+    }
+
+    pub fn contains(&self, key: &K) -> bool {
+        self.histories.read().contains_key(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.histories.read().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.histories.read().is_empty()
+    }
+
+    /// Returns the open document keys, most recently used first.
+    pub fn keys(&self) -> Vec<K> {
+        self.recency.lock().iter().cloned().collect()
+    }
+
+    pub fn budget(&self) -> RegistryBudget {
+        *self.budget.read()
+    }
+
+    /// Replaces the shared budget and enforces it right away.
+    pub fn set_budget(&self, budget: RegistryBudget) {
+        *self.budget.write() = budget;
+        self.enforce_budget();
+    }
+
+    /// Returns how much of the budget all documents currently take up.
+    pub fn usage(&self) -> usize {
+        let by_cost = self.budget().by_cost();
+        self.histories
+            .read()
+            .values()
+            .map(|history| history.usage(by_cost))
+            .sum()
+    }
+
+    /// Executes `command` in the history of `key`, opening it if needed, then enforces the budget.
+    pub fn execute_command(&self, key: K, command: C, ctx: &C::Context) {
+        self.open(key).execute_command(command, ctx);
+        self.enforce_budget();
+    }
+
+    /// Undoes the last command of `key`. Does nothing if the document has no history.
+    pub fn undo(&self, key: &K, ctx: &C::Context) {
+        if let Some(history) = self.get(key) {
+            history.undo(ctx);
+            self.enforce_budget();
+        }
+    }
+
+    /// Redoes the next command of `key`. Does nothing if the document has no history.
+    pub fn redo(&self, key: &K, ctx: &C::Context) {
+        if let Some(history) = self.get(key) {
+            history.redo(ctx);
+            self.enforce_budget();
+        }
+    }
+
+    /// Evicts entries, starting with the least recently used document, until the budget holds.
+    ///
+    /// The total is taken once and lowered by each eviction, so entries other threads add while
+    /// this runs are left to the next pass.
+    ///
+    /// # Returns
+    ///
+    /// The number of evicted entries.
+    pub fn enforce_budget(&self) -> usize {
+        let budget = self.budget();
+        let by_cost = budget.by_cost();
+
+        let histories = self.histories.read();
+        let least_recent: Vec<_> = self
+            .recency
+            .lock()
+            .iter()
+            .rev()
+            .filter_map(|key| histories.get(key).cloned())
+            .collect();
+        let mut usage: usize = histories
+            .values()
+            .map(|history| history.usage(by_cost))
+            .sum();
+
+        let mut evicted = 0;
+        for history in least_recent {
+            while usage > budget.max() {
+                let Some(weight) = history.evict_oldest(by_cost) else {
+                    break;
+                };
+
+                usage = usage.saturating_sub(weight);
+                evicted += 1;
+            }
+        }
+
+        evicted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell,
+        sync::atomic::{AtomicI32, Ordering},
+    };
+
+    use super::*;
+
+    struct AddCommand(i32);
+
+    impl MutableCommand for AddCommand {
+        type Context = RefCell<i32>;
+
+        fn execute(&self, ctx: &mut Self::Context) {
+            *ctx.get_mut() += self.0;
+        }
+
+        fn undo(&self, ctx: &mut Self::Context) {
+            *ctx.get_mut() -= self.0;
+        }
+
+        fn cost(&self) -> usize {
+            usize::try_from(self.0).unwrap()
+        }
+    }
+
+    struct AtomicAdd(i32);
+
+    impl Command for AtomicAdd {
+        type Context = AtomicI32;
+
+        fn execute(&self, ctx: &Self::Context) {
+            ctx.fetch_add(self.0, Ordering::Relaxed);
+        }
+
+        fn undo(&self, ctx: &Self::Context) {
+            ctx.fetch_sub(self.0, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_evicts_from_least_recently_used() {
+        let mut registry = HistoryRegistry::new(RegistryBudget::Entries(5), 10, true);
+        let mut first = RefCell::new(0);
+        let mut second = RefCell::new(0);
+
+        for _ in 0..3 {
+            registry.execute_command("first", AddCommand(1), &mut first);
+        }
+        for _ in 0..3 {
+            registry.execute_command("second", AddCommand(1), &mut second);
+        }
+
+        assert_eq!(registry.usage(), 5);
+        assert_eq!(registry.get(&"first").unwrap().position(), 2);
+        assert_eq!(registry.get(&"second").unwrap().position(), 3);
+        assert_eq!(
+            registry.keys().copied().collect::<Vec<_>>(),
+            ["second", "first"]
+        );
+
+        registry.undo(&"first", &mut first);
+        registry.execute_command("first", AddCommand(1), &mut first);
+        registry.execute_command("first", AddCommand(1), &mut first);
+        assert_eq!(*first.borrow(), 4);
+        assert_eq!(registry.get(&"first").unwrap().position(), 3);
+        assert_eq!(registry.get(&"second").unwrap().position(), 2);
+
+        assert!(registry.close(&"second").is_some());
+        assert_eq!(registry.len(), 1);
+        assert_eq!(registry.usage(), 3);
+    }
+
+    #[test]
+    fn test_cost_budget() {
+        let mut registry = HistoryRegistry::new(RegistryBudget::Entries(100), 10, true);
+        let mut ctx = RefCell::new(0);
+
+        registry.execute_command(1, AddCommand(4), &mut ctx);
+        registry.execute_command(2, AddCommand(3), &mut ctx);
+        registry.execute_command(2, AddCommand(3), &mut ctx);
+
+        registry.set_budget(RegistryBudget::Cost(6));
+        assert_eq!(registry.usage(), 6);
+        assert!(registry.get(&1).unwrap().undo_history().is_none());
+        assert_eq!(*ctx.borrow(), 10);
+    }
+
+    #[test]
+    fn test_concurrent_registry() {
+        let registry = Arc::new(ConcurrentHistoryRegistry::new(
+            RegistryBudget::Entries(8),
+            NonZeroUsize::new(10).unwrap(),
+            true,
+        ));
+        let ctx = Arc::new(AtomicI32::new(0));
+
+        let handles: Vec<_> = (0..4)
+            .map(|document| {
+                let registry = Arc::clone(&registry);
+                let ctx = Arc::clone(&ctx);
+                std::thread::spawn(move || {
+                    for _ in 0..5 {
+                        registry.execute_command(document, AtomicAdd(1), &ctx);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(ctx.load(Ordering::Relaxed), 20);
+        assert_eq!(registry.len(), 4);
+        assert!(registry.usage() <= 8);
+
+        let most_recent = registry.keys()[0];
+        assert!(registry.close(&most_recent).is_some());
+        assert!(!registry.contains(&most_recent));
+    }
+
+    #[test]
+    fn test_racing_open_and_close_keep_recency_in_step() {
+        let registry = Arc::new(ConcurrentHistoryRegistry::<_, AtomicAdd>::new(
+            RegistryBudget::Entries(8),
+            NonZeroUsize::new(10).unwrap(),
+            true,
+        ));
+        let opening = Arc::new(AtomicI32::new(-1));
+
+        // Closes every document right as it is being opened.
+        let closer = {
+            let registry = Arc::clone(&registry);
+            let opening = Arc::clone(&opening);
+            std::thread::spawn(move || {
+                for key in 0..2000 {
+                    while opening.load(Ordering::Acquire) < key {
+                        std::hint::spin_loop();
+                    }
+                    registry.close(&key);
+                }
+            })
+        };
+        for key in 0..2000 {
+            opening.store(key, Ordering::Release);
+            registry.open(key);
+        }
+        closer.join().unwrap();
+
+        let keys = registry.keys();
+        assert_eq!(keys.len(), registry.len());
+        assert!(keys.iter().all(|key| registry.contains(key)));
+    }
+}
@@ -0,0 +1,581 @@
+use std::{
+    borrow::Cow,
+    num::NonZeroUsize,
+    panic::{self, AssertUnwindSafe},
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{
+    concurrent_command_history::ConcurrentCommandHistory,
+    error::HistoryError,
+    shared_context::{SharedContext, SharedContextGuard},
+    simple_command_history::SimpleCommandHistory,
+    traits::{
+        command::Command, command_history::CommandHistory, mutable_command::MutableCommand,
+        mutable_command_history::MutableCommandHistory,
+    },
+};
+
+/// A [`SimpleCommandHistory`] that owns the context its commands are applied to, so they can
+/// never be undone against the wrong one.
+///
+/// # Examples
+///
+/// ```
+/// use command_history::prelude::*;
+///
+/// struct Add(i32);
+///
+/// impl MutableCommand for Add {
+///     type Context = i32;
+///     fn execute(&self, ctx: &mut i32) { *ctx += self.0; }
+///     fn undo(&self, ctx: &mut i32) { *ctx -= self.0; }
+/// }
+///
+/// let mut document = HistoryWithContext::new(SimpleCommandHistory::new(10, true), 0);
+/// document.execute_command(Add(5));
+/// document.undo();
+/// assert_eq!(*document.context(), 0);
+/// ```
+pub struct HistoryWithContext<C: MutableCommand> {
+    history: SimpleCommandHistory<C>,
+    ctx: C::Context,
+}
+
+impl<C: MutableCommand> HistoryWithContext<C> {
+    #[must_use]
+    pub fn new(history: SimpleCommandHistory<C>, ctx: C::Context) -> Self {
+        Self { history, ctx }
+    }
+
+    pub fn execute_command(&mut self, command: C) {
+        self.history.execute_command(command, &mut self.ctx);
+    }
+
+    pub fn batch_execute(&mut self, commands: Vec<C>) {
+        self.history.batch_execute(commands, &mut self.ctx);
+    }
+
+    pub fn undo(&mut self) {
+        self.history.undo(&mut self.ctx);
+    }
+
+    pub fn redo(&mut self) {
+        self.history.redo(&mut self.ctx);
+    }
+
+    pub fn set_history_limit(&mut self, limit: NonZeroUsize) {
+        self.history.set_history_limit(limit);
+    }
+
+    #[must_use]
+    pub fn context(&self) -> &C::Context {
+        &self.ctx
+    }
+
+    /// Modifies the context directly.
+    ///
+    /// Changes made here are not recorded, so they must not touch state that recorded commands
+    /// rely on to undo or redo correctly.
+    pub fn modify_context<F, R>(&mut self, f: F) -> R
+    where
+        F: FnOnce(&mut C::Context) -> R,
+    {
+        f(&mut self.ctx)
+    }
+
+    #[must_use]
+    pub fn history(&self) -> &SimpleCommandHistory<C> {
+        &self.history
+    }
+
+    /// Gives access to the history together with its context, for the operations that are not
+    /// forwarded by the wrapper.
+    pub fn with_history<F, R>(&mut self, f: F) -> R
+    where
+        F: FnOnce(&mut SimpleCommandHistory<C>, &mut C::Context) -> R,
+    {
+        f(&mut self.history, &mut self.ctx)
+    }
+
+    #[must_use]
+    pub fn into_parts(self) -> (SimpleCommandHistory<C>, C::Context) {
+        (self.history, self.ctx)
+    }
+}
+
+/// A [`ConcurrentCommandHistory`] bound to the context its commands are applied to.
+///
+/// The wrapper is as cheap to share as the history itself. Cloning it shares both the history and
+/// the context.
+pub struct ConcurrentHistoryWithContext<C: Command + Send + Sync>
+where
+    C::Context: Clone,
+{
+    history: Arc<ConcurrentCommandHistory<C>>,
+    ctx: C::Context,
+}
+
+impl<C> ConcurrentHistoryWithContext<C>
+where
+    C: Command + Send + Sync,
+    C::Context: Clone,
+{
+    #[must_use]
+    pub fn new(history: Arc<ConcurrentCommandHistory<C>>, ctx: C::Context) -> Self {
+        Self { history, ctx }
+    }
+
+    pub fn execute_command(&self, command: C) {
+        self.history.execute_command(command, &self.ctx);
+    }
+
+    pub fn batch_execute(&self, commands: Vec<C>) {
+        self.history.batch_execute(commands, &self.ctx);
+    }
+
+    pub fn undo(&self) {
+        self.history.undo(&self.ctx);
+    }
+
+    pub fn redo(&self) {
+        self.history.redo(&self.ctx);
+    }
+
+    pub fn set_history_limit(&self, limit: NonZeroUsize) {
+        self.history.set_history_limit(limit);
+    }
+
+    #[must_use]
+    pub fn context(&self) -> &C::Context {
+        &self.ctx
+    }
+
+    #[must_use]
+    pub fn history(&self) -> &Arc<ConcurrentCommandHistory<C>> {
+        &self.history
+    }
+}
+
+impl<C> Clone for ConcurrentHistoryWithContext<C>
+where
+    C: Command + Send + Sync,
+    C::Context: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            history: Arc::clone(&self.history),
+            ctx: self.ctx.clone(),
+        }
+    }
+}
+
+/// A [`ConcurrentCommandHistory`] bound to a [`SharedContext`], applying [`MutableCommand`]s to
+/// the guarded value.
+///
+/// Every operation is recorded as a single [`Locked`] entry, which locks the context once for all
+/// the commands it runs instead of every command locking it on its own, so other users of the
+/// context never observe a half-applied batch. The non-blocking and deadline-bounded operations
+/// give up if either the history or the context is not available in time, see
+/// [`ConcurrentCommandHistory::enable_context_deadlines`].
+///
+/// The wrapper is as cheap to share as the history itself. Cloning it shares both the history and
+/// the context.
+pub struct SharedHistoryWithContext<C>
+where
+    C: MutableCommand + Send + Sync,
+{
+    history: Arc<ConcurrentCommandHistory<Locked<C>>>,
+    ctx: SharedContext<C::Context>,
+}
+
+impl<C> SharedHistoryWithContext<C>
+where
+    C: MutableCommand + Send + Sync,
+{
+    /// Binds `history` to `ctx` and enables its context deadlines.
+    #[must_use]
+    pub fn new(
+        history: Arc<ConcurrentCommandHistory<Locked<C>>>,
+        ctx: SharedContext<C::Context>,
+    ) -> Self {
+        history.enable_context_deadlines();
+        Self { history, ctx }
+    }
+
+    pub fn execute_command(&self, command: C) {
+        self.history
+            .execute_command(Locked::from(command), &self.ctx);
+    }
+
+    /// Executes `commands` under a single lock of the context and records them as one entry, so
+    /// they are undone and redone together.
+    pub fn batch_execute(&self, commands: Vec<C>) {
+        if !commands.is_empty() {
+            self.history.execute_command(Locked(commands), &self.ctx);
+        }
+    }
+
+    pub fn undo(&self) {
+        self.history.undo(&self.ctx);
+    }
+
+    pub fn redo(&self) {
+        self.history.redo(&self.ctx);
+    }
+
+    /// Executes `command` if neither the history nor the context is locked by another thread,
+    /// without blocking.
+    ///
+    /// # Errors
+    ///
+    /// The same as [`ConcurrentCommandHistory::try_execute_command`].
+    pub fn try_execute_command(&self, command: C) -> Result<(), HistoryError> {
+        self.history
+            .try_execute_command(Locked::from(command), &self.ctx)
+    }
+
+    /// Like [`try_execute_command`](Self::try_execute_command), but waits up to `timeout` for the
+    /// history and the context.
+    ///
+    /// # Errors
+    ///
+    /// The same as [`try_execute_command`](Self::try_execute_command).
+    pub fn execute_command_for(&self, command: C, timeout: Duration) -> Result<(), HistoryError> {
+        self.history
+            .execute_command_for(Locked::from(command), &self.ctx, timeout)
+    }
+
+    /// Undoes the last operation if neither the history nor the context is locked by another
+    /// thread, without blocking.
+    ///
+    /// # Errors
+    ///
+    /// The same as [`ConcurrentCommandHistory::try_undo`].
+    pub fn try_undo(&self) -> Result<(), HistoryError> {
+        self.history.try_undo(&self.ctx)
+    }
+
+    /// Like [`try_undo`](Self::try_undo), but waits up to `timeout` for the history and the
+    /// context.
+    ///
+    /// # Errors
+    ///
+    /// The same as [`try_undo`](Self::try_undo).
+    pub fn undo_for(&self, timeout: Duration) -> Result<(), HistoryError> {
+        self.history.undo_for(&self.ctx, timeout)
+    }
+
+    /// Redoes the last undone operation if neither the history nor the context is locked by
+    /// another thread, without blocking.
+    ///
+    /// # Errors
+    ///
+    /// The same as [`ConcurrentCommandHistory::try_redo`].
+    pub fn try_redo(&self) -> Result<(), HistoryError> {
+        self.history.try_redo(&self.ctx)
+    }
+
+    /// Like [`try_redo`](Self::try_redo), but waits up to `timeout` for the history and the
+    /// context.
+    ///
+    /// # Errors
+    ///
+    /// The same as [`try_redo`](Self::try_redo).
+    pub fn redo_for(&self, timeout: Duration) -> Result<(), HistoryError> {
+        self.history.redo_for(&self.ctx, timeout)
+    }
+
+    pub fn set_history_limit(&self, limit: NonZeroUsize) {
+        self.history.set_history_limit(limit);
+    }
+
+    /// Returns the bound context, e.g. to hand it to other users.
+    #[must_use]
+    pub fn context(&self) -> &SharedContext<C::Context> {
+        &self.ctx
+    }
+
+    /// Locks the context for reading or for changes that are not recorded.
+    #[must_use]
+    pub fn lock_context(&self) -> SharedContextGuard<'_, C::Context> {
+        self.ctx.lock()
+    }
+
+    /// Returns the history, for the operations that are not forwarded by the wrapper.
+    #[must_use]
+    pub fn history(&self) -> &Arc<ConcurrentCommandHistory<Locked<C>>> {
+        &self.history
+    }
+
+    #[must_use]
+    pub fn into_parts(
+        self,
+    ) -> (
+        Arc<ConcurrentCommandHistory<Locked<C>>>,
+        SharedContext<C::Context>,
+    ) {
+        (self.history, self.ctx)
+    }
+}
+
+impl<C> Clone for SharedHistoryWithContext<C>
+where
+    C: MutableCommand + Send + Sync,
+{
+    fn clone(&self) -> Self {
+        Self {
+            history: Arc::clone(&self.history),
+            ctx: self.ctx.clone(),
+        }
+    }
+}
+
+/// The entry recorded by [`SharedHistoryWithContext`]: one or more [`MutableCommand`]s applied
+/// under a single lock of the context.
+///
+/// The commands are applied all or nothing. If one of them panics, the ones applied before it are
+/// reverted before the panic reaches the history.
+pub struct Locked<C>(Vec<C>);
+
+impl<C: MutableCommand> Locked<C> {
+    /// Returns the commands in the order they are executed.
+    #[must_use]
+    pub fn commands(&self) -> &[C] {
+        &self.0
+    }
+
+    /// Runs `step` on every command of `commands`, reverting the finished ones with `revert` if
+    /// one of them panics.
+    fn apply<'a>(
+        commands: impl Iterator<Item = &'a C>,
+        ctx: &SharedContext<C::Context>,
+        step: fn(&C, &mut C::Context),
+        revert: fn(&C, &mut C::Context),
+    ) where
+        C: 'a,
+    {
+        let mut value = ctx.lock();
+        let mut finished = Vec::new();
+        for command in commands {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| step(command, &mut value)));
+            if let Err(payload) = result {
+                for command in finished.into_iter().rev() {
+                    revert(command, &mut value);
+                }
+                // The value is consistent again, so the lock must not be poisoned by the panic.
+                drop(value);
+                panic::resume_unwind(payload);
+            }
+            finished.push(command);
+        }
+    }
+}
+
+impl<C: MutableCommand> From<C> for Locked<C> {
+    fn from(command: C) -> Self {
+        Self(vec![command])
+    }
+}
+
+impl<C: MutableCommand> Command for Locked<C> {
+    type Context = SharedContext<C::Context>;
+
+    fn execute(&self, ctx: &Self::Context) {
+        Self::apply(self.0.iter(), ctx, C::execute, C::undo);
+    }
+
+    fn undo(&self, ctx: &Self::Context) {
+        Self::apply(self.0.iter().rev(), ctx, C::undo, C::redo);
+    }
+
+    fn redo(&self, ctx: &Self::Context) {
+        Self::apply(self.0.iter(), ctx, C::redo, C::undo);
+    }
+
+    fn description(&self) -> Cow<'_, str> {
+        match self.0.as_slice() {
+            [command] => command.description(),
+            commands => Cow::Owned(format!("Batch of {} commands", commands.len())),
+        }
+    }
+
+    fn cost(&self) -> usize {
+        self.0.iter().map(C::cost).sum()
+    }
+
+    fn on_evicted(&self) {
+        self.0.iter().for_each(C::on_evicted);
+    }
+
+    fn on_discarded(&self) {
+        self.0.iter().for_each(C::on_discarded);
+    }
+
+    fn on_finalized(&self) {
+        self.0.iter().for_each(C::on_finalized);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicI32, Ordering},
+        thread,
+    };
+
+    use super::*;
+    use crate::panic_policy::PanicPolicy;
+
+    #[derive(Clone)]
+    struct Add(i32);
+
+    impl MutableCommand for Add {
+        type Context = Vec<i32>;
+
+        fn execute(&self, ctx: &mut Self::Context) {
+            ctx.push(self.0);
+        }
+
+        fn undo(&self, ctx: &mut Self::Context) {
+            ctx.pop();
+        }
+    }
+
+    struct AtomicAdd(i32);
+
+    impl Command for AtomicAdd {
+        type Context = Arc<AtomicI32>;
+
+        fn execute(&self, ctx: &Self::Context) {
+            ctx.fetch_add(self.0, Ordering::Relaxed);
+        }
+
+        fn undo(&self, ctx: &Self::Context) {
+            ctx.fetch_sub(self.0, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_owned_context() {
+        let mut document = HistoryWithContext::new(SimpleCommandHistory::new(5, true), Vec::new());
+        document.batch_execute(vec![Add(1), Add(2)]);
+        document.undo();
+        assert_eq!(document.context(), &[1]);
+
+        document.redo();
+        document.modify_context(|ctx| ctx.reserve(8));
+        let (history, ctx) = document.into_parts();
+        assert_eq!(ctx, [1, 2]);
+        assert_eq!(history.position(), 2);
+    }
+
+    #[test]
+    fn test_concurrent_bound_context() {
+        let document = ConcurrentHistoryWithContext::new(
+            ConcurrentCommandHistory::new(NonZeroUsize::new(10).unwrap(), true),
+            Arc::new(AtomicI32::new(0)),
+        );
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let document = document.clone();
+                thread::spawn(move || document.execute_command(AtomicAdd(2)))
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        document.undo();
+        assert_eq!(document.context().load(Ordering::Relaxed), 6);
+        assert_eq!(document.history().position(), 3);
+    }
+
+    #[test]
+    fn test_shared_context_batches_under_one_lock() {
+        let document = SharedHistoryWithContext::new(
+            ConcurrentCommandHistory::new(NonZeroUsize::new(100).unwrap(), true),
+            SharedContext::new(Vec::new()),
+        );
+
+        let handles: Vec<_> = (0..4)
+            .map(|thread| {
+                let document = document.clone();
+                thread::spawn(move || document.batch_execute(vec![Add(thread); 3]))
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let ctx = document.lock_context().clone();
+        assert_eq!(ctx.len(), 12);
+        assert!(ctx
+            .chunks(3)
+            .all(|batch| batch.iter().all(|v| *v == batch[0])));
+
+        document.undo();
+        assert_eq!(document.lock_context().len(), 9);
+        assert_eq!(document.history().position(), 3);
+        let newest = &document.history().undo_history().unwrap()[0];
+        assert_eq!(newest.description(), "Batch of 3 commands");
+        assert_eq!(newest.commands().len(), 3);
+    }
+
+    struct Fragile(i32);
+
+    impl MutableCommand for Fragile {
+        type Context = Vec<i32>;
+
+        fn execute(&self, ctx: &mut Self::Context) {
+            assert!(self.0 >= 0, "negative value");
+            ctx.push(self.0);
+        }
+
+        fn undo(&self, ctx: &mut Self::Context) {
+            ctx.pop();
+        }
+    }
+
+    #[test]
+    fn test_panicking_batch_is_reverted() {
+        let document = SharedHistoryWithContext::new(
+            ConcurrentCommandHistory::new(NonZeroUsize::new(5).unwrap(), true),
+            SharedContext::new(Vec::new()),
+        );
+        document.history().set_panic_policy(PanicPolicy::Discard);
+
+        document.batch_execute(vec![Fragile(1), Fragile(2), Fragile(-1), Fragile(3)]);
+        assert!(document.lock_context().is_empty());
+        assert_eq!(document.history().position(), 0);
+    }
+
+    #[test]
+    fn test_locked_context_gives_up() {
+        let document = SharedHistoryWithContext::new(
+            ConcurrentCommandHistory::new(NonZeroUsize::new(5).unwrap(), true),
+            SharedContext::default(),
+        );
+        document.execute_command(Add(1));
+
+        let guard = document.lock_context();
+        assert_eq!(document.try_undo(), Err(HistoryError::Busy));
+        assert_eq!(
+            document.execute_command_for(Add(2), Duration::from_millis(10)),
+            Err(HistoryError::Busy)
+        );
+        assert!(document
+            .context()
+            .try_lock_for(Duration::from_millis(10))
+            .is_none());
+        drop(guard);
+
+        document.try_execute_command(Add(2)).unwrap();
+        document.undo_for(Duration::from_secs(5)).unwrap();
+        document.try_redo().unwrap();
+        document.redo_for(Duration::ZERO).unwrap();
+        assert_eq!(*document.lock_context(), [1, 2]);
+    }
+}
@@ -0,0 +1,44 @@
+/// A condition on a context that a history checks after every execute, undo and redo.
+///
+/// When the check fails, the history reverts the step it just applied and reports the violation
+/// as [`HistoryError::InvariantViolated`](crate::error::HistoryError::InvariantViolated).
+///
+/// Implemented for closures that take the context and return a description of the violation as
+/// the error.
+///
+/// # Examples
+///
+/// ```
+/// use command_history::invariant::Invariant;
+///
+/// let no_duplicates = |ids: &Vec<u32>| {
+///     let mut sorted = ids.clone();
+///     sorted.sort_unstable();
+///     sorted.dedup();
+///     if sorted.len() == ids.len() {
+///         Ok(())
+///     } else {
+///         Err(String::from("duplicate id"))
+///     }
+/// };
+///
+/// assert!(no_duplicates.check(&vec![1, 2]).is_ok());
+/// assert!(no_duplicates.check(&vec![1, 1]).is_err());
+/// ```
+pub trait Invariant<T>: Send + Sync {
+    /// Returns a description of the violation if `ctx` breaks the invariant.
+    ///
+    /// # Errors
+    ///
+    /// Returns the description of the violation.
+    fn check(&self, ctx: &T) -> Result<(), String>;
+}
+
+impl<T, F> Invariant<T> for F
+where
+    F: Fn(&T) -> Result<(), String> + Send + Sync,
+{
+    fn check(&self, ctx: &T) -> Result<(), String> {
+        self(ctx)
+    }
+}
@@ -7,15 +7,56 @@
 #![warn(clippy::cargo)]
 #![allow(dead_code)]
 
+pub mod checkpoint;
+pub mod compaction;
 pub mod concurrent_command_history;
+pub mod error;
+pub mod history_entry;
+pub mod history_registry;
+pub mod history_with_context;
+pub mod invariant;
+pub mod mirror;
+pub mod multi_context;
+pub mod panic_policy;
+pub mod replay;
+pub mod retention;
+mod scope;
 pub mod shared_context;
+pub mod shared_rw_context;
 pub mod simple_command_history;
+pub mod snapshot_context;
+pub mod spill;
+pub mod sync;
+pub mod tentative;
+#[cfg(feature = "testing")]
+pub mod testing;
 pub mod traits;
 
 pub mod prelude {
+	pub use crate::checkpoint::{Checkpoint, CheckpointConfig, CheckpointInterval};
+	pub use crate::compaction::CompactionMode;
 	pub use crate::concurrent_command_history::ConcurrentCommandHistory;
-	pub use crate::shared_context::SharedContext;
+	pub use crate::error::HistoryError;
+	pub use crate::history_entry::{EntryMetadata, EntryPayload, HistoryEntry};
+	pub use crate::history_registry::{ConcurrentHistoryRegistry, HistoryRegistry, RegistryBudget};
+	pub use crate::history_with_context::{
+		ConcurrentHistoryWithContext, HistoryWithContext, SharedHistoryWithContext,
+	};
+	pub use crate::invariant::Invariant;
+	pub use crate::mirror::{MirrorDivergence, MirrorId};
+	pub use crate::multi_context::{lock_all, LockMany, MultiContext};
+	pub use crate::panic_policy::{PanicPolicy, PanicStage};
+	pub use crate::replay::ReplayDivergence;
+	pub use crate::retention::RetentionPolicy;
+	pub use crate::shared_context::{
+		ContextLock, SharedContext, SharedContextGuard, Versioned, WeakSharedContext,
+	};
+	pub use crate::shared_rw_context::SharedRwContext;
 	pub use crate::simple_command_history::SimpleCommandHistory;
+	pub use crate::snapshot_context::SnapshotContext;
+	pub use crate::spill::Spill;
+	pub use crate::sync::PoisonPolicy;
+	pub use crate::tentative::Tentative;
 	pub use crate::traits::command::Command;
 	pub use crate::traits::command_history::CommandHistory;
 	pub use crate::traits::mutable_command::MutableCommand;
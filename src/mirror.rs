@@ -0,0 +1,43 @@
+use std::fmt;
+
+use crate::panic_policy::PanicStage;
+
+/// Identifies a mirror registered with
+/// [`ConcurrentCommandHistory::add_mirror`](crate::concurrent_command_history::ConcurrentCommandHistory::add_mirror).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MirrorId(pub(crate) u64);
+
+/// Describes the step at which a mirror stopped following its history.
+///
+/// # Fields
+///
+/// * `mirror` - The mirror that diverged.
+/// * `stage` - Whether the entry was being executed, undone or redone.
+/// * `description` - The description of the entry.
+/// * `reason` - The panic message, or the violation reported by the history's invariant.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MirrorDivergence {
+    pub mirror: MirrorId,
+    pub stage: PanicStage,
+    pub description: String,
+    pub reason: String,
+}
+
+impl fmt::Display for MirrorDivergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "mirror {} diverged during {} ({}): {}",
+            self.mirror.0, self.stage, self.description, self.reason
+        )
+    }
+}
+
+impl std::error::Error for MirrorDivergence {}
+
+/// A secondary context that receives every step applied to the primary one.
+pub(crate) struct Mirror<T> {
+    pub(crate) id: MirrorId,
+    pub(crate) ctx: T,
+    pub(crate) divergence: Option<MirrorDivergence>,
+}
@@ -0,0 +1,252 @@
+use std::fmt;
+
+use crate::shared_context::{SharedContext, SharedContextGuard};
+
+/// Locks several [`SharedContext`]s of the same type at once and returns their guards in the
+/// order of `contexts`.
+///
+/// The contexts are always locked in the same global order, no matter the order they are passed
+/// in, so two threads locking overlapping sets can never deadlock each other.
+///
+/// # Panics
+///
+/// Panics if the same context is passed twice, since locking it a second time would deadlock.
+#[must_use]
+pub fn lock_all<'a, T>(contexts: &[&'a SharedContext<T>]) -> Vec<SharedContextGuard<'a, T>> {
+    let mut order: Vec<usize> = (0..contexts.len()).collect();
+    order.sort_unstable_by_key(|&index| contexts[index].address());
+    assert!(
+        order
+            .windows(2)
+            .all(|pair| contexts[pair[0]].address() != contexts[pair[1]].address()),
+        "the same context was passed twice"
+    );
+
+    let mut guards: Vec<_> = contexts.iter().map(|_| None).collect();
+    for index in order {
+        guards[index] = Some(contexts[index].lock());
+    }
+
+    guards.into_iter().flatten().collect()
+}
+
+/// Locks a tuple of [`SharedContext`] references of possibly different types in the same global
+/// order as [`lock_all`].
+///
+/// Implemented for tuples of two to four references.
+///
+/// # Examples
+///
+/// ```
+/// use command_history::{multi_context::LockMany, shared_context::SharedContext};
+///
+/// let document = SharedContext::new(String::from("text"));
+/// let clipboard = SharedContext::new(Vec::<String>::new());
+///
+/// let (mut document, mut clipboard) = (&document, &clipboard).lock_many();
+/// clipboard.push(std::mem::take(&mut *document));
+/// ```
+pub trait LockMany<'a> {
+    type Guards;
+
+    /// Locks every context and returns the guards in tuple order.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the same context appears twice.
+    fn lock_many(self) -> Self::Guards;
+}
+
+/// A set of shared contexts that is locked as a whole.
+///
+/// Implemented for tuples of two to four [`SharedContext`]s.
+pub trait ContextSet {
+    type Guards<'a>
+    where
+        Self: 'a;
+
+    fn lock_set(&self) -> Self::Guards<'_>;
+}
+
+macro_rules! impl_lock_many {
+    ($($name:ident: $index:tt),+) => {
+        impl<'a, $($name),+> LockMany<'a> for ($(&'a SharedContext<$name>,)+) {
+            type Guards = ($(SharedContextGuard<'a, $name>,)+);
+
+            fn lock_many(self) -> Self::Guards {
+                let mut order = [$((self.$index.address(), $index)),+];
+                order.sort_unstable();
+                assert!(
+                    order.windows(2).all(|pair| pair[0].0 != pair[1].0),
+                    "the same context was passed twice"
+                );
+
+                let mut guards = ($(None::<SharedContextGuard<'a, $name>>,)+);
+                for (_, index) in order {
+                    match index {
+                        $($index => guards.$index = Some(self.$index.lock()),)+
+                        _ => unreachable!(),
+                    }
+                }
+
+                ($(guards.$index.expect("every context was locked"),)+)
+            }
+        }
+
+        impl<$($name),+> ContextSet for ($(SharedContext<$name>,)+) {
+            type Guards<'a> = ($(SharedContextGuard<'a, $name>,)+) where Self: 'a;
+
+            fn lock_set(&self) -> Self::Guards<'_> {
+                ($(&self.$index,)+).lock_many()
+            }
+        }
+    };
+}
+
+impl_lock_many!(A: 0, B: 1);
+impl_lock_many!(A: 0, B: 1, C: 2);
+impl_lock_many!(A: 0, B: 1, C: 2, D: 3);
+
+/// A command context made of several [`SharedContext`]s, for commands that move data between
+/// them, e.g. from a document to a clipboard.
+///
+/// Use it as the `Context` of a [`Command`](crate::traits::command::Command) and call
+/// [`lock`](Self::lock) to get all guards at once without risking a deadlock with other commands
+/// locking the same contexts.
+///
+/// # Type Parameters
+///
+/// * `S` - A tuple of two to four `SharedContext`s.
+pub struct MultiContext<S> {
+    contexts: S,
+}
+
+impl<S: ContextSet> MultiContext<S> {
+    #[must_use]
+    pub fn new(contexts: S) -> Self {
+        Self { contexts }
+    }
+
+    /// Locks every context in the global order and returns the guards in tuple order.
+    pub fn lock(&self) -> S::Guards<'_> {
+        self.contexts.lock_set()
+    }
+
+    #[must_use]
+    pub fn contexts(&self) -> &S {
+        &self.contexts
+    }
+
+    #[must_use]
+    pub fn into_contexts(self) -> S {
+        self.contexts
+    }
+}
+
+impl<S: Clone> Clone for MultiContext<S> {
+    fn clone(&self) -> Self {
+        Self {
+            contexts: self.contexts.clone(),
+        }
+    }
+}
+
+impl<S: fmt::Debug> fmt::Debug for MultiContext<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("MultiContext").field(&self.contexts).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{num::NonZeroUsize, sync::Arc, thread};
+
+    use super::*;
+    use crate::{
+        concurrent_command_history::ConcurrentCommandHistory,
+        traits::{command::Command, command_history::CommandHistory},
+    };
+
+    struct Cut;
+
+    impl Command for Cut {
+        type Context = MultiContext<(SharedContext<Vec<char>>, SharedContext<Vec<char>>)>;
+
+        fn execute(&self, ctx: &Self::Context) {
+            let (mut document, mut clipboard) = ctx.lock();
+            if let Some(ch) = document.pop() {
+                clipboard.push(ch);
+            }
+        }
+
+        fn undo(&self, ctx: &Self::Context) {
+            let (mut document, mut clipboard) = ctx.lock();
+            if let Some(ch) = clipboard.pop() {
+                document.push(ch);
+            }
+        }
+    }
+
+    #[test]
+    fn test_lock_all_keeps_input_order() {
+        let first = SharedContext::new(1);
+        let second = SharedContext::new(2);
+
+        let guards = lock_all(&[&second, &first]);
+        assert_eq!([*guards[0], *guards[1]], [2, 1]);
+    }
+
+    #[test]
+    #[should_panic(expected = "the same context was passed twice")]
+    fn test_lock_many_rejects_duplicates() {
+        let context = SharedContext::new(0);
+        let _ = (&context, &context).lock_many();
+    }
+
+    #[test]
+    fn test_opposite_orders_do_not_deadlock() {
+        let left = SharedContext::new(0);
+        let right = SharedContext::new(0);
+
+        let handles: Vec<_> = (0..4)
+            .map(|thread| {
+                let (left, right) = (left.clone(), right.clone());
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        if thread % 2 == 0 {
+                            let (mut a, mut b) = (&left, &right).lock_many();
+                            *a += 1;
+                            *b += 1;
+                        } else {
+                            let (mut b, mut a) = (&right, &left).lock_many();
+                            *a += 1;
+                            *b += 1;
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!((*left.lock(), *right.lock()), (4000, 4000));
+    }
+
+    #[test]
+    fn test_multi_context_with_concurrent_history() {
+        let history = ConcurrentCommandHistory::new(NonZeroUsize::new(5).unwrap(), true);
+        let document = SharedContext::new(vec!['a', 'b']);
+        let clipboard = SharedContext::new(Vec::new());
+        let ctx = Arc::new(MultiContext::new((document.clone(), clipboard.clone())));
+
+        history.execute_command(Cut, &ctx);
+        history.execute_command(Cut, &ctx);
+        assert!(document.lock().is_empty());
+        assert_eq!(*clipboard.lock(), ['b', 'a']);
+
+        history.undo(&ctx);
+        assert_eq!(*document.lock(), ['a']);
+        assert_eq!(*clipboard.lock(), ['b']);
+    }
+}
@@ -0,0 +1,131 @@
+use std::{
+    any::Any,
+    fmt,
+    panic::{self, AssertUnwindSafe},
+};
+
+use crate::error::HistoryError;
+
+/// What a history does when a command panics while it is executed, undone or redone.
+///
+/// Whatever the policy, the stacks are left consistent: a command that panics while executed is
+/// never recorded, and an entry that panics while undone or redone is either put back where it was
+/// taken from or discarded. The context itself is left as the command left it when it panicked.
+///
+/// With the `std` lock backend, a command that panics while holding the guard of a
+/// [`SharedContext`](crate::shared_context::SharedContext) poisons it, and the history cannot
+/// tell. Under [`Restore`](Self::Restore) and [`Discard`](Self::Discard), repair the context and
+/// call [`clear_poison`](crate::shared_context::SharedContext::clear_poison) before retrying, or
+/// have the context recover poisoned locks with
+/// [`set_poison_policy`](crate::shared_context::SharedContext::set_poison_policy).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PanicPolicy {
+    /// Put the entry back where it was taken from and let the panic unwind to the caller. This
+    /// is the default.
+    #[default]
+    Propagate,
+    /// Catch the panic and put the entry back where it was taken from, so the operation can be
+    /// retried once the cause is fixed. The panic is reported as [`HistoryError::Panicked`].
+    Restore,
+    /// Catch the panic and discard the entry, together with the redo entries that build on it.
+    /// The panic is reported as [`HistoryError::Panicked`].
+    Discard,
+}
+
+/// The operation during which a command panicked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PanicStage {
+    Execute,
+    Undo,
+    Redo,
+}
+
+impl fmt::Display for PanicStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Execute => "execute",
+            Self::Undo => "undo",
+            Self::Redo => "redo",
+        })
+    }
+}
+
+/// A panic caught while running a command, held until the history has restored its invariants
+/// and released its locks.
+pub(crate) struct CaughtPanic {
+    stage: PanicStage,
+    description: String,
+    payload: Box<dyn Any + Send>,
+}
+
+impl CaughtPanic {
+    pub(crate) fn stage(&self) -> PanicStage {
+        self.stage
+    }
+
+    /// Resumes the panic under [`PanicPolicy::Propagate`], or turns it into an error otherwise.
+    pub(crate) fn settle(self, policy: PanicPolicy) -> HistoryError {
+        if policy == PanicPolicy::Propagate {
+            panic::resume_unwind(self.payload);
+        }
+
+        HistoryError::Panicked {
+            stage: self.stage,
+            message: self.message(),
+            description: self.description,
+        }
+    }
+
+    pub(crate) fn message(&self) -> String {
+        if let Some(message) = self.payload.downcast_ref::<&str>() {
+            (*message).to_owned()
+        } else if let Some(message) = self.payload.downcast_ref::<String>() {
+            message.clone()
+        } else {
+            String::from("<non-string panic payload>")
+        }
+    }
+}
+
+/// Why an operation of a history stopped, before the panic policy was applied.
+pub(crate) enum Failure {
+    Error(HistoryError),
+    Panic(CaughtPanic),
+}
+
+impl Failure {
+    /// Resumes a caught panic under [`PanicPolicy::Propagate`], or returns the error.
+    ///
+    /// Must only be called once the history released its locks, so unwinding cannot poison them.
+    pub(crate) fn settle(self, policy: PanicPolicy) -> HistoryError {
+        match self {
+            Self::Error(error) => error,
+            Self::Panic(caught) => caught.settle(policy),
+        }
+    }
+}
+
+impl From<HistoryError> for Failure {
+    fn from(error: HistoryError) -> Self {
+        Self::Error(error)
+    }
+}
+
+impl From<CaughtPanic> for Failure {
+    fn from(caught: CaughtPanic) -> Self {
+        Self::Panic(caught)
+    }
+}
+
+/// Runs `f`, catching a panic together with the description of the entry that caused it.
+pub(crate) fn catch<R>(
+    stage: PanicStage,
+    description: impl FnOnce() -> String,
+    f: impl FnOnce() -> R,
+) -> Result<R, CaughtPanic> {
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| CaughtPanic {
+        stage,
+        description: description(),
+        payload,
+    })
+}
@@ -0,0 +1,36 @@
+use std::fmt;
+
+/// Describes the first step at which a replayed history stopped matching the expected state.
+///
+/// Returned by `replay_with` on [`SimpleCommandHistory`](crate::simple_command_history::SimpleCommandHistory)
+/// and [`ConcurrentCommandHistory`](crate::concurrent_command_history::ConcurrentCommandHistory)
+/// when the comparison callback rejects the context after a command has been re-applied or a
+/// compacted snapshot entry has been restored.
+///
+/// # Fields
+///
+/// * `position` - The zero-based chronological index of the diverging command (0 is the oldest).
+/// * `description` - The description of the diverging command, or of the snapshot entry that
+///   covers it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplayDivergence {
+    pub position: usize,
+    pub description: String,
+}
+
+impl fmt::Display for ReplayDivergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "replay diverged at position {} ({})",
+            self.position, self.description
+        )
+    }
+}
+
+impl std::error::Error for ReplayDivergence {}
+
+/// Returns how many of `available` commands a replay should apply when asked to stop at `until`.
+pub(crate) fn replay_len(available: usize, until: Option<usize>) -> usize {
+    until.map_or(available, |until| until.min(available))
+}
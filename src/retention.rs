@@ -0,0 +1,297 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    time::{Duration, SystemTime},
+};
+
+/// A read-only view of a history entry handed to [`RetentionPolicy`] implementations.
+///
+/// # Fields
+///
+/// * `cost` - The command's `cost()`.
+/// * `description` - The command's `description()`.
+/// * `executed_at` - When the command was first executed.
+/// * `tags` - The tags attached to the entry.
+#[derive(Debug, Clone)]
+pub struct EntryInfo<'a> {
+    pub cost: usize,
+    pub description: Cow<'a, str>,
+    pub executed_at: SystemTime,
+    pub tags: &'a [String],
+}
+
+/// Decides which entries a history keeps.
+///
+/// Histories consult their policy after every push onto the undo or redo stack, and before
+/// executing a command through `try_execute_command`. Entries are always passed most recent
+/// first and can only be evicted from the oldest end, since dropping an entry from the middle
+/// of a stack would break the chain of states it leads through.
+///
+/// # Required Methods
+///
+/// * `excess(&self, entries)`: Returns how many of the oldest entries have to be evicted.
+///
+/// # Provided Methods
+///
+/// * `admits(&self, entries, candidate, limit)`: Returns whether `candidate` may be added at
+///   all. The default implementation always admits.
+pub trait RetentionPolicy: Send + Sync {
+    /// Returns how many entries, counted from the oldest end of `entries`, have to be evicted for
+    /// the rest to satisfy the policy.
+    fn excess(&self, entries: &[EntryInfo<'_>]) -> usize;
+
+    /// Returns whether `candidate` may be pushed on top of `entries`. Refused commands are not
+    /// executed.
+    ///
+    /// `limit` is how many entries the history holds before it drops the oldest one on its own,
+    /// whatever the policy says.
+    fn admits(
+        &self,
+        _entries: &[EntryInfo<'_>],
+        _candidate: &EntryInfo<'_>,
+        _limit: usize,
+    ) -> bool {
+        true
+    }
+}
+
+/// Keeps at most the given number of entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MaxCount(pub usize);
+
+impl RetentionPolicy for MaxCount {
+    fn excess(&self, entries: &[EntryInfo<'_>]) -> usize {
+        entries.len().saturating_sub(self.0)
+    }
+}
+
+/// Keeps the most recent entries whose summed `cost()` stays within the given budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MaxCost(pub usize);
+
+impl RetentionPolicy for MaxCost {
+    fn excess(&self, entries: &[EntryInfo<'_>]) -> usize {
+        let mut total = 0usize;
+        let kept = entries
+            .iter()
+            .take_while(|entry| {
+                total = total.saturating_add(entry.cost);
+                total <= self.0
+            })
+            .count();
+
+        entries.len() - kept
+    }
+}
+
+/// Evicts entries that were executed longer ago than the given duration.
+///
+/// Age only grows while the history is idle, so call `enforce_retention` on the history to
+/// apply this policy without pushing a new entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MaxAge(pub Duration);
+
+impl RetentionPolicy for MaxAge {
+    fn excess(&self, entries: &[EntryInfo<'_>]) -> usize {
+        let now = SystemTime::now();
+        entries
+            .iter()
+            .rev()
+            .take_while(|entry| {
+                now.duration_since(entry.executed_at)
+                    .is_ok_and(|age| age > self.0)
+            })
+            .count()
+    }
+}
+
+/// Limits how many entries of each category are kept, where the category is derived from the
+/// command description.
+///
+/// Categories without a quota are unlimited. Since entries can only be evicted from the oldest
+/// end, exceeding a quota evicts everything up to and including the oldest entry over quota.
+pub struct CategoryQuota {
+    categorize: Box<dyn Fn(&str) -> String + Send + Sync>,
+    quotas: HashMap<String, usize>,
+}
+
+impl CategoryQuota {
+    /// Creates a policy that maps each description to a category with `categorize`.
+    #[must_use]
+    pub fn new<F>(categorize: F) -> Self
+    where
+        F: Fn(&str) -> String + Send + Sync + 'static,
+    {
+        Self {
+            categorize: Box::new(categorize),
+            quotas: HashMap::new(),
+        }
+    }
+
+    /// Creates a policy whose category is the part of the description before the first `:`.
+    #[must_use]
+    pub fn by_prefix() -> Self {
+        Self::new(|description| {
+            description
+                .split_once(':')
+                .map_or(description, |(prefix, _)| prefix)
+                .trim()
+                .to_owned()
+        })
+    }
+
+    #[must_use]
+    pub fn quota(mut self, category: impl Into<String>, max: usize) -> Self {
+        self.quotas.insert(category.into(), max);
+        self
+    }
+}
+
+impl RetentionPolicy for CategoryQuota {
+    fn excess(&self, entries: &[EntryInfo<'_>]) -> usize {
+        let mut seen: HashMap<String, usize> = HashMap::new();
+        for (index, entry) in entries.iter().enumerate() {
+            let category = (self.categorize)(&entry.description);
+            let Some(&max) = self.quotas.get(&category) else {
+                continue;
+            };
+
+            let count = seen.entry(category).or_default();
+            *count += 1;
+            if *count > max {
+                return entries.len() - index;
+            }
+        }
+
+        0
+    }
+}
+
+/// Refuses new commands once the wrapped policy would have to evict something or the history limit
+/// is reached, instead of dropping or compacting the oldest entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RefuseWhenFull<P>(pub P);
+
+impl<P: RetentionPolicy> RetentionPolicy for RefuseWhenFull<P> {
+    fn excess(&self, _entries: &[EntryInfo<'_>]) -> usize {
+        0
+    }
+
+    fn admits(&self, entries: &[EntryInfo<'_>], candidate: &EntryInfo<'_>, limit: usize) -> bool {
+        if entries.len() >= limit {
+            return false;
+        }
+
+        let mut with_candidate = Vec::with_capacity(entries.len() + 1);
+        with_candidate.push(candidate.clone());
+        with_candidate.extend_from_slice(entries);
+
+        self.0.excess(&with_candidate) == 0 && self.0.admits(entries, candidate, limit)
+    }
+}
+
+/// Combines several policies: an entry is kept only if every policy keeps it, and a command is
+/// admitted only if every policy admits it.
+#[derive(Default)]
+pub struct AllOf {
+    policies: Vec<Box<dyn RetentionPolicy>>,
+}
+
+impl AllOf {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[must_use]
+    pub fn with<P: RetentionPolicy + 'static>(mut self, policy: P) -> Self {
+        self.policies.push(Box::new(policy));
+        self
+    }
+}
+
+impl RetentionPolicy for AllOf {
+    fn excess(&self, entries: &[EntryInfo<'_>]) -> usize {
+        self.policies
+            .iter()
+            .map(|policy| policy.excess(entries))
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn admits(&self, entries: &[EntryInfo<'_>], candidate: &EntryInfo<'_>, limit: usize) -> bool {
+        self.policies
+            .iter()
+            .all(|policy| policy.admits(entries, candidate, limit))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(cost: usize, description: &'static str) -> EntryInfo<'static> {
+        EntryInfo {
+            cost,
+            description: Cow::Borrowed(description),
+            executed_at: SystemTime::now(),
+            tags: &[],
+        }
+    }
+
+    #[test]
+    fn test_max_count() {
+        let entries = [info(1, "a"), info(1, "b"), info(1, "c")];
+        assert_eq!(MaxCount(2).excess(&entries), 1);
+        assert_eq!(MaxCount(5).excess(&entries), 0);
+    }
+
+    #[test]
+    fn test_max_cost() {
+        let entries = [info(3, "a"), info(4, "b"), info(2, "c"), info(1, "d")];
+        assert_eq!(MaxCost(7).excess(&entries), 2);
+        assert_eq!(MaxCost(10).excess(&entries), 0);
+        assert_eq!(MaxCost(2).excess(&entries), 4);
+    }
+
+    #[test]
+    fn test_max_age() {
+        let mut old = info(1, "old");
+        old.executed_at = SystemTime::now() - Duration::from_mins(1);
+        let entries = [info(1, "new"), old.clone(), old];
+
+        assert_eq!(MaxAge(Duration::from_secs(45)).excess(&entries), 2);
+        assert_eq!(MaxAge(Duration::from_mins(2)).excess(&entries), 0);
+    }
+
+    #[test]
+    fn test_category_quota() {
+        let policy = CategoryQuota::by_prefix().quota("paint", 1);
+        let entries = [
+            info(1, "paint: red"),
+            info(1, "move: left"),
+            info(1, "paint: blue"),
+            info(1, "move: up"),
+        ];
+
+        assert_eq!(policy.excess(&entries), 2);
+        assert_eq!(policy.excess(&entries[1..]), 0);
+    }
+
+    #[test]
+    fn test_refuse_when_full_and_all_of() {
+        let entries = [info(1, "a"), info(1, "b")];
+        let refuse = RefuseWhenFull(MaxCount(2));
+        assert_eq!(refuse.excess(&entries), 0);
+        assert!(!refuse.admits(&entries, &info(1, "c"), 10));
+        assert!(refuse.admits(&entries[1..], &info(1, "c"), 10));
+        assert!(!refuse.admits(&entries[1..], &info(1, "c"), 1));
+
+        let combined = AllOf::new().with(MaxCount(3)).with(MaxCost(1));
+        assert_eq!(combined.excess(&entries), 1);
+        assert!(combined.admits(&entries, &info(1, "c"), 2));
+
+        let combined = combined.with(refuse);
+        assert!(!combined.admits(&entries, &info(1, "c"), 10));
+    }
+}
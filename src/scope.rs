@@ -0,0 +1,47 @@
+use std::collections::VecDeque;
+
+use crate::{
+    checkpoint::Checkpoints,
+    compaction::merged_metadata,
+    history_entry::{EntryPayload, HistoryEntry},
+    retention::RetentionPolicy,
+};
+
+/// The state of a parent history set aside while a child scope is open.
+///
+/// The child works on fresh stacks without a limit, checkpoints or retention policy, so nothing
+/// it records can be evicted before it is committed or aborted.
+pub(crate) struct Scope<E, S> {
+    pub(crate) undo: VecDeque<E>,
+    pub(crate) redo: VecDeque<E>,
+    pub(crate) history_limit: usize,
+    pub(crate) checkpoints: Option<Checkpoints<S>>,
+    pub(crate) retention: Option<Box<dyn RetentionPolicy>>,
+}
+
+/// Folds the undo stack of a committed scope into a single parent entry.
+///
+/// Returns `None` if the scope recorded nothing, and a plain command entry if it recorded a single
+/// command.
+pub(crate) fn fold<C, S>(undo: VecDeque<HistoryEntry<C, S>>) -> Option<HistoryEntry<C, S>> {
+    let entries: Vec<_> = undo.into_iter().rev().collect();
+    let executed_at = entries.first()?.executed_at();
+    let metadata = merged_metadata(&entries);
+
+    let mut commands: Vec<C> = entries
+        .into_iter()
+        .flat_map(|entry| match entry.into_payload() {
+            EntryPayload::Command(command) => vec![command],
+            EntryPayload::Compound(commands) => commands,
+            EntryPayload::Snapshot { .. } => unreachable!("scopes never compact their entries"),
+        })
+        .collect();
+
+    let payload = if commands.len() == 1 {
+        EntryPayload::Command(commands.pop()?)
+    } else {
+        EntryPayload::Compound(commands)
+    };
+
+    Some(HistoryEntry::from_payload(payload, metadata).with_executed_at(executed_at))
+}
@@ -1,16 +1,27 @@
-use parking_lot::{Mutex, MutexGuard};
-use std::sync::Arc;
+use std::{
+    fmt,
+    ops::{Deref, DerefMut},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, LockResult, Weak,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::sync::{self, Condvar, Mutex, MutexGuard, PoisonPolicy, PoisonPolicyExt};
 
 /// A thread-safe shared context that wraps a value of type `T` using an `Arc<Mutex<T>>`.
 ///
 /// This allows multiple threads to have shared ownership of the value and safely access
-/// or modify it.
+/// or modify it. Every mutable access bumps a version counter, so other threads can wait for
+/// changes or subscribe to them instead of polling.
 ///
 /// # Examples
 ///
 /// ```
 /// use std::sync::Arc;
-/// use parking_lot::Mutex;
+/// use command_history::sync::Mutex;
 /// use command_history::shared_context::SharedContext;
 ///
 /// let context = SharedContext::new(5);
@@ -19,6 +30,7 @@ use std::sync::Arc;
 ///     *value += 1;
 /// }
 /// assert_eq!(*context.lock(), 6);
+/// assert_eq!(context.version(), 1);
 /// ```
 ///
 /// # Type Parameters
@@ -28,36 +40,206 @@ use std::sync::Arc;
 /// # Methods
 ///
 /// * `new(value: T) -> Self` - Creates a new `SharedContext` with the given value. The value is wrapped in an `Arc<Mutex<T>>`.
-/// * `lock(&self) -> MutexGuard<'_, T>` - Locks the mutex and returns a guard that allows access to the value. Blocks if the mutex is already locked.
-/// * `try_lock(&self) -> Option<MutexGuard<'_, T>>` - Tries to lock the mutex and returns a guard if successful. Returns `None` if the mutex is already locked.
+/// * `lock(&self) -> SharedContextGuard<'_, T>` - Locks the mutex and returns a guard that allows access to the value. Blocks if the mutex is already locked.
+/// * `try_lock(&self) -> Option<SharedContextGuard<'_, T>>` - Tries to lock the mutex and returns a guard if successful. Returns `None` if the mutex is already locked.
+/// * `try_lock_for(&self, timeout: Duration) -> Option<SharedContextGuard<'_, T>>` - Like `try_lock`, but waits up to `timeout` for the mutex.
+/// * `lock_checked(&self) -> LockResult<SharedContextGuard<'_, T>>` - Like `lock`, but returns poisoning as an error instead of applying the poison policy.
+/// * `is_poisoned`, `clear_poison` - Inspect and reset the poisoning of the lock. Only the `std` backend poisons its locks.
+/// * `set_poison_policy(&self, policy)` - Chooses whether `lock` panics or recovers once the lock is poisoned, for this context only.
 /// * `into_inner(self) -> T` - Consumes the `SharedContext` and returns the inner value. Panics if there are multiple references to the `SharedContext`.
+/// * `try_into_inner(self) -> Result<T, Self>` - Like `into_inner`, but hands the context back instead of panicking.
+/// * `downgrade(&self) -> WeakSharedContext<T>` - Creates a non-owning handle to the same value.
+/// * `strong_count(&self) -> usize` - Returns how many `SharedContext` handles share the value.
+/// * `ptr_eq(&self, other) -> bool` - Returns whether two handles share the same value.
 /// * `modify<F>(&self, f: F)` - Modifies the value using the given closure. The value is locked during the call.
+/// * `transaction<F, R, E>(&self, f: F) -> Result<R, E>` - Like `modify`, but rolls the value back if the closure fails or panics.
+/// * `version(&self) -> u64` - Returns how many times the value was mutably accessed.
+/// * `wait_for_change(&self, since, timeout) -> Option<u64>` - Blocks until the version moves past `since`.
+/// * `subscribe(&self, f) -> SubscriptionId` - Calls `f` with the new version after every change.
+/// * `unsubscribe(&self, id) -> bool` - Removes a subscriber.
 ///
 /// # Traits
 ///
 /// * `Clone` - Allows cloning the `SharedContext`, which will share the same underlying value.
-/// * `From<Arc<Mutex<T>>>` - Allows creating a `SharedContext` from an existing `Arc<Mutex<T>>`.
+/// * `From<Arc<Mutex<T>>>` - Allows creating a `SharedContext` from an existing `Arc<Mutex<T>>`. Changes made through the original `Arc` are not versioned, and every conversion starts its own version counter and subscriber list: two contexts converted from the same `Arc` share the value but not its versioning, so `wait_for_change` on one never sees changes made through the other. Clone one context instead.
 /// * `AsRef<Arc<Mutex<T>>>` - Allows getting a reference to the underlying `Arc<Mutex<T>>`.
 /// * `Default` - Allows creating a default `SharedContext` with a default value of `T`.
 /// * `Debug` - Allows debugging the `SharedContext`. The value is locked during the call.
 pub struct SharedContext<T> {
     inner: Arc<Mutex<T>>,
+    versioning: Arc<Versioning>,
+}
+
+/// A context that counts its changes, which lets
+/// [`ConcurrentCommandHistory::undo_if_unchanged`](crate::concurrent_command_history::ConcurrentCommandHistory::undo_if_unchanged)
+/// notice changes made behind the history's back.
+pub trait Versioned {
+    /// Returns a number that grows with every change of the context.
+    fn version(&self) -> u64;
+}
+
+impl<T> Versioned for SharedContext<T> {
+    fn version(&self) -> u64 {
+        SharedContext::version(self)
+    }
+}
+
+/// A context guarded by a lock, which lets
+/// [`ConcurrentCommandHistory::enable_context_deadlines`](crate::concurrent_command_history::ConcurrentCommandHistory::enable_context_deadlines)
+/// bound the wait for it.
+pub trait ContextLock {
+    /// Waits until the context can be locked for writing or `deadline` passes, and returns whether
+    /// it could. The lock is released again before returning.
+    fn lockable_by(&self, deadline: Instant) -> bool;
+}
+
+impl<T> ContextLock for SharedContext<T> {
+    fn lockable_by(&self, deadline: Instant) -> bool {
+        self.inner.try_lock_until(deadline).is_some()
+    }
+}
+
+/// Identifies a subscriber registered with [`SharedContext::subscribe`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SubscriptionId(u64);
+
+type Subscriber = Arc<dyn Fn(u64) + Send + Sync>;
+
+#[derive(Default)]
+struct Versioning {
+    version: AtomicU64,
+    changed: Condvar,
+    next_subscription: AtomicU64,
+    subscribers: Mutex<Vec<(SubscriptionId, Subscriber)>>,
+}
+
+/// The guard returned by [`SharedContext::lock`].
+///
+/// Dereferencing it mutably marks the value as changed. When a changed guard is released, the
+/// version is bumped, waiting threads are woken and subscribers are called on the releasing
+/// thread, after the lock has been released.
+pub struct SharedContextGuard<'a, T> {
+    guard: Option<MutexGuard<'a, T>>,
+    versioning: &'a Versioning,
+    changed: bool,
+}
+
+impl<T> SharedContextGuard<'_, T> {
+    /// Restores `backup` and forgets that the value was changed.
+    fn roll_back(&mut self, backup: T) {
+        **self = backup;
+        self.changed = false;
+    }
+}
+
+impl<T> Deref for SharedContextGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard
+            .as_ref()
+            .expect("the guard is only taken when dropped")
+    }
+}
+
+impl<T> DerefMut for SharedContextGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.changed = true;
+        self.guard
+            .as_mut()
+            .expect("the guard is only taken when dropped")
+    }
+}
+
+impl<T> Drop for SharedContextGuard<'_, T> {
+    fn drop(&mut self) {
+        if !self.changed {
+            return;
+        }
+
+        // Bumped while still locked, so a waiter checking the version under the lock cannot miss it.
+        let version = self.versioning.version.fetch_add(1, Ordering::AcqRel) + 1;
+        drop(self.guard.take());
+
+        self.versioning.changed.notify_all();
+
+        let subscribers: Vec<_> = self
+            .versioning
+            .subscribers
+            .lock()
+            .iter()
+            .map(|(_, subscriber)| Arc::clone(subscriber))
+            .collect();
+        for subscriber in subscribers {
+            subscriber(version);
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for SharedContextGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
 }
 
 impl<T> SharedContext<T> {
     pub fn new(value: T) -> Self {
-        Self {
-            inner: Arc::new(Mutex::new(value)),
-        }
+        Self::from(Arc::new(Mutex::new(value)))
+    }
+
+    #[must_use]
+    pub fn lock(&self) -> SharedContextGuard<'_, T> {
+        self.guard(self.inner.lock())
+    }
+
+    /// Locks the context like [`lock`](Self::lock), but reports a poisoned lock to the caller
+    /// instead of applying the [`PoisonPolicy`].
+    ///
+    /// The value of a poisoned lock can still be inspected or repaired through the guard inside
+    /// the error. Call [`clear_poison`](Self::clear_poison) once it is consistent again.
+    ///
+    /// # Errors
+    ///
+    /// Returns the guard wrapped in a [`PoisonError`](std::sync::PoisonError) if a thread panicked while holding the
+    /// lock. The `parking_lot` backend never poisons its locks.
+    pub fn lock_checked(&self) -> LockResult<SharedContextGuard<'_, T>> {
+        sync::map_lock_result(sync::lock_checked(&self.inner), |guard| self.guard(guard))
+    }
+
+    /// Returns whether a thread panicked while holding the lock.
+    #[must_use]
+    pub fn is_poisoned(&self) -> bool {
+        sync::is_poisoned(&self.inner)
+    }
+
+    /// Marks a poisoned lock as usable again.
+    pub fn clear_poison(&self) {
+        sync::clear_poison(&self.inner);
+    }
+
+    /// Sets what locking this context does once a thread panicked while holding it. The policy is
+    /// shared by every handle to the value and does not affect other contexts.
+    pub fn set_poison_policy(&self, policy: PoisonPolicy) {
+        self.inner.set_poison_policy(policy);
     }
 
-    pub fn lock(&self) -> MutexGuard<'_, T> {
-        self.inner.lock()
+    #[must_use]
+    pub fn try_lock(&self) -> Option<SharedContextGuard<'_, T>> {
+        self.inner.try_lock().map(|guard| self.guard(guard))
     }
 
+    /// Locks the context, giving up if it is not available within `timeout`.
     #[must_use]
-    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
-        self.inner.try_lock()
+    pub fn try_lock_for(&self, timeout: Duration) -> Option<SharedContextGuard<'_, T>> {
+        self.lock_until(sync::deadline_after(timeout))
+    }
+
+    /// Locks the context, giving up once `deadline` has passed. `None` waits as long as it takes.
+    pub(crate) fn lock_until(
+        &self,
+        deadline: Option<Instant>,
+    ) -> Option<SharedContextGuard<'_, T>> {
+        sync::lock_until(&self.inner, deadline).map(|guard| self.guard(guard))
     }
 
     /// Consumes the `SharedContext` and returns the inner value.
@@ -67,10 +249,44 @@ impl<T> SharedContext<T> {
     /// Panics if there are multiple references to the `SharedContext`.
     #[must_use]
     pub fn into_inner(self) -> T {
-        Arc::try_unwrap(self.inner)
+        self.try_into_inner()
             .ok()
             .expect("Multiple references to SharedContext exist")
-            .into_inner()
+    }
+
+    /// Consumes the `SharedContext` and returns the inner value, or hands the context back if
+    /// other references to it exist.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err(self)` if the value is shared with other `SharedContext`s or `Arc`s.
+    pub fn try_into_inner(self) -> Result<T, Self> {
+        let versioning = self.versioning;
+        Arc::try_unwrap(self.inner)
+            .map(Mutex::into_inner)
+            .map_err(|inner| Self { inner, versioning })
+    }
+
+    /// Creates a handle that does not keep the value alive, e.g. for long-lived commands or
+    /// observers.
+    #[must_use]
+    pub fn downgrade(&self) -> WeakSharedContext<T> {
+        WeakSharedContext {
+            inner: Arc::downgrade(&self.inner),
+            versioning: Arc::downgrade(&self.versioning),
+        }
+    }
+
+    /// Returns how many strong handles share the value.
+    #[must_use]
+    pub fn strong_count(&self) -> usize {
+        Arc::strong_count(&self.inner)
+    }
+
+    /// Returns whether both handles share the same value.
+    #[must_use]
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
     }
 
     pub fn modify<F>(&self, f: F)
@@ -80,12 +296,184 @@ impl<T> SharedContext<T> {
         let mut value = self.lock();
         f(&mut *value);
     }
+
+    /// Modifies the value with `f` as a single all-or-nothing change.
+    ///
+    /// The value is locked and backed up before `f` runs. If `f` returns `Err` or panics, the
+    /// backup is restored before the lock is released, so no one ever sees the partial change and
+    /// the version is not bumped. Panics are resumed after the rollback.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of `f` if it failed, in which case the change was rolled back. `Ok` means
+    /// the change was committed.
+    pub fn transaction<F, R, E>(&self, f: F) -> Result<R, E>
+    where
+        T: Clone,
+        F: FnOnce(&mut T) -> Result<R, E>,
+    {
+        let mut guard = self.lock();
+        let backup = T::clone(&guard);
+
+        match panic::catch_unwind(AssertUnwindSafe(|| f(&mut guard))) {
+            Ok(Ok(value)) => Ok(value),
+            Ok(Err(error)) => {
+                guard.roll_back(backup);
+                Err(error)
+            }
+            Err(payload) => {
+                guard.roll_back(backup);
+                drop(guard);
+                panic::resume_unwind(payload)
+            }
+        }
+    }
+
+    /// Returns the current version, which is bumped every time a guard that was mutably
+    /// dereferenced is released.
+    #[must_use]
+    pub fn version(&self) -> u64 {
+        self.versioning.version.load(Ordering::Acquire)
+    }
+
+    /// Blocks until the version is greater than `since`, e.g. a version returned by an earlier
+    /// call to [`version`](Self::version).
+    ///
+    /// The context is locked while waiting, so calling this while the current thread holds a guard
+    /// of the same context deadlocks.
+    ///
+    /// # Returns
+    ///
+    /// The new version, or `None` if `timeout` elapsed first. A `timeout` too long to be
+    /// represented waits as long as it takes.
+    #[must_use]
+    pub fn wait_for_change(&self, since: u64, timeout: Option<Duration>) -> Option<u64> {
+        let deadline = timeout.and_then(sync::deadline_after);
+        let policy = self.inner.poison_policy();
+        let mut guard = self.inner.lock();
+
+        loop {
+            let version = self.version();
+            if version > since {
+                return Some(version);
+            }
+
+            let (next, timed_out) = self.versioning.changed.wait_until(guard, deadline, policy);
+            if timed_out {
+                return Some(self.version()).filter(|version| *version > since);
+            }
+            guard = next;
+        }
+    }
+
+    /// Registers `subscriber` to be called with the new version after every change.
+    ///
+    /// Subscribers run on the thread that made the change, after the value was unlocked, so they
+    /// may lock the context themselves.
+    pub fn subscribe<F>(&self, subscriber: F) -> SubscriptionId
+    where
+        F: Fn(u64) + Send + Sync + 'static,
+    {
+        let id = SubscriptionId(
+            self.versioning
+                .next_subscription
+                .fetch_add(1, Ordering::Relaxed),
+        );
+        self.versioning
+            .subscribers
+            .lock()
+            .push((id, Arc::new(subscriber)));
+        id
+    }
+
+    /// Removes a subscriber and returns whether it was registered.
+    #[must_use]
+    pub fn unsubscribe(&self, id: SubscriptionId) -> bool {
+        let mut subscribers = self.versioning.subscribers.lock();
+        let before = subscribers.len();
+        subscribers.retain(|(subscribed, _)| *subscribed != id);
+        before != subscribers.len()
+    }
+
+    /// The key that orders contexts for locking several of them at once.
+    pub(crate) fn address(&self) -> usize {
+        Arc::as_ptr(&self.inner).cast::<()>() as usize
+    }
+
+    fn guard<'a>(&'a self, guard: MutexGuard<'a, T>) -> SharedContextGuard<'a, T> {
+        SharedContextGuard {
+            guard: Some(guard),
+            versioning: &self.versioning,
+            changed: false,
+        }
+    }
+}
+
+/// A non-owning handle to the value of a [`SharedContext`], created with
+/// [`SharedContext::downgrade`].
+pub struct WeakSharedContext<T> {
+    inner: Weak<Mutex<T>>,
+    versioning: Weak<Versioning>,
+}
+
+impl<T> WeakSharedContext<T> {
+    /// Creates a handle that never upgrades.
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            inner: Weak::new(),
+            versioning: Weak::new(),
+        }
+    }
+
+    /// Returns a strong handle if the value is still alive.
+    #[must_use]
+    pub fn upgrade(&self) -> Option<SharedContext<T>> {
+        Some(SharedContext {
+            inner: self.inner.upgrade()?,
+            versioning: self.versioning.upgrade()?,
+        })
+    }
+
+    /// Returns how many strong handles keep the value alive.
+    #[must_use]
+    pub fn strong_count(&self) -> usize {
+        self.inner.strong_count()
+    }
+
+    /// Returns whether both handles point to the same value.
+    #[must_use]
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        self.inner.ptr_eq(&other.inner)
+    }
+}
+
+impl<T> Clone for WeakSharedContext<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Weak::clone(&self.inner),
+            versioning: Weak::clone(&self.versioning),
+        }
+    }
+}
+
+impl<T> Default for WeakSharedContext<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> fmt::Debug for WeakSharedContext<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "WeakSharedContext(strong: {})", self.strong_count())
+    }
 }
 
 impl<T> Clone for SharedContext<T> {
     fn clone(&self) -> Self {
         Self {
             inner: Arc::clone(&self.inner),
+            versioning: Arc::clone(&self.versioning),
         }
     }
 }
@@ -111,9 +499,14 @@ where
     }
 }
 
+/// Wraps `arc` with a version counter and subscriber list of its own, which are not shared with
+/// any other context converted from the same `Arc`.
 impl<T> From<Arc<Mutex<T>>> for SharedContext<T> {
     fn from(arc: Arc<Mutex<T>>) -> Self {
-        Self { inner: arc }
+        Self {
+            inner: arc,
+            versioning: Arc::default(),
+        }
     }
 }
 
@@ -249,4 +642,247 @@ mod tests {
         let guard = context.lock();
         assert_eq!(format!("{guard:?}"), "5");
     }
+
+    #[test]
+    fn test_version_bumps_on_mutable_access() {
+        let context = SharedContext::new(5);
+        assert_eq!(*context.lock(), 5);
+        assert_eq!(context.version(), 0);
+
+        *context.lock() += 1;
+        context.modify(|value| *value += 1);
+        assert_eq!(context.version(), 2);
+        assert_eq!(context.clone().version(), 2);
+    }
+
+    #[test]
+    fn test_wait_for_change() {
+        let context = SharedContext::new(0);
+        let since = context.version();
+        assert_eq!(
+            context.wait_for_change(since, Some(Duration::from_millis(10))),
+            None
+        );
+
+        let writer = context.clone();
+        let handle = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            *writer.lock() = 1;
+        });
+
+        assert_eq!(context.wait_for_change(since, None), Some(1));
+        handle.join().unwrap();
+        assert_eq!(context.wait_for_change(0, None), Some(1));
+        assert_eq!(context.wait_for_change(0, Some(Duration::MAX)), Some(1));
+    }
+
+    #[test]
+    fn test_subscribers() {
+        let context = SharedContext::new(0);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+
+        let observer = context.clone();
+        let log = Arc::clone(&seen);
+        let id = context.subscribe(move |version| log.lock().push((version, *observer.lock())));
+
+        context.modify(|value| *value = 7);
+        assert!(context.unsubscribe(id));
+        assert!(!context.unsubscribe(id));
+        context.modify(|value| *value = 8);
+
+        assert_eq!(*seen.lock(), [(1, 7)]);
+    }
+
+    #[test]
+    fn test_history_changes_are_observable() {
+        use crate::{
+            concurrent_command_history::ConcurrentCommandHistory,
+            traits::{command::Command, command_history::CommandHistory},
+        };
+
+        struct Set(i32);
+
+        impl Command for Set {
+            type Context = SharedContext<Vec<i32>>;
+
+            fn execute(&self, ctx: &Self::Context) {
+                ctx.lock().push(self.0);
+            }
+
+            fn undo(&self, ctx: &Self::Context) {
+                ctx.lock().pop();
+            }
+        }
+
+        let history = ConcurrentCommandHistory::new(std::num::NonZeroUsize::new(5).unwrap(), true);
+        let context = SharedContext::new(Vec::new());
+
+        let reader = context.clone();
+        let since = reader.version();
+        let handle = std::thread::spawn(move || reader.wait_for_change(since, None));
+
+        history.execute_command(Set(1), &context);
+        assert_eq!(handle.join().unwrap(), Some(1));
+
+        history.undo(&context);
+        assert_eq!(context.version(), 2);
+    }
+
+    #[test]
+    fn test_try_into_inner() {
+        let context = SharedContext::new(100);
+        let cloned = context.clone();
+        assert!(context.ptr_eq(&cloned));
+        assert_eq!(context.strong_count(), 2);
+
+        let context = context.try_into_inner().unwrap_err();
+        drop(cloned);
+        assert_eq!(context.try_into_inner().ok(), Some(100));
+    }
+
+    #[test]
+    fn test_weak_handles() {
+        let context = SharedContext::new(1);
+        let weak = context.downgrade();
+        assert_eq!(weak.strong_count(), 1);
+        assert!(weak.ptr_eq(&context.downgrade()));
+
+        let upgraded = weak.upgrade().unwrap();
+        *upgraded.lock() += 1;
+        assert_eq!(context.version(), 1);
+        assert!(upgraded.ptr_eq(&context));
+        drop(upgraded);
+
+        assert_eq!(context.into_inner(), 2);
+        assert!(weak.upgrade().is_none());
+        assert!(WeakSharedContext::<i32>::default().upgrade().is_none());
+        assert_eq!(format!("{weak:?}"), "WeakSharedContext(strong: 0)");
+    }
+
+    #[test]
+    fn test_commands_holding_weak_contexts() {
+        use crate::{
+            concurrent_command_history::ConcurrentCommandHistory,
+            traits::{command::Command, command_history::CommandHistory},
+        };
+
+        struct Notify {
+            observer: WeakSharedContext<Vec<&'static str>>,
+        }
+
+        impl Command for Notify {
+            type Context = SharedContext<i32>;
+
+            fn execute(&self, ctx: &Self::Context) {
+                *ctx.lock() += 1;
+                if let Some(observer) = self.observer.upgrade() {
+                    observer.lock().push("executed");
+                }
+            }
+
+            fn undo(&self, ctx: &Self::Context) {
+                *ctx.lock() -= 1;
+                if let Some(observer) = self.observer.upgrade() {
+                    observer.lock().push("undone");
+                }
+            }
+        }
+
+        let history = ConcurrentCommandHistory::new(std::num::NonZeroUsize::new(5).unwrap(), true);
+        let context = SharedContext::new(0);
+        let observer = SharedContext::new(Vec::new());
+
+        history.execute_command(
+            Notify {
+                observer: observer.downgrade(),
+            },
+            &context,
+        );
+        assert_eq!(observer.try_into_inner().ok(), Some(vec!["executed"]));
+
+        history.undo(&context);
+        assert_eq!(context.into_inner(), 0);
+    }
+
+    #[test]
+    fn test_transaction_commits_or_rolls_back() {
+        let context = SharedContext::new(vec![1]);
+
+        let committed: Result<usize, ()> = context.transaction(|value| {
+            value.push(2);
+            Ok(value.len())
+        });
+        assert_eq!(committed, Ok(2));
+        assert_eq!(context.version(), 1);
+
+        let failed = context.transaction(|value| {
+            value.push(3);
+            if value.len() > 2 {
+                Err("too long")
+            } else {
+                Ok(())
+            }
+        });
+        assert_eq!(failed, Err("too long"));
+        assert_eq!(*context.lock(), [1, 2]);
+        assert_eq!(context.version(), 1);
+    }
+
+    #[test]
+    fn test_transaction_rolls_back_on_panic() {
+        let context = SharedContext::new(vec![1]);
+
+        let outcome = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            context.transaction(|value| -> Result<(), ()> {
+                value.clear();
+                panic!("halfway through");
+            })
+        }));
+
+        assert!(outcome.is_err());
+        assert_eq!(*context.lock(), [1]);
+        assert_eq!(context.version(), 0);
+    }
+
+    #[test]
+    #[cfg(not(feature = "parking_lot"))]
+    fn test_lock_checked_reports_poisoning() {
+        let context = SharedContext::new(1);
+        let poisoner = context.clone();
+        std::thread::spawn(move || {
+            let _guard = poisoner.lock();
+            panic!("poison the context");
+        })
+        .join()
+        .unwrap_err();
+
+        assert!(context.is_poisoned());
+        let mut repaired = context.lock_checked().unwrap_err().into_inner();
+        *repaired = 2;
+        drop(repaired);
+
+        context.clear_poison();
+        assert_eq!(*context.lock_checked().unwrap(), 2);
+    }
+
+    #[test]
+    #[cfg(not(feature = "parking_lot"))]
+    fn test_poison_policy_is_per_context() {
+        let poisoned = |value| {
+            let context = SharedContext::new(value);
+            let poisoner = context.clone();
+            std::thread::spawn(move || {
+                let _guard = poisoner.lock();
+                panic!("poison the context");
+            })
+            .join()
+            .unwrap_err();
+            context
+        };
+
+        let (recovering, propagating) = (poisoned(1), poisoned(2));
+        recovering.clone().set_poison_policy(PoisonPolicy::Recover);
+        assert_eq!(*recovering.lock(), 1);
+        assert!(std::panic::catch_unwind(AssertUnwindSafe(|| *propagating.lock())).is_err());
+    }
 }
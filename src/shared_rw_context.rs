@@ -0,0 +1,274 @@
+use std::{sync::Arc, time::Instant};
+
+use crate::shared_context::ContextLock;
+use crate::sync::{
+    PoisonPolicy, PoisonPolicyExt, RwLock, RwLockReadGuard, RwLockUpgradableReadGuard,
+    RwLockWriteGuard,
+};
+
+/// A thread-safe shared context that wraps a value of type `T` using an `Arc<RwLock<T>>`.
+///
+/// Unlike [`SharedContext`](crate::shared_context::SharedContext), any number of readers can
+/// access the value at the same time, which suits states that are read far more often than
+/// commands change them, e.g. by rendering threads.
+///
+/// # Examples
+///
+/// ```
+/// use command_history::shared_rw_context::SharedRwContext;
+///
+/// let context = SharedRwContext::new(5);
+/// {
+///     let first = context.read();
+///     let second = context.read();
+///     assert_eq!(*first + *second, 10);
+/// }
+/// *context.write() += 1;
+/// assert_eq!(*context.read(), 6);
+/// ```
+///
+/// # Type Parameters
+///
+/// * `T` - The type of the value to be shared.
+///
+/// # Methods
+///
+/// * `new(value: T) -> Self` - Creates a new `SharedRwContext` with the given value. The value is wrapped in an `Arc<RwLock<T>>`.
+/// * `read(&self) -> RwLockReadGuard<'_, T>` - Locks the value for shared reading. Blocks while a writer holds the lock.
+/// * `write(&self) -> RwLockWriteGuard<'_, T>` - Locks the value for exclusive writing. Blocks while any other guard is held.
+/// * `upgradable_read(&self) -> RwLockUpgradableReadGuard<'_, T>` - Locks the value for reading with the option to upgrade to a write lock without letting another writer in between. Without the `parking_lot` feature, the guard holds the write lock from the start and shuts out other readers as well.
+/// * `try_read`, `try_write`, `try_upgradable_read` - Like the above, but return `None` instead of blocking.
+/// * `set_poison_policy(&self, policy)` - Chooses whether locking panics or recovers once the lock is poisoned, for this context only.
+/// * `into_inner(self) -> T` - Consumes the `SharedRwContext` and returns the inner value. Panics if there are multiple references to the `SharedRwContext`.
+/// * `modify<F>(&self, f: F)` - Modifies the value using the given closure. The value is write-locked during the call.
+///
+/// # Traits
+///
+/// * `Clone` - Allows cloning the `SharedRwContext`, which will share the same underlying value.
+/// * `From<Arc<RwLock<T>>>` - Allows creating a `SharedRwContext` from an existing `Arc<RwLock<T>>`.
+/// * `AsRef<Arc<RwLock<T>>>` - Allows getting a reference to the underlying `Arc<RwLock<T>>`.
+/// * `Default` - Allows creating a default `SharedRwContext` with a default value of `T`.
+/// * `Debug` - Allows debugging the `SharedRwContext`. The value is read-locked during the call.
+pub struct SharedRwContext<T> {
+    inner: Arc<RwLock<T>>,
+}
+
+impl<T> SharedRwContext<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(value)),
+        }
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        self.inner.read()
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        self.inner.write()
+    }
+
+    pub fn upgradable_read(&self) -> RwLockUpgradableReadGuard<'_, T> {
+        self.inner.upgradable_read()
+    }
+
+    #[must_use]
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        self.inner.try_read()
+    }
+
+    #[must_use]
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        self.inner.try_write()
+    }
+
+    #[must_use]
+    pub fn try_upgradable_read(&self) -> Option<RwLockUpgradableReadGuard<'_, T>> {
+        self.inner.try_upgradable_read()
+    }
+
+    /// Sets what locking this context does once a thread panicked while holding it. The policy is
+    /// shared by every handle to the value and does not affect other contexts.
+    pub fn set_poison_policy(&self, policy: PoisonPolicy) {
+        self.inner.set_poison_policy(policy);
+    }
+
+    /// Consumes the `SharedRwContext` and returns the inner value.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are multiple references to the `SharedRwContext`.
+    #[must_use]
+    pub fn into_inner(self) -> T {
+        Arc::try_unwrap(self.inner)
+            .ok()
+            .expect("Multiple references to SharedRwContext exist")
+            .into_inner()
+    }
+
+    pub fn modify<F>(&self, f: F)
+    where
+        F: FnOnce(&mut T),
+    {
+        let mut value = self.write();
+        f(&mut *value);
+    }
+}
+
+impl<T> ContextLock for SharedRwContext<T> {
+    fn lockable_by(&self, deadline: Instant) -> bool {
+        self.inner.try_write_until(deadline).is_some()
+    }
+}
+
+impl<T> Clone for SharedRwContext<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<T> Default for SharedRwContext<T>
+where
+    T: Default,
+{
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T> std::fmt::Debug for SharedRwContext<T>
+where
+    T: std::fmt::Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.inner.try_read() {
+            Some(value) => write!(f, "SharedRwContext({:?})", *value),
+            None => write!(f, "SharedRwContext(<locked>)"),
+        }
+    }
+}
+
+impl<T> From<Arc<RwLock<T>>> for SharedRwContext<T> {
+    fn from(arc: Arc<RwLock<T>>) -> Self {
+        Self { inner: arc }
+    }
+}
+
+impl<T> AsRef<Arc<RwLock<T>>> for SharedRwContext<T> {
+    fn as_ref(&self) -> &Arc<RwLock<T>> {
+        &self.inner
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroUsize;
+
+    use super::*;
+    use crate::{
+        checkpoint::Checkpoint,
+        concurrent_command_history::ConcurrentCommandHistory,
+        traits::{command::Command, command_history::CommandHistory},
+    };
+
+    struct Push(i32);
+
+    impl Command for Push {
+        type Context = SharedRwContext<Vec<i32>>;
+
+        fn execute(&self, ctx: &Self::Context) {
+            ctx.write().push(self.0);
+        }
+
+        fn undo(&self, ctx: &Self::Context) {
+            ctx.write().pop();
+        }
+    }
+
+    #[test]
+    fn test_concurrent_readers() {
+        let context = SharedRwContext::new(5);
+        let first = context.read();
+        let second = context.try_read();
+        assert!(second.is_some());
+        assert!(context.try_write().is_none());
+        assert_eq!(*first, 5);
+    }
+
+    #[test]
+    #[cfg(feature = "parking_lot")]
+    fn test_upgradable_read() {
+        let context = SharedRwContext::new(vec![1]);
+        let reader = context.read();
+
+        let upgradable = context.upgradable_read();
+        assert!(context.try_upgradable_read().is_none());
+        assert_eq!(upgradable.len(), reader.len());
+        drop(reader);
+
+        let mut writer = RwLockUpgradableReadGuard::upgrade(upgradable);
+        writer.push(2);
+        drop(writer);
+        assert_eq!(*context.read(), [1, 2]);
+    }
+
+    #[test]
+    #[cfg(not(feature = "parking_lot"))]
+    fn test_upgradable_read_falls_back_to_the_write_lock() {
+        let context = SharedRwContext::new(vec![1]);
+
+        let upgradable = context.upgradable_read();
+        assert!(context.try_read().is_none());
+        assert!(context.try_upgradable_read().is_none());
+        assert_eq!(upgradable.len(), 1);
+
+        let mut writer = RwLockUpgradableReadGuard::upgrade(upgradable);
+        writer.push(2);
+        drop(writer);
+        assert_eq!(*context.read(), [1, 2]);
+    }
+
+    #[test]
+    fn test_ergonomics() {
+        let context: SharedRwContext<i32> = SharedRwContext::default();
+        let cloned = context.clone();
+        cloned.modify(|value| *value += 3);
+        assert_eq!(format!("{context:?}"), "SharedRwContext(3)");
+
+        let guard = context.write();
+        assert_eq!(format!("{cloned:?}"), "SharedRwContext(<locked>)");
+        drop(guard);
+        drop(cloned);
+
+        let arc = Arc::new(RwLock::new(7));
+        let from_arc = SharedRwContext::from(Arc::clone(&arc));
+        assert!(Arc::ptr_eq(from_arc.as_ref(), &arc));
+        assert_eq!(context.into_inner(), 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "Multiple references to SharedRwContext exist")]
+    fn test_into_inner_should_panic() {
+        let context = SharedRwContext::new(100);
+        let _cloned_context = context.clone();
+        let _ = context.into_inner();
+    }
+
+    #[test]
+    fn test_with_concurrent_history() {
+        let history = ConcurrentCommandHistory::new(NonZeroUsize::new(5).unwrap(), true);
+        let ctx = SharedRwContext::new(Vec::new());
+
+        history.execute_command(Push(1), &ctx);
+        let snapshot = ctx.capture();
+        history.execute_command(Push(2), &ctx);
+        history.undo(&ctx);
+        assert_eq!(*ctx.read(), [1]);
+
+        history.redo(&ctx);
+        ctx.restore(&snapshot);
+        assert_eq!(*ctx.read(), [1]);
+    }
+}
@@ -1,14 +1,66 @@
-use std::{collections::VecDeque, num::NonZeroUsize};
+use std::{
+    borrow::Cow, collections::VecDeque, mem, num::NonZeroUsize, path::PathBuf, time::SystemTime,
+};
 
-use crate::traits::{
-    mutable_command::MutableCommand, mutable_command_history::MutableCommandHistory,
+use crate::{
+    checkpoint::{CheckpointConfig, Checkpoints},
+    compaction::{merged_metadata, Compaction, CompactionMode},
+    error::HistoryError,
+    history_entry::{EntryMetadata, EntryPayload, HistoryEntry},
+    invariant::Invariant,
+    panic_policy::{self, CaughtPanic, PanicPolicy, PanicStage},
+    replay::{replay_len, ReplayDivergence},
+    retention::{EntryInfo, RetentionPolicy},
+    scope::{self, Scope},
+    spill::{Spill, SpillStore},
+    traits::{mutable_command::MutableCommand, mutable_command_history::MutableCommandHistory},
 };
 
+/// The entry type stored by [`SimpleCommandHistory`].
+pub type SimpleEntry<C> = HistoryEntry<C, <C as MutableCommand>::Context>;
+
+type Capture<T> = fn(&T) -> T;
+
+/// Executes the child commands of an entry, handed out by
+/// [`SimpleCommandHistory::execute_with_children`].
+pub struct Children<C> {
+    commands: Vec<C>,
+}
+
+impl<C: MutableCommand> Children<C> {
+    /// Executes `command` right away and records it as a child of the entry.
+    pub fn execute(&mut self, command: C, ctx: &mut C::Context) {
+        command.execute(ctx);
+        self.commands.push(command);
+    }
+
+    /// Returns how many children were executed so far.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+}
+
 pub struct SimpleCommandHistory<C: MutableCommand> {
-    undo: VecDeque<C>,
-    redo: VecDeque<C>,
+    undo: VecDeque<SimpleEntry<C>>,
+    redo: VecDeque<SimpleEntry<C>>,
     history_limit: usize,
     clear_redo_on_execute: bool,
+    evicted: usize,
+    checkpoints: Option<Checkpoints<C::Context>>,
+    retention: Option<Box<dyn RetentionPolicy>>,
+    compaction: Option<Compaction>,
+    snapshot_capture: Option<Capture<C::Context>>,
+    spill: Option<SpillStore<C>>,
+    spill_error: Option<HistoryError>,
+    scopes: Vec<Scope<SimpleEntry<C>, C::Context>>,
+    panic_policy: PanicPolicy,
+    invariant: Option<Box<dyn Invariant<C::Context>>>,
 }
 
 impl<C: MutableCommand> SimpleCommandHistory<C> {
@@ -19,19 +71,64 @@ impl<C: MutableCommand> SimpleCommandHistory<C> {
             redo: VecDeque::with_capacity(history_limit),
             history_limit,
             clear_redo_on_execute,
+            evicted: 0,
+            checkpoints: None,
+            retention: None,
+            compaction: None,
+            snapshot_capture: None,
+            spill: None,
+            spill_error: None,
+            scopes: Vec::new(),
+            panic_policy: PanicPolicy::default(),
+            invariant: None,
         }
     }
+
+    /// Returns the undone-able commands, most recent first. Commands merged into compound entries
+    /// are listed individually, commands compacted into snapshots are not listed.
     #[must_use]
     pub fn undo_history(&self) -> Option<Vec<&C>> {
         if self.undo.is_empty() {
             None
         } else {
-            Some(self.undo.iter().collect())
+            Some(
+                self.undo
+                    .iter()
+                    .flat_map(|entry| entry.commands().iter().rev())
+                    .collect(),
+            )
         }
     }
 
+    /// Returns the redo-able commands, next to be redone first. Commands merged into compound
+    /// entries are listed individually, commands compacted into snapshots are not listed.
     #[must_use]
     pub fn redo_history(&self) -> Option<Vec<&C>> {
+        if self.redo.is_empty() {
+            None
+        } else {
+            Some(
+                self.redo
+                    .iter()
+                    .flat_map(|entry| entry.commands().iter())
+                    .collect(),
+            )
+        }
+    }
+
+    /// Returns the undo stack entries, most recent first, including their metadata.
+    #[must_use]
+    pub fn undo_entries(&self) -> Option<Vec<&SimpleEntry<C>>> {
+        if self.undo.is_empty() {
+            None
+        } else {
+            Some(self.undo.iter().collect())
+        }
+    }
+
+    /// Returns the redo stack entries, next to be redone first, including their metadata.
+    #[must_use]
+    pub fn redo_entries(&self) -> Option<Vec<&SimpleEntry<C>>> {
         if self.redo.is_empty() {
             None
         } else {
@@ -39,66 +136,1051 @@ impl<C: MutableCommand> SimpleCommandHistory<C> {
         }
     }
 
-    fn push_undo(&mut self, command: C) {
-        while self.undo.len() >= self.history_limit {
-            self.undo.pop_back();
+    /// Returns the undo entry at `index` (0 is the most recent) for annotating it.
+    pub fn undo_entry_mut(&mut self, index: usize) -> Option<&mut SimpleEntry<C>> {
+        self.undo.get_mut(index)
+    }
+
+    /// Returns the redo entry at `index` (0 is the next to be redone) for annotating it.
+    pub fn redo_entry_mut(&mut self, index: usize) -> Option<&mut SimpleEntry<C>> {
+        self.redo.get_mut(index)
+    }
+
+    /// Executes `command` unless the retention policy refuses it.
+    ///
+    /// [`execute_command`](MutableCommandHistory::execute_command) silently drops refused
+    /// commands, this reports them instead.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`HistoryError::Refused`] if the retention policy does not admit the command. The
+    /// command is not executed in that case.
+    ///
+    /// Returns [`HistoryError::InvariantViolated`] if the command broke the invariant. It is undone
+    /// and not recorded in that case.
+    pub fn try_execute_command(
+        &mut self,
+        command: C,
+        ctx: &mut C::Context,
+    ) -> Result<(), HistoryError> {
+        self.execute_with_metadata(command, ctx, EntryMetadata::default())
+    }
+
+    /// Executes `command` and records it together with `metadata`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`HistoryError::Refused`] if the retention policy does not admit the command. The
+    /// command is not executed in that case.
+    ///
+    /// Returns [`HistoryError::Panicked`] if the command panicked and the [`PanicPolicy`] caught
+    /// it. The command is not recorded in that case.
+    ///
+    /// Returns [`HistoryError::InvariantViolated`] if the command broke the invariant. It is undone
+    /// and not recorded in that case.
+    pub fn execute_with_metadata(
+        &mut self,
+        command: C,
+        ctx: &mut C::Context,
+        metadata: EntryMetadata,
+    ) -> Result<(), HistoryError> {
+        self.execute_entry(command, ctx, metadata, |_, _| {})
+    }
+
+    /// Executes `command` and then `issue`, which may execute child commands through the given
+    /// [`Children`], e.g. follow-up changes that depend on the outcome of `command`.
+    ///
+    /// A command cannot reach the history from inside its own `execute`, so this is how it issues
+    /// sub-commands. The children are recorded in the same entry as `command` and undone and redone
+    /// together with it, the children first when undoing.
+    ///
+    /// # Errors
+    ///
+    /// The same as [`execute_with_metadata`](Self::execute_with_metadata). A panic in `issue` is
+    /// treated like a panic of `command`, and nothing is recorded.
+    pub fn execute_with_children<F>(
+        &mut self,
+        command: C,
+        ctx: &mut C::Context,
+        issue: F,
+    ) -> Result<(), HistoryError>
+    where
+        F: FnOnce(&mut Children<C>, &mut C::Context),
+    {
+        self.execute_entry(command, ctx, EntryMetadata::default(), issue)
+    }
+
+    fn execute_entry<F>(
+        &mut self,
+        command: C,
+        ctx: &mut C::Context,
+        metadata: EntryMetadata,
+        issue: F,
+    ) -> Result<(), HistoryError>
+    where
+        F: FnOnce(&mut Children<C>, &mut C::Context),
+    {
+        if let Some(retention) = self.retention.as_ref().filter(|_| self.scopes.is_empty()) {
+            let candidate = EntryInfo {
+                cost: command.cost(),
+                description: command.description(),
+                executed_at: SystemTime::now(),
+                tags: &metadata.tags,
+            };
+            let entries = Self::entry_infos(&self.undo);
+            let limit = self.history_limit.saturating_sub(self.checkpoint_weight());
+
+            if !retention.admits(&entries, &candidate, limit) {
+                return Err(HistoryError::Refused {
+                    description: candidate.description.into_owned(),
+                });
+            }
+        }
+
+        let mut children = Children {
+            commands: Vec::new(),
+        };
+        let executed = panic_policy::catch(
+            PanicStage::Execute,
+            || command.description().into_owned(),
+            || {
+                command.execute(ctx);
+                issue(&mut children, ctx);
+            },
+        );
+
+        let children = children.commands;
+        if let Err(caught) = executed {
+            command.on_discarded();
+            for child in &children {
+                child.on_discarded();
+            }
+            return Err(caught.settle(self.panic_policy));
+        }
+
+        let cost = command.cost() + children.iter().map(C::cost).sum::<usize>();
+        let entry = if children.is_empty() {
+            HistoryEntry::with_metadata(command, metadata)
+        } else {
+            let mut commands = vec![command];
+            commands.extend(children);
+            HistoryEntry::from_payload(EntryPayload::Compound(commands), metadata)
+        };
+
+        if let Err(error) = self.check_invariant(&entry, ctx) {
+            Self::undo_payload(entry.payload(), ctx, self.snapshot_capture);
+            Self::notify(&entry, C::on_discarded);
+            return Err(error);
+        }
+
+        self.push_undo(entry, Some(ctx));
+
+        if self.clear_redo_on_execute {
+            self.discard_redo();
         }
 
-        self.undo.push_front(command);
+        self.checkpoint_after_execute(cost, ctx);
+        Ok(())
+    }
+
+    /// Sets the policy consulted after every push, in addition to the history limit, and applies
+    /// it right away.
+    pub fn set_retention_policy<P: RetentionPolicy + 'static>(&mut self, policy: P) {
+        self.retention = Some(Box::new(policy));
+        self.enforce_retention();
+    }
+
+    pub fn clear_retention_policy(&mut self) {
+        self.retention = None;
     }
 
-    fn push_redo(&mut self, command: C) {
-        while self.redo.len() >= self.history_limit {
-            self.redo.pop_back();
+    /// Applies the retention policy to both stacks now, e.g. to drop entries that aged out while
+    /// the history was idle.
+    ///
+    /// # Returns
+    ///
+    /// The number of evicted entries, always 0 while a scope is open.
+    pub fn enforce_retention(&mut self) -> usize {
+        let undo_excess = self.retention_excess(&self.undo);
+        for _ in 0..undo_excess {
+            self.evict_oldest_undo();
         }
 
-        self.redo.push_front(command);
+        let redo_excess = self.retention_excess(&self.redo);
+        self.trim_redo(self.redo.len() - redo_excess);
+
+        undo_excess + redo_excess
     }
-}
 
-impl<C: MutableCommand> MutableCommandHistory<C> for SimpleCommandHistory<C> {
-    fn execute_command(&mut self, command: C, ctx: &mut C::Context) {
-        command.execute(ctx);
+    fn entry_info(entry: &SimpleEntry<C>) -> EntryInfo<'_> {
+        let (cost, description) = match entry.command() {
+            Some(command) => (command.cost(), command.description()),
+            None => (
+                entry.commands().iter().map(C::cost).sum::<usize>().max(1),
+                Cow::Owned(entry.group_description()),
+            ),
+        };
+
+        EntryInfo {
+            cost,
+            description,
+            executed_at: entry.executed_at(),
+            tags: entry.tags(),
+        }
+    }
 
-        self.push_undo(command);
+    fn entry_infos(stack: &VecDeque<SimpleEntry<C>>) -> Vec<EntryInfo<'_>> {
+        stack.iter().map(Self::entry_info).collect()
+    }
 
-        if self.clear_redo_on_execute {
-            self.redo.clear();
+    /// Returns how much of a shared budget `entry` takes up: 1, or its cost if `by_cost` is set.
+    fn weight(entry: &SimpleEntry<C>, by_cost: bool) -> usize {
+        if by_cost {
+            Self::entry_info(entry).cost
+        } else {
+            1
         }
     }
 
-    fn undo(&mut self, ctx: &mut C::Context) {
-        if let Some(command) = self.undo.pop_front() {
-            command.undo(ctx);
+    /// Returns how much of a shared budget this history takes up: its number of entries, or their
+    /// summed cost if `by_cost` is set.
+    pub(crate) fn usage(&self, by_cost: bool) -> usize {
+        self.undo
+            .iter()
+            .chain(&self.redo)
+            .map(|entry| Self::weight(entry, by_cost))
+            .sum()
+    }
 
-            self.push_redo(command);
+    /// Drops the oldest undo entry, or the furthest redo entry once the undo stack is empty.
+    ///
+    /// # Returns
+    ///
+    /// How much of a shared budget the dropped entry took up, as counted by
+    /// [`usage`](Self::usage), or `None` if both stacks are empty.
+    pub(crate) fn evict_oldest(&mut self, by_cost: bool) -> Option<usize> {
+        if let Some(entry) = self.undo.back() {
+            let weight = Self::weight(entry, by_cost);
+            self.evict_oldest_undo();
+            Some(weight)
+        } else if let Some(entry) = self.redo.back() {
+            let weight = Self::weight(entry, by_cost);
+            self.trim_redo(self.redo.len() - 1);
+            Some(weight)
+        } else {
+            None
         }
     }
 
-    fn redo(&mut self, ctx: &mut C::Context) {
-        if let Some(command) = self.redo.pop_front() {
-            command.execute(ctx);
-            self.push_undo(command);
+    /// Returns how many of the oldest entries of `stack` the retention policy wants gone, or 0
+    /// while a scope is open.
+    fn retention_excess(&self, stack: &VecDeque<SimpleEntry<C>>) -> usize {
+        let retention = self.retention.as_ref().filter(|_| self.scopes.is_empty());
+        retention.map_or(0, |retention| {
+            retention.excess(&Self::entry_infos(stack)).min(stack.len())
+        })
+    }
+
+    /// Merges the oldest entries into compound entries once the history limit is reached, instead
+    /// of dropping them.
+    ///
+    /// # Arguments
+    ///
+    /// * `batch` - How many of the oldest entries are merged at a time. Values below 2 are raised
+    ///   to 2.
+    pub fn enable_compaction(&mut self, batch: NonZeroUsize) {
+        self.compaction = Some(Compaction::new(CompactionMode::Compound, batch));
+    }
+
+    /// Replaces the oldest entries with snapshots of the context before and after them once the
+    /// history limit is reached, so the oldest state stays reachable with bounded memory.
+    ///
+    /// Building the first snapshot undoes the whole undo stack on a copy of the context, and
+    /// later ones redo the merged commands on a copy, so commands must not have side effects
+    /// outside their context.
+    ///
+    /// [`set_history_limit`](MutableCommandHistory::set_history_limit) has no context to build
+    /// snapshots from, so after lowering the limit the undo stack stays above it until the next
+    /// command is executed or redone.
+    ///
+    /// # Arguments
+    ///
+    /// * `batch` - How many of the oldest entries are merged at a time. Values below 2 are raised
+    ///   to 2.
+    pub fn enable_snapshot_compaction(&mut self, batch: NonZeroUsize)
+    where
+        C::Context: Clone,
+    {
+        self.snapshot_capture = Some(C::Context::clone);
+        self.compaction = Some(Compaction::new(CompactionMode::Snapshot, batch));
+    }
+
+    /// Goes back to dropping the oldest entries. Entries that were already compacted are kept.
+    pub fn disable_compaction(&mut self) {
+        self.compaction = None;
+    }
+
+    /// Opens a child scope, e.g. for the edits made inside a modal dialog.
+    ///
+    /// Until the scope is committed or aborted, every operation on the history works on a fresh
+    /// undo/redo stack of its own, so undo only reaches the edits made inside the scope. Scopes
+    /// can be nested. The scope is unbounded: the history limit, checkpoints, compaction and
+    /// retention policy are suspended until it is closed. Limits and policies set inside the scope
+    /// are not applied to it and are discarded when it closes.
+    pub fn begin_scope(&mut self) {
+        self.scopes.push(Scope {
+            undo: mem::take(&mut self.undo),
+            redo: mem::take(&mut self.redo),
+            history_limit: mem::replace(&mut self.history_limit, usize::MAX),
+            checkpoints: self.checkpoints.take(),
+            retention: self.retention.take(),
+        });
+    }
+
+    /// Returns how many scopes are currently open.
+    #[must_use]
+    pub fn scope_depth(&self) -> usize {
+        self.scopes.len()
+    }
+
+    /// Closes the innermost scope and records everything it did as a single entry on the parent,
+    /// as if it had been executed there. The scope's redo stack is dropped.
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - The context the scope's commands were applied to.
+    ///
+    /// # Returns
+    ///
+    /// `false` if no scope is open.
+    pub fn commit_scope(&mut self, ctx: &mut C::Context) -> bool {
+        let Some(child) = self.close_scope() else {
+            return false;
+        };
+
+        if let Some(entry) = scope::fold(child) {
+            let cost = entry.commands().iter().map(C::cost).sum();
+            self.push_undo(entry, Some(ctx));
+
+            if self.clear_redo_on_execute {
+                self.discard_redo();
+            }
+
+            self.checkpoint_after_execute(cost, ctx);
         }
+
+        true
     }
 
-    fn set_history_limit(&mut self, limit: NonZeroUsize) {
-        self.history_limit = limit.get();
+    /// Closes the innermost scope and reverts everything it did, leaving the parent as it was when
+    /// the scope was opened.
+    ///
+    /// # Returns
+    ///
+    /// `false` if no scope is open.
+    pub fn abort_scope(&mut self, ctx: &mut C::Context) -> bool {
+        let Some(child) = self.close_scope() else {
+            return false;
+        };
+
+        for entry in &child {
+            Self::undo_payload(entry.payload(), ctx, self.snapshot_capture);
+            Self::notify(entry, C::on_discarded);
+        }
+
+        true
+    }
+
+    /// Restores the parent state and returns the undo stack of the closed scope. The redo stack of
+    /// the scope is discarded.
+    fn close_scope(&mut self) -> Option<VecDeque<SimpleEntry<C>>> {
+        let parent = self.scopes.pop()?;
+
+        for entry in mem::replace(&mut self.redo, parent.redo) {
+            Self::notify(&entry, C::on_discarded);
+        }
+        self.history_limit = parent.history_limit;
+        self.checkpoints = parent.checkpoints;
+        self.retention = parent.retention;
+        Some(mem::replace(&mut self.undo, parent.undo))
+    }
+
+    /// Writes entries evicted from memory to the file at `path` instead of dropping them, and
+    /// loads them back when [`try_undo`](Self::try_undo) reaches them.
+    ///
+    /// The history limit and retention policy now decide how many entries stay in memory, and
+    /// compaction is suspended while spilling. The file is created or truncated now and removed
+    /// when spilling is disabled or the history is dropped. Replay, `go_to` and the history
+    /// listings only see the entries held in memory.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`HistoryError::SpillIo`] if the file cannot be created. Any previous spill file is
+    /// kept in that case.
+    pub fn enable_spill(&mut self, path: impl Into<PathBuf>) -> Result<(), HistoryError>
+    where
+        C: Spill,
+    {
+        self.spill = Some(SpillStore::create(path.into())?);
+        Ok(())
+    }
+
+    /// Stops spilling and drops every spilled entry together with the file. The lifecycle hooks
+    /// of spilled commands are not called, since they only exist in the file.
+    pub fn disable_spill(&mut self) {
+        self.spill = None;
+        self.spill_error = None;
+    }
+
+    /// Returns how many entries are currently held in the spill file.
+    #[must_use]
+    pub fn spilled_len(&self) -> usize {
+        self.spill.as_ref().map_or(0, SpillStore::len)
+    }
+
+    /// Returns the error of the last failed write to the spill file, if any.
+    ///
+    /// Writes happen while entries are evicted, which cannot report errors. A failed write drops
+    /// the entry and every entry spilled before it.
+    pub fn take_spill_error(&mut self) -> Option<HistoryError> {
+        self.spill_error.take()
+    }
+
+    /// Undoes the last command, loading it back from the spill file first if the in-memory undo
+    /// stack is empty.
+    ///
+    /// [`undo`](MutableCommandHistory::undo) does the same but ignores spill errors.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`HistoryError::SpillFileMissing`], [`HistoryError::SpillIo`] or
+    /// [`HistoryError::SpillCorrupted`] if the spilled entry cannot be read back. Nothing changes
+    /// in that case, so the call can be retried once the file is restored, or the spilled entries
+    /// given up with [`disable_spill`](Self::disable_spill).
+    ///
+    /// Returns [`HistoryError::Panicked`] if the command panicked and the [`PanicPolicy`] caught
+    /// it.
+    ///
+    /// Returns [`HistoryError::InvariantViolated`] if undoing broke the invariant. The command is
+    /// redone and stays on the undo stack in that case.
+    pub fn try_undo(&mut self, ctx: &mut C::Context) -> Result<(), HistoryError> {
+        if self.undo.is_empty() {
+            if let Some(spill) = self.spill.as_mut() {
+                if let Some(entry) = spill.pop()? {
+                    self.undo.push_front(entry);
+                    self.evicted = self.evicted.saturating_sub(1);
+                }
+            }
+        }
+
+        if let Some(mut entry) = self.undo.pop_front() {
+            let capture = self.snapshot_capture;
+            if let Err(caught) = panic_policy::catch(
+                PanicStage::Undo,
+                || Self::describe(&entry),
+                || Self::undo_payload(entry.payload(), ctx, capture),
+            ) {
+                return Err(self.recover_from_panic(caught, entry));
+            }
+
+            if let Err(error) = self.check_invariant(&entry, ctx) {
+                Self::redo_payload(entry.payload(), ctx, capture);
+                self.undo.push_front(entry);
+                return Err(error);
+            }
+            entry.touch();
+
+            self.push_redo(entry);
+        }
+
+        Ok(())
+    }
+
+    /// Redoes the last undone command.
+    ///
+    /// [`redo`](MutableCommandHistory::redo) does the same but ignores caught panics.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`HistoryError::Panicked`] if the command panicked and the [`PanicPolicy`] caught
+    /// it.
+    ///
+    /// Returns [`HistoryError::InvariantViolated`] if redoing broke the invariant. The command is
+    /// undone again and stays on the redo stack in that case.
+    pub fn try_redo(&mut self, ctx: &mut C::Context) -> Result<(), HistoryError> {
+        if let Some(mut entry) = self.redo.pop_front() {
+            let capture = self.snapshot_capture;
+            if let Err(caught) = panic_policy::catch(
+                PanicStage::Redo,
+                || Self::describe(&entry),
+                || Self::redo_payload(entry.payload(), ctx, capture),
+            ) {
+                return Err(self.recover_from_panic(caught, entry));
+            }
+
+            if let Err(error) = self.check_invariant(&entry, ctx) {
+                Self::undo_payload(entry.payload(), ctx, capture);
+                self.redo.push_front(entry);
+                return Err(error);
+            }
+            entry.touch();
+
+            self.push_undo(entry, Some(ctx));
+        }
+
+        Ok(())
+    }
+
+    /// Checks `invariant` on the context after every execute, undo and redo. A step that breaks it
+    /// is reverted right away and reported as [`HistoryError::InvariantViolated`].
+    ///
+    /// The context is not checked when the invariant is set, and neither are scopes, replays or
+    /// the checkpoints restored by [`go_to`](Self::go_to).
+    pub fn set_invariant<I: Invariant<C::Context> + 'static>(&mut self, invariant: I) {
+        self.invariant = Some(Box::new(invariant));
+    }
+
+    pub fn clear_invariant(&mut self) {
+        self.invariant = None;
+    }
+
+    fn check_invariant(
+        &self,
+        entry: &SimpleEntry<C>,
+        ctx: &C::Context,
+    ) -> Result<(), HistoryError> {
+        let Some(invariant) = &self.invariant else {
+            return Ok(());
+        };
+
+        invariant
+            .check(ctx)
+            .map_err(|violation| HistoryError::InvariantViolated {
+                description: Self::describe(entry),
+                violation,
+            })
+    }
+
+    /// Sets what happens when a command panics while it is executed, undone or redone.
+    pub fn set_panic_policy(&mut self, policy: PanicPolicy) {
+        self.panic_policy = policy;
+    }
+
+    #[must_use]
+    pub fn panic_policy(&self) -> PanicPolicy {
+        self.panic_policy
+    }
+
+    /// Puts `entry` back onto the stack it was taken from, or discards it together with the redo
+    /// entries building on it, and then settles the panic.
+    fn recover_from_panic(&mut self, caught: CaughtPanic, entry: SimpleEntry<C>) -> HistoryError {
+        let undoing = caught.stage() == PanicStage::Undo;
+        match self.panic_policy {
+            PanicPolicy::Propagate | PanicPolicy::Restore if undoing => self.undo.push_front(entry),
+            PanicPolicy::Propagate | PanicPolicy::Restore => self.redo.push_front(entry),
+            PanicPolicy::Discard => {
+                Self::notify(&entry, C::on_discarded);
+                self.discard_redo();
+            }
+        }
+
+        caught.settle(self.panic_policy)
+    }
 
-        while self.undo.len() > self.history_limit {
-            self.undo.pop_back();
+    fn describe(entry: &SimpleEntry<C>) -> String {
+        entry.command().map_or_else(
+            || entry.group_description(),
+            |command| command.description().into_owned(),
+        )
+    }
+
+    /// Re-executes the undo history onto `ctx` in chronological order (oldest first) using `redo`.
+    ///
+    /// This is meant to rebuild a state from a fresh baseline context, e.g. to reproduce a bug
+    /// report. The history itself is left untouched. Compacted snapshot entries are replayed by
+    /// restoring the state after them, as a single step covering all merged commands.
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - The baseline context to replay onto.
+    /// * `until` - Stop after this many commands. `None` replays the whole undo history. A
+    ///   snapshot entry that would cross `until` is not applied, so fewer commands may be
+    ///   replayed.
+    ///
+    /// # Returns
+    ///
+    /// The number of commands that were replayed.
+    pub fn replay(&self, ctx: &mut C::Context, until: Option<usize>) -> usize {
+        self.replay_with(ctx, until, |_, _, _| true)
+            .unwrap_or_default()
+    }
+
+    /// Like [`replay`](Self::replay), but calls `compare` after every replayed command and stops
+    /// at the first step it rejects.
+    ///
+    /// `compare` receives the chronological position of the command, the command itself and the
+    /// context after the command was re-applied, and returns `true` when the state is as expected.
+    /// For a compacted snapshot entry it receives `None` and the position of the last merged
+    /// command, after the state following the entry was restored.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`ReplayDivergence`] describing the first command after which `compare` returned
+    /// `false`. The context is left in the diverged state.
+    pub fn replay_with<F>(
+        &self,
+        ctx: &mut C::Context,
+        until: Option<usize>,
+        mut compare: F,
+    ) -> Result<usize, ReplayDivergence>
+    where
+        F: FnMut(usize, Option<&C>, &C::Context) -> bool,
+    {
+        let total = self.undo.iter().map(HistoryEntry::command_count).sum();
+        let count = replay_len(total, until);
+
+        let mut position = 0;
+        for entry in self.undo.iter().rev() {
+            if position >= count {
+                break;
+            }
+
+            if let EntryPayload::Snapshot { after, merged, .. } = entry.payload() {
+                if position + merged > count {
+                    break;
+                }
+
+                *ctx = Self::restore(self.snapshot_capture, after);
+                position += merged;
+
+                if !compare(position - 1, None, ctx) {
+                    return Err(ReplayDivergence {
+                        position: position - 1,
+                        description: entry.group_description(),
+                    });
+                }
+                continue;
+            }
+
+            for command in entry.commands().iter().take(count - position) {
+                command.redo(ctx);
+
+                if !compare(position, Some(command), ctx) {
+                    return Err(ReplayDivergence {
+                        position,
+                        description: command.description().into_owned(),
+                    });
+                }
+
+                position += 1;
+            }
+        }
+
+        Ok(position)
+    }
+
+    /// Enables periodic snapshots of the context, which lets [`go_to`](Self::go_to) restore the
+    /// nearest snapshot and replay only the remaining commands instead of undoing step by step.
+    ///
+    /// Snapshots count toward the history limit with the configured weight. Any previously held
+    /// snapshots are discarded.
+    pub fn enable_checkpoints(&mut self, config: CheckpointConfig)
+    where
+        C::Context: Clone,
+    {
+        self.checkpoints = Some(Checkpoints::new(config, C::Context::clone));
+    }
+
+    pub fn disable_checkpoints(&mut self) {
+        self.checkpoints = None;
+    }
+
+    #[must_use]
+    pub fn checkpoint_count(&self) -> usize {
+        self.checkpoints.as_ref().map_or(0, Checkpoints::len)
+    }
+
+    /// Returns the current position in the history, i.e. the number of entries that can be undone.
+    #[must_use]
+    pub fn position(&self) -> usize {
+        self.undo.len()
+    }
+
+    /// Moves the history to `position`, where 0 is the oldest state that can still be reached and
+    /// `position() + redo_history().len()` is the newest.
+    ///
+    /// When a checkpoint is closer to `position` than the current state, the context is restored
+    /// from it and only the commands between the checkpoint and `position` are redone.
+    ///
+    /// # Returns
+    ///
+    /// `false` if `position` lies outside the reachable range, in which case nothing changes, or
+    /// if a command panicked and the [`PanicPolicy`] caught it or a step broke the invariant, in
+    /// which case the move stops there.
+    pub fn go_to(&mut self, position: usize, ctx: &mut C::Context) -> bool {
+        let mut current = self.undo.len();
+        if position > current + self.redo.len() {
+            return false;
+        }
+
+        let restored = self.checkpoints.as_ref().and_then(|checkpoints| {
+            checkpoints
+                .nearest(self.evicted, self.evicted + position)
+                .map(|(checkpoint, snapshot)| (checkpoint - self.evicted, snapshot))
+                .filter(|(relative, _)| position - relative < current.abs_diff(position))
+                .map(|(relative, snapshot)| (relative, checkpoints.capture(snapshot)))
+        });
+
+        if let Some((relative, snapshot)) = restored {
+            *ctx = snapshot;
+            self.shift(current, relative);
+            current = relative;
+        }
+
+        let moved = if position < current {
+            (position..current).try_for_each(|_| self.try_undo(ctx))
+        } else {
+            (current..position).try_for_each(|_| self.try_redo(ctx))
+        };
+
+        moved.is_ok()
+    }
+
+    /// Moves entries between the stacks without running them, so `position()` becomes `target`.
+    fn shift(&mut self, current: usize, target: usize) {
+        if target < current {
+            for _ in target..current {
+                if let Some(mut entry) = self.undo.pop_front() {
+                    entry.touch();
+                    self.push_redo(entry);
+                }
+            }
+        } else {
+            for _ in current..target {
+                if let Some(mut entry) = self.redo.pop_front() {
+                    entry.touch();
+                    self.push_undo(entry, None);
+                }
+            }
+        }
+    }
+
+    fn restore(capture: Option<Capture<C::Context>>, snapshot: &C::Context) -> C::Context {
+        let capture =
+            capture.expect("snapshot entries only exist once snapshot compaction is enabled");
+        capture(snapshot)
+    }
+
+    fn undo_payload(
+        payload: &EntryPayload<C, C::Context>,
+        ctx: &mut C::Context,
+        capture: Option<Capture<C::Context>>,
+    ) {
+        match payload {
+            EntryPayload::Command(command) => command.undo(ctx),
+            EntryPayload::Compound(commands) => {
+                for command in commands.iter().rev() {
+                    command.undo(ctx);
+                }
+            }
+            EntryPayload::Snapshot { before, .. } => *ctx = Self::restore(capture, before),
+        }
+    }
+
+    fn redo_payload(
+        payload: &EntryPayload<C, C::Context>,
+        ctx: &mut C::Context,
+        capture: Option<Capture<C::Context>>,
+    ) {
+        match payload {
+            EntryPayload::Command(command) => command.redo(ctx),
+            EntryPayload::Compound(commands) => {
+                for command in commands {
+                    command.redo(ctx);
+                }
+            }
+            EntryPayload::Snapshot { after, .. } => *ctx = Self::restore(capture, after),
+        }
+    }
+
+    fn checkpoint_weight(&self) -> usize {
+        self.checkpoints.as_ref().map_or(0, Checkpoints::weight)
+    }
+
+    fn evict_oldest_undo(&mut self) {
+        if let Some(entry) = self.undo.pop_back() {
+            let spilled = self
+                .spill
+                .as_mut()
+                .is_some_and(|spill| match spill.push(&entry) {
+                    Ok(()) => true,
+                    Err(error) => {
+                        // Everything spilled so far leads up to this entry and is unreachable without it.
+                        spill.clear();
+                        self.spill_error = Some(error);
+                        false
+                    }
+                });
+
+            if !spilled {
+                Self::notify(&entry, C::on_evicted);
+            }
+
+            self.advance_base(1);
+        }
+    }
+
+    /// Records that `count` steps were removed from the oldest end of the undo stack.
+    fn advance_base(&mut self, count: usize) {
+        self.evicted += count;
+
+        if let Some(checkpoints) = self.checkpoints.as_mut() {
+            checkpoints.prune_before(self.evicted);
+        }
+    }
+
+    /// Records that the `count` entries following the `skipped` oldest ones were merged into a
+    /// single entry.
+    fn merge_base(&mut self, skipped: usize, count: usize) {
+        let start = self.evicted + skipped;
+        self.evicted += count - 1;
+
+        if let Some(checkpoints) = self.checkpoints.as_mut() {
+            checkpoints.merge(start, count);
+        }
+    }
+
+    /// Brings the undo stack and the checkpoints within `limit`, compacting the oldest entries
+    /// when compaction is enabled and evicting them otherwise.
+    ///
+    /// `ctx` has to be the state at the top of the undo stack. Snapshot compaction is deferred
+    /// when it is `None`. Nothing is compacted or evicted while a scope is open.
+    fn shrink_undo(&mut self, limit: usize, ctx: Option<&C::Context>) {
+        if !self.scopes.is_empty() {
+            return;
+        }
+
+        while self.undo.len() + self.checkpoint_weight() > limit {
+            if self.undo.len() > 1 {
+                let compacted = match self.compaction.filter(|_| self.spill.is_none()) {
+                    Some(compaction) => match (compaction.mode, ctx) {
+                        (CompactionMode::Compound, _) => self.compact_compound(compaction.batch),
+                        (CompactionMode::Snapshot, Some(ctx)) => {
+                            self.compact_snapshot(compaction.batch, ctx)
+                        }
+                        (CompactionMode::Snapshot, None) => return,
+                    },
+                    None => false,
+                };
+
+                if !compacted {
+                    self.evict_oldest_undo();
+                }
+            } else if !self
+                .checkpoints
+                .as_mut()
+                .is_some_and(Checkpoints::pop_oldest)
+            {
+                break;
+            }
+        }
+    }
+
+    /// Takes the oldest `count` entries off the undo stack, oldest first.
+    fn take_oldest(&mut self, count: usize) -> Vec<SimpleEntry<C>> {
+        let mut entries = Vec::with_capacity(count);
+        for _ in 0..count {
+            entries.extend(self.undo.pop_back());
+        }
+
+        entries
+    }
+
+    fn compact_compound(&mut self, batch: usize) -> bool {
+        let barrier = self
+            .undo
+            .back()
+            .is_some_and(|entry| matches!(entry.payload(), EntryPayload::Snapshot { .. }));
+        let count = batch.min(self.undo.len() - usize::from(barrier));
+        if count < 2 {
+            return false;
+        }
+
+        let snapshot = if barrier { self.undo.pop_back() } else { None };
+        let entries = self.take_oldest(count);
+        let metadata = merged_metadata(&entries);
+        let executed_at = entries[0].executed_at();
+
+        let commands = entries
+            .into_iter()
+            .flat_map(|entry| match entry.into_payload() {
+                EntryPayload::Command(command) => vec![command],
+                EntryPayload::Compound(commands) => commands,
+                EntryPayload::Snapshot { .. } => unreachable!("snapshots are never merged"),
+            })
+            .collect();
+
+        let merged = HistoryEntry::from_payload(EntryPayload::Compound(commands), metadata)
+            .with_executed_at(executed_at);
+        self.undo.push_back(merged);
+        self.undo.extend(snapshot);
+
+        self.merge_base(usize::from(barrier), count);
+        true
+    }
+
+    fn compact_snapshot(&mut self, batch: usize, ctx: &C::Context) -> bool {
+        let capture = self.snapshot_capture;
+        let Some(copy) = capture else {
+            return false;
+        };
+
+        let count = batch.min(self.undo.len());
+        let keep = self.undo.len() - count;
+
+        let mut state = copy(ctx);
+        let mut after = None;
+        for (index, entry) in self.undo.iter().enumerate() {
+            if index == keep {
+                after = Some(copy(&state));
+            }
+
+            Self::undo_payload(entry.payload(), &mut state, capture);
+        }
+
+        let Some(after) = after else {
+            return false;
+        };
+
+        let entries = self.take_oldest(count);
+        let metadata = merged_metadata(&entries);
+        let executed_at = entries[0].executed_at();
+        let merged = entries.iter().map(HistoryEntry::command_count).sum();
+        for entry in &entries {
+            Self::notify(entry, C::on_evicted);
+        }
+
+        let snapshot = EntryPayload::Snapshot {
+            before: state,
+            after,
+            merged,
+        };
+        self.undo.push_back(
+            HistoryEntry::from_payload(snapshot, metadata).with_executed_at(executed_at),
+        );
+
+        self.merge_base(0, count);
+        true
+    }
+
+    /// Evicts the furthest redo entries until at most `limit` are left.
+    fn trim_redo(&mut self, limit: usize) {
+        while self.redo.len() > limit {
+            if let Some(entry) = self.redo.pop_back() {
+                Self::notify(&entry, C::on_evicted);
+            }
+        }
+
+        let end = self.evicted + self.undo.len() + self.redo.len();
+        if let Some(checkpoints) = self.checkpoints.as_mut() {
+            checkpoints.truncate_after(end);
+        }
+    }
+
+    /// Drops every redo entry, since it can no longer be reached from the current state.
+    fn discard_redo(&mut self) {
+        for entry in self.redo.drain(..) {
+            Self::notify(&entry, C::on_discarded);
+        }
+        self.trim_redo(0);
+    }
+
+    /// Calls `hook` on every command held by `entry`.
+    fn notify(entry: &SimpleEntry<C>, hook: fn(&C)) {
+        entry.commands().iter().for_each(hook);
+    }
+
+    fn checkpoint_after_execute(&mut self, cost: usize, ctx: &C::Context) {
+        let position = self.evicted + self.undo.len();
+        if let Some(checkpoints) = self.checkpoints.as_mut() {
+            checkpoints.truncate_after(position - 1);
+
+            if checkpoints.record(cost) {
+                let snapshot = checkpoints.capture(ctx);
+                checkpoints.insert(position, snapshot);
+
+                self.shrink_undo(self.history_limit, Some(ctx));
+            }
+        }
+    }
+
+    /// Pushes `entry` onto the undo stack and enforces the history limit and retention policy.
+    ///
+    /// `ctx` has to be the state after `entry`, or `None` if the context does not match the stack.
+    fn push_undo(&mut self, entry: SimpleEntry<C>, ctx: Option<&C::Context>) {
+        self.undo.push_front(entry);
+        self.shrink_undo(self.history_limit, ctx);
+
+        for _ in 0..self.retention_excess(&self.undo) {
+            self.evict_oldest_undo();
         }
+    }
+
+    fn push_redo(&mut self, entry: SimpleEntry<C>) {
+        self.redo.push_front(entry);
 
-        while self.redo.len() > self.history_limit {
-            self.redo.pop_back();
+        let excess = self.retention_excess(&self.redo);
+        self.trim_redo(self.history_limit.min(self.redo.len() - excess));
+    }
+}
+
+impl<C: MutableCommand> Drop for SimpleCommandHistory<C> {
+    fn drop(&mut self) {
+        let parents = self
+            .scopes
+            .iter()
+            .flat_map(|scope| scope.undo.iter().chain(&scope.redo));
+        for entry in self.undo.iter().chain(&self.redo).chain(parents) {
+            Self::notify(entry, C::on_finalized);
         }
     }
 }
 
+impl<C: MutableCommand> MutableCommandHistory<C> for SimpleCommandHistory<C> {
+    fn execute_command(&mut self, command: C, ctx: &mut C::Context) {
+        let _ = self.try_execute_command(command, ctx);
+    }
+
+    fn undo(&mut self, ctx: &mut C::Context) {
+        let _ = self.try_undo(ctx);
+    }
+
+    fn redo(&mut self, ctx: &mut C::Context) {
+        let _ = self.try_redo(ctx);
+    }
+
+    fn set_history_limit(&mut self, limit: NonZeroUsize) {
+        self.history_limit = limit.get();
+
+        // Snapshot compaction needs a context and is left to the next execute or redo.
+        self.shrink_undo(self.history_limit, None);
+        self.trim_redo(self.history_limit);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::cell::RefCell;
+    use crate::{
+        checkpoint::CheckpointInterval,
+        retention::{MaxAge, MaxCost, MaxCount, RefuseWhenFull},
+    };
+    use std::{
+        cell::{Cell, RefCell},
+        panic::AssertUnwindSafe,
+        rc::Rc,
+        time::Duration,
+    };
 
     struct TestCommand {
         value: i32,
@@ -115,6 +1197,18 @@ mod tests {
         }
     }
 
+    impl Spill for TestCommand {
+        fn encode(&self, out: &mut Vec<u8>) {
+            out.extend_from_slice(&self.value.to_le_bytes());
+        }
+
+        fn decode(bytes: &[u8]) -> Option<Self> {
+            Some(TestCommand {
+                value: i32::from_le_bytes(bytes.try_into().ok()?),
+            })
+        }
+    }
+
     #[test]
     fn test_new() {
         let history = SimpleCommandHistory::<TestCommand>::new(5, true);
@@ -443,4 +1537,891 @@ mod tests {
         let history = SimpleCommandHistory::<TestCommand>::new(5, true);
         assert!(history.redo_history().is_none());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_replay() {
+        let mut history = SimpleCommandHistory::new(5, true);
+        let mut ctx = RefCell::new(0);
+
+        for value in 1..=3 {
+            history.execute_command(TestCommand { value }, &mut ctx);
+        }
+
+        let mut fresh = RefCell::new(0);
+        assert_eq!(history.replay(&mut fresh, None), 3);
+        assert_eq!(*fresh.borrow(), *ctx.borrow());
+
+        let mut partial = RefCell::new(0);
+        assert_eq!(history.replay(&mut partial, Some(2)), 2);
+        assert_eq!(*partial.borrow(), 3);
+        assert_eq!(history.undo.len(), 3);
+    }
+
+    #[test]
+    fn test_replay_with_divergence() {
+        let mut history = SimpleCommandHistory::new(5, true);
+        let mut ctx = RefCell::new(0);
+
+        for value in 1..=3 {
+            history.execute_command(TestCommand { value }, &mut ctx);
+        }
+
+        let expected = [1, 3, 7];
+        let mut fresh = RefCell::new(0);
+        let divergence = history
+            .replay_with(&mut fresh, None, |position, _, ctx| {
+                *ctx.borrow() == expected[position]
+            })
+            .unwrap_err();
+
+        assert_eq!(divergence.position, 2);
+        assert_eq!(divergence.description, "Unknown command");
+        assert_eq!(*fresh.borrow(), 6);
+
+        let mut fresh = RefCell::new(0);
+        let replayed = history.replay_with(&mut fresh, Some(2), |position, _, ctx| {
+            *ctx.borrow() == expected[position]
+        });
+        assert_eq!(replayed, Ok(2));
+    }
+
+    struct CountingCommand {
+        value: i32,
+        calls: Rc<Cell<usize>>,
+    }
+
+    impl MutableCommand for CountingCommand {
+        type Context = i32;
+        fn execute(&self, ctx: &mut Self::Context) {
+            self.calls.set(self.calls.get() + 1);
+            *ctx += self.value;
+        }
+
+        fn undo(&self, ctx: &mut Self::Context) {
+            self.calls.set(self.calls.get() + 1);
+            *ctx -= self.value;
+        }
+
+        fn cost(&self) -> usize {
+            usize::try_from(self.value).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_go_to_without_checkpoints() {
+        let mut history = SimpleCommandHistory::new(10, true);
+        let mut ctx = RefCell::new(0);
+
+        for value in 1..=4 {
+            history.execute_command(TestCommand { value }, &mut ctx);
+        }
+
+        assert!(history.go_to(1, &mut ctx));
+        assert_eq!(*ctx.borrow(), 1);
+        assert_eq!(history.position(), 1);
+        assert_eq!(history.redo.len(), 3);
+
+        assert!(history.go_to(3, &mut ctx));
+        assert_eq!(*ctx.borrow(), 6);
+
+        assert!(!history.go_to(5, &mut ctx));
+        assert_eq!(*ctx.borrow(), 6);
+    }
+
+    #[test]
+    fn test_go_to_restores_nearest_checkpoint() {
+        let calls = Rc::new(Cell::new(0));
+        let mut history = SimpleCommandHistory::new(100, true);
+        history.enable_checkpoints(CheckpointConfig::new(
+            CheckpointInterval::Commands(NonZeroUsize::new(10).unwrap()),
+            0,
+        ));
+        let mut ctx = 0;
+
+        for _ in 0..50 {
+            let command = CountingCommand {
+                value: 1,
+                calls: Rc::clone(&calls),
+            };
+            history.execute_command(command, &mut ctx);
+        }
+        assert_eq!(history.checkpoint_count(), 5);
+
+        calls.set(0);
+        assert!(history.go_to(13, &mut ctx));
+        assert_eq!(ctx, 13);
+        assert_eq!(calls.get(), 3);
+        assert_eq!(history.position(), 13);
+        assert_eq!(history.redo.len(), 37);
+
+        calls.set(0);
+        assert!(history.go_to(42, &mut ctx));
+        assert_eq!(ctx, 42);
+        assert_eq!(calls.get(), 2);
+
+        calls.set(0);
+        assert!(history.go_to(41, &mut ctx));
+        assert_eq!(ctx, 41);
+        assert_eq!(calls.get(), 1);
+
+        history.execute_command(
+            CountingCommand {
+                value: 1,
+                calls: Rc::clone(&calls),
+            },
+            &mut ctx,
+        );
+        assert_eq!(history.checkpoint_count(), 4);
+    }
+
+    #[test]
+    fn test_go_to_after_compaction() {
+        for snapshots in [false, true] {
+            let calls = Rc::new(Cell::new(0));
+            let mut history = SimpleCommandHistory::new(4, true);
+            let batch = NonZeroUsize::new(3).unwrap();
+            if snapshots {
+                history.enable_snapshot_compaction(batch);
+            } else {
+                history.enable_compaction(batch);
+            }
+            history.enable_checkpoints(CheckpointConfig::new(
+                CheckpointInterval::Commands(NonZeroUsize::new(1).unwrap()),
+                0,
+            ));
+            let mut ctx = 0;
+
+            for value in 1..=6 {
+                history.execute_command(counting(value, &calls), &mut ctx);
+            }
+            assert_eq!(history.position(), 4);
+            assert_eq!(history.checkpoint_count(), 4);
+
+            for (position, expected) in [(0, 0), (2, 10), (1, 6), (4, 21), (3, 15)] {
+                assert!(history.go_to(position, &mut ctx));
+                assert_eq!(ctx, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_checkpoints_by_cost_count_toward_limit() {
+        let calls = Rc::new(Cell::new(0));
+        let mut history = SimpleCommandHistory::new(10, true);
+        history.enable_checkpoints(CheckpointConfig::new(
+            CheckpointInterval::Cost(NonZeroUsize::new(5).unwrap()),
+            2,
+        ));
+        let mut ctx = 0;
+
+        for value in [2, 3, 1, 1, 1, 1, 1] {
+            let command = CountingCommand {
+                value,
+                calls: Rc::clone(&calls),
+            };
+            history.execute_command(command, &mut ctx);
+        }
+
+        assert_eq!(history.checkpoint_count(), 2);
+        assert_eq!(history.undo.len() + 2 * history.checkpoint_count(), 10);
+        assert_eq!(history.undo.len(), 6);
+        assert_eq!(history.evicted, 1);
+
+        assert!(history.go_to(1, &mut ctx));
+        assert_eq!(ctx, 5);
+        assert!(history.go_to(0, &mut ctx));
+        assert_eq!(ctx, 2);
+    }
+
+    #[test]
+    fn test_entry_metadata_survives_undo_redo() {
+        let mut history = SimpleCommandHistory::new(5, true);
+        let mut ctx = RefCell::new(0);
+
+        history
+            .execute_with_metadata(
+                TestCommand { value: 2 },
+                &mut ctx,
+                EntryMetadata::new().author("alice").tag("edit"),
+            )
+            .unwrap();
+        history.execute_command(TestCommand { value: 3 }, &mut ctx);
+
+        let entries = history.undo_entries().unwrap();
+        assert_eq!(entries[0].author(), None);
+        assert_eq!(entries[1].author(), Some("alice"));
+        assert!(entries[1].has_tag("edit"));
+        assert!(entries[1].last_moved_at().is_none());
+
+        history
+            .undo_entry_mut(1)
+            .unwrap()
+            .set_note(Some("first edit".to_owned()));
+
+        history.undo(&mut ctx);
+        history.undo(&mut ctx);
+
+        let entry = history.redo_entries().unwrap()[0];
+        assert_eq!(entry.command().unwrap().value, 2);
+        assert_eq!(entry.note(), Some("first edit"));
+        assert!(entry.last_moved_at().unwrap() >= entry.executed_at());
+
+        history.redo(&mut ctx);
+        let entry = history.undo_entries().unwrap()[0];
+        assert_eq!(entry.author(), Some("alice"));
+        assert_eq!(entry.tags(), ["edit"]);
+        assert_eq!(*ctx.borrow(), 2);
+    }
+
+    #[test]
+    fn test_retention_policy_evicts_oldest() {
+        let mut history = SimpleCommandHistory::new(10, true);
+        history.set_retention_policy(MaxCost(5));
+        let mut ctx = 0;
+        let calls = Rc::new(Cell::new(0));
+
+        for value in [1, 2, 3] {
+            let command = CountingCommand {
+                value,
+                calls: Rc::clone(&calls),
+            };
+            history.execute_command(command, &mut ctx);
+        }
+
+        assert_eq!(ctx, 6);
+        assert_eq!(history.undo.len(), 2);
+
+        history.undo(&mut ctx);
+        history.undo(&mut ctx);
+        assert_eq!(ctx, 1);
+        assert_eq!(history.redo.len(), 2);
+
+        history.set_retention_policy(MaxCount(1));
+        assert_eq!(history.redo.len(), 1);
+        assert_eq!(history.redo[0].command().unwrap().value, 2);
+    }
+
+    #[test]
+    fn test_retention_policy_refuses_when_full() {
+        let mut history = SimpleCommandHistory::new(10, true);
+        history.set_retention_policy(RefuseWhenFull(MaxCount(2)));
+        let mut ctx = RefCell::new(0);
+
+        history.execute_command(TestCommand { value: 1 }, &mut ctx);
+        history.execute_command(TestCommand { value: 2 }, &mut ctx);
+
+        let refused = history.try_execute_command(TestCommand { value: 3 }, &mut ctx);
+        assert_eq!(
+            refused,
+            Err(HistoryError::Refused {
+                description: "Unknown command".to_owned()
+            })
+        );
+        assert_eq!(*ctx.borrow(), 3);
+        assert_eq!(history.undo.len(), 2);
+
+        history.undo(&mut ctx);
+        assert!(history
+            .try_execute_command(TestCommand { value: 3 }, &mut ctx)
+            .is_ok());
+        assert_eq!(*ctx.borrow(), 4);
+    }
+
+    #[test]
+    fn test_refuse_when_full_respects_the_history_limit() {
+        let mut history = SimpleCommandHistory::new(2, true);
+        history.set_retention_policy(RefuseWhenFull(MaxCount(10)));
+        let mut ctx = RefCell::new(0);
+
+        history.execute_command(TestCommand { value: 1 }, &mut ctx);
+        history.execute_command(TestCommand { value: 2 }, &mut ctx);
+        assert!(matches!(
+            history.try_execute_command(TestCommand { value: 3 }, &mut ctx),
+            Err(HistoryError::Refused { .. })
+        ));
+        assert_eq!((history.position(), *ctx.get_mut()), (2, 3));
+
+        history.undo(&mut ctx);
+        history.undo(&mut ctx);
+        assert_eq!(*ctx.get_mut(), 0);
+    }
+
+    #[test]
+    fn test_enforce_retention_on_demand() {
+        let mut history = SimpleCommandHistory::new(10, true);
+        let mut ctx = RefCell::new(0);
+
+        for value in 1..=3 {
+            history.execute_command(TestCommand { value }, &mut ctx);
+        }
+
+        history.set_retention_policy(MaxAge(Duration::from_hours(1)));
+        assert_eq!(history.enforce_retention(), 0);
+
+        history.set_retention_policy(MaxAge(Duration::ZERO));
+        assert_eq!(history.undo.len(), 0);
+        assert_eq!(*ctx.borrow(), 6);
+    }
+
+    fn counting(value: i32, calls: &Rc<Cell<usize>>) -> CountingCommand {
+        CountingCommand {
+            value,
+            calls: Rc::clone(calls),
+        }
+    }
+
+    #[test]
+    fn test_compound_compaction() {
+        let calls = Rc::new(Cell::new(0));
+        let mut history = SimpleCommandHistory::new(3, true);
+        history.enable_compaction(NonZeroUsize::new(2).unwrap());
+        let mut ctx = 0;
+
+        for value in 1..=5 {
+            history.execute_command(counting(value, &calls), &mut ctx);
+        }
+
+        assert_eq!(history.undo.len(), 3);
+        assert_eq!(history.undo_history().unwrap().len(), 5);
+        assert_eq!(history.undo[2].command_count(), 3);
+        assert!(history.undo[2].command().is_none());
+
+        for _ in 0..3 {
+            history.undo(&mut ctx);
+        }
+        assert_eq!(ctx, 0);
+        assert_eq!(history.redo_history().unwrap().len(), 5);
+
+        history.redo(&mut ctx);
+        assert_eq!(ctx, 6);
+    }
+
+    #[test]
+    fn test_snapshot_compaction() {
+        let calls = Rc::new(Cell::new(0));
+        let mut history = SimpleCommandHistory::new(3, true);
+        history.enable_snapshot_compaction(NonZeroUsize::new(2).unwrap());
+        let mut ctx = 0;
+
+        for value in 1..=6 {
+            history.execute_command(counting(value, &calls), &mut ctx);
+        }
+
+        assert_eq!(history.undo.len(), 3);
+        assert!(matches!(
+            history.undo[2].payload(),
+            EntryPayload::Snapshot { before: 0, .. }
+        ));
+
+        let mut replayed = 0;
+        assert_eq!(history.replay(&mut replayed, None), 6);
+        assert_eq!(replayed, 21);
+
+        let mut partial = 0;
+        assert_eq!(history.replay(&mut partial, Some(1)), 0);
+        assert_eq!(partial, 0);
+        assert_eq!(history.replay(&mut partial, Some(5)), 5);
+        assert_eq!(partial, 15);
+
+        let mut steps = Vec::new();
+        let divergence = history
+            .replay_with(&mut 0, None, |position, command, ctx| {
+                steps.push((position, command.is_some(), *ctx));
+                *ctx < 15
+            })
+            .unwrap_err();
+        assert_eq!(steps, [(3, false, 10), (4, true, 15)]);
+        assert_eq!(divergence.position, 4);
+
+        for _ in 0..3 {
+            history.undo(&mut ctx);
+        }
+        assert_eq!(ctx, 0);
+
+        calls.set(0);
+        for _ in 0..3 {
+            history.redo(&mut ctx);
+        }
+        assert_eq!(ctx, 21);
+        assert!(calls.get() < 6);
+    }
+
+    #[test]
+    fn test_lowered_limit_is_compacted_on_next_execute() {
+        let calls = Rc::new(Cell::new(0));
+        let mut history = SimpleCommandHistory::new(4, true);
+        history.enable_snapshot_compaction(NonZeroUsize::new(2).unwrap());
+        let mut ctx = 0;
+
+        for value in 1..=4 {
+            history.execute_command(counting(value, &calls), &mut ctx);
+        }
+        history.set_history_limit(NonZeroUsize::new(2).unwrap());
+        assert_eq!(history.undo.len(), 4);
+
+        history.execute_command(counting(5, &calls), &mut ctx);
+        assert!(history.undo.len() <= 2);
+        assert!(matches!(
+            history.undo.back().unwrap().payload(),
+            EntryPayload::Snapshot { before: 0, .. }
+        ));
+
+        while history.undo_history().is_some() {
+            history.undo(&mut ctx);
+        }
+        assert_eq!(ctx, 0);
+    }
+
+    fn spill_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "command_history_simple_{name}_{}.spill",
+            std::process::id()
+        ))
+    }
+
+    #[test]
+    fn test_spill_and_load_back() {
+        let mut history = SimpleCommandHistory::new(2, true);
+        history.enable_spill(spill_path("load_back")).unwrap();
+        let mut ctx = RefCell::new(0);
+
+        for value in 1..=5 {
+            history
+                .execute_with_metadata(
+                    TestCommand { value },
+                    &mut ctx,
+                    EntryMetadata::new().tag(format!("step {value}")),
+                )
+                .unwrap();
+        }
+
+        assert_eq!(history.undo.len(), 2);
+        assert_eq!(history.spilled_len(), 3);
+
+        for _ in 0..5 {
+            history.try_undo(&mut ctx).unwrap();
+        }
+        assert_eq!(*ctx.borrow(), 0);
+        assert_eq!(history.spilled_len(), 0);
+        assert!(history.redo[0].has_tag("step 1"));
+
+        history.redo(&mut ctx);
+        assert_eq!(*ctx.borrow(), 1);
+        assert!(history.take_spill_error().is_none());
+    }
+
+    #[test]
+    fn test_spill_errors_are_recoverable() {
+        let path = spill_path("errors");
+        let mut history = SimpleCommandHistory::new(1, true);
+        history.enable_spill(&path).unwrap();
+        let mut ctx = RefCell::new(0);
+
+        for value in 1..=3 {
+            history.execute_command(TestCommand { value }, &mut ctx);
+        }
+        history.undo(&mut ctx);
+        assert_eq!(*ctx.borrow(), 3);
+
+        let intact = std::fs::read(&path).unwrap();
+        let mut damaged = intact.clone();
+        *damaged.last_mut().unwrap() ^= 0xff;
+        std::fs::write(&path, &damaged).unwrap();
+        assert!(matches!(
+            history.try_undo(&mut ctx),
+            Err(HistoryError::SpillCorrupted { .. })
+        ));
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            history.try_undo(&mut ctx),
+            Err(HistoryError::SpillFileMissing { .. })
+        ));
+        assert_eq!(*ctx.borrow(), 3);
+
+        std::fs::write(&path, &intact).unwrap();
+        history.try_undo(&mut ctx).unwrap();
+        assert_eq!(*ctx.borrow(), 1);
+        assert_eq!(history.spilled_len(), 1);
+
+        history.disable_spill();
+        assert!(!path.exists());
+        history.try_undo(&mut ctx).unwrap();
+        assert_eq!(*ctx.borrow(), 1);
+    }
+
+    #[test]
+    fn test_scope_commit_and_abort() {
+        let mut history = SimpleCommandHistory::new(2, true);
+        let mut ctx = RefCell::new(0);
+        history.execute_command(TestCommand { value: 1 }, &mut ctx);
+
+        history.begin_scope();
+        for value in [10, 20, 30] {
+            history.execute_command(TestCommand { value }, &mut ctx);
+        }
+        history.undo(&mut ctx);
+        assert_eq!(*ctx.borrow(), 31);
+        assert_eq!(history.undo_history().unwrap().len(), 2);
+
+        history.begin_scope();
+        history.execute_command(TestCommand { value: 100 }, &mut ctx);
+        assert!(history.abort_scope(&mut ctx));
+        assert_eq!(*ctx.borrow(), 31);
+        assert_eq!(history.scope_depth(), 1);
+
+        assert!(history.commit_scope(&mut ctx));
+        assert!(!history.commit_scope(&mut ctx));
+        assert_eq!(history.position(), 2);
+
+        history.undo(&mut ctx);
+        assert_eq!(*ctx.borrow(), 1);
+        history.redo(&mut ctx);
+        assert_eq!(*ctx.borrow(), 31);
+    }
+
+    #[test]
+    fn test_scopes_suspend_compaction() {
+        let calls = Rc::new(Cell::new(0));
+        let mut history = SimpleCommandHistory::new(10, true);
+        history.enable_snapshot_compaction(NonZeroUsize::new(2).unwrap());
+        let mut ctx = 0;
+
+        history.begin_scope();
+        history.set_history_limit(NonZeroUsize::new(2).unwrap());
+        history.set_retention_policy(MaxCount(1));
+        for value in 1..=5 {
+            history.execute_command(counting(value, &calls), &mut ctx);
+        }
+        assert_eq!(history.position(), 5);
+        assert!(history.commit_scope(&mut ctx));
+
+        assert_eq!((history.position(), ctx), (1, 15));
+        history.undo(&mut ctx);
+        assert_eq!(ctx, 0);
+    }
+
+    struct Fragile {
+        value: i32,
+        panics_on: Option<PanicStage>,
+    }
+
+    impl Fragile {
+        fn check(&self, stage: PanicStage) {
+            assert!(self.panics_on != Some(stage), "{stage} failed");
+        }
+    }
+
+    impl MutableCommand for Fragile {
+        type Context = Vec<i32>;
+
+        fn execute(&self, ctx: &mut Self::Context) {
+            self.check(PanicStage::Execute);
+            ctx.push(self.value);
+        }
+
+        fn undo(&self, ctx: &mut Self::Context) {
+            self.check(PanicStage::Undo);
+            ctx.pop();
+        }
+
+        fn redo(&self, ctx: &mut Self::Context) {
+            self.check(PanicStage::Redo);
+            ctx.push(self.value);
+        }
+    }
+
+    fn fragile_history(
+        policy: PanicPolicy,
+        panics_on: PanicStage,
+    ) -> (SimpleCommandHistory<Fragile>, Vec<i32>) {
+        let mut history = SimpleCommandHistory::new(10, true);
+        history.set_panic_policy(policy);
+        let mut ctx = Vec::new();
+
+        history.execute_command(
+            Fragile {
+                value: 1,
+                panics_on: None,
+            },
+            &mut ctx,
+        );
+        if panics_on != PanicStage::Execute {
+            history.execute_command(
+                Fragile {
+                    value: 2,
+                    panics_on: Some(panics_on),
+                },
+                &mut ctx,
+            );
+        }
+        if panics_on == PanicStage::Redo {
+            history.undo(&mut ctx);
+        }
+
+        (history, ctx)
+    }
+
+    #[test]
+    fn test_panic_while_executing() {
+        for policy in [
+            PanicPolicy::Propagate,
+            PanicPolicy::Restore,
+            PanicPolicy::Discard,
+        ] {
+            let (mut history, mut ctx) = fragile_history(policy, PanicStage::Execute);
+            let command = Fragile {
+                value: 2,
+                panics_on: Some(PanicStage::Execute),
+            };
+
+            let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                history.try_execute_command(command, &mut ctx)
+            }));
+            match policy {
+                PanicPolicy::Propagate => assert!(result.is_err()),
+                _ => assert!(matches!(
+                    result.unwrap(),
+                    Err(HistoryError::Panicked {
+                        stage: PanicStage::Execute,
+                        ..
+                    })
+                )),
+            }
+
+            assert_eq!(history.position(), 1);
+            history.undo(&mut ctx);
+            assert!(ctx.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_panic_while_undoing() {
+        for policy in [
+            PanicPolicy::Propagate,
+            PanicPolicy::Restore,
+            PanicPolicy::Discard,
+        ] {
+            let (mut history, mut ctx) = fragile_history(policy, PanicStage::Undo);
+
+            let result = std::panic::catch_unwind(AssertUnwindSafe(|| history.try_undo(&mut ctx)));
+            match policy {
+                PanicPolicy::Propagate => assert!(result.is_err()),
+                _ => assert_eq!(
+                    result.unwrap(),
+                    Err(HistoryError::Panicked {
+                        stage: PanicStage::Undo,
+                        description: String::from("Unknown command"),
+                        message: String::from("undo failed"),
+                    })
+                ),
+            }
+
+            let expected = if policy == PanicPolicy::Discard { 1 } else { 2 };
+            assert_eq!(history.position(), expected);
+            assert!(history.redo_history().is_none());
+            assert_eq!(ctx, [1, 2]);
+        }
+    }
+
+    #[test]
+    fn test_panic_while_redoing() {
+        for policy in [
+            PanicPolicy::Propagate,
+            PanicPolicy::Restore,
+            PanicPolicy::Discard,
+        ] {
+            let (mut history, mut ctx) = fragile_history(policy, PanicStage::Redo);
+
+            let result = std::panic::catch_unwind(AssertUnwindSafe(|| history.try_redo(&mut ctx)));
+            assert_eq!(result.is_err(), policy == PanicPolicy::Propagate);
+
+            let expected = usize::from(policy != PanicPolicy::Discard);
+            assert_eq!(
+                history.redo_history().map_or(0, |redo| redo.len()),
+                expected
+            );
+            assert_eq!(history.position(), 1);
+            if policy == PanicPolicy::Restore {
+                assert!(!history.go_to(2, &mut ctx));
+            }
+            assert_eq!(ctx, [1]);
+        }
+    }
+
+    #[test]
+    fn test_children_are_undone_with_their_parent() {
+        let mut history = SimpleCommandHistory::new(5, true);
+        let mut ctx = RefCell::new(0);
+
+        history
+            .execute_with_children(TestCommand { value: 1 }, &mut ctx, |children, ctx| {
+                children.execute(TestCommand { value: 10 }, ctx);
+                children.execute(TestCommand { value: 100 }, ctx);
+                assert_eq!(children.len(), 2);
+            })
+            .unwrap();
+        assert_eq!(*ctx.borrow(), 111);
+        assert_eq!(history.position(), 1);
+        assert_eq!(history.undo_history().unwrap().len(), 3);
+
+        history.undo(&mut ctx);
+        assert_eq!(*ctx.borrow(), 0);
+        history.redo(&mut ctx);
+        assert_eq!(*ctx.borrow(), 111);
+    }
+
+    /// Logs which lifecycle hooks were called on it.
+    struct Tracked {
+        value: i32,
+        log: Rc<RefCell<Vec<(i32, &'static str)>>>,
+    }
+
+    impl MutableCommand for Tracked {
+        type Context = Vec<i32>;
+
+        fn execute(&self, ctx: &mut Self::Context) {
+            assert!(self.value >= 0, "negative value");
+            ctx.push(self.value);
+        }
+
+        fn undo(&self, ctx: &mut Self::Context) {
+            ctx.pop();
+        }
+
+        fn on_evicted(&self) {
+            self.log.borrow_mut().push((self.value, "evicted"));
+        }
+
+        fn on_discarded(&self) {
+            self.log.borrow_mut().push((self.value, "discarded"));
+        }
+
+        fn on_finalized(&self) {
+            self.log.borrow_mut().push((self.value, "finalized"));
+        }
+    }
+
+    #[test]
+    fn test_lifecycle_hooks() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let tracked = |value| Tracked {
+            value,
+            log: Rc::clone(&log),
+        };
+        let mut history = SimpleCommandHistory::new(2, true);
+        let mut ctx = Vec::new();
+
+        for value in 1..=3 {
+            history.execute_command(tracked(value), &mut ctx);
+        }
+        assert_eq!(*log.borrow(), [(1, "evicted")]);
+
+        history.undo(&mut ctx);
+        history.execute_command(tracked(4), &mut ctx);
+        assert_eq!(log.borrow()[1..], [(3, "discarded")]);
+
+        history.begin_scope();
+        history.execute_command(tracked(5), &mut ctx);
+        history.abort_scope(&mut ctx);
+        assert_eq!(log.borrow()[2..], [(5, "discarded")]);
+
+        history.set_history_limit(NonZeroUsize::new(1).unwrap());
+        drop(history);
+        assert_eq!(log.borrow()[3..], [(2, "evicted"), (4, "finalized")]);
+        assert_eq!(ctx, [1, 2, 4]);
+    }
+
+    #[test]
+    fn test_discarded_hook_when_execute_panics() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let tracked = |value| Tracked {
+            value,
+            log: Rc::clone(&log),
+        };
+        let mut history = SimpleCommandHistory::new(10, true);
+        history.set_panic_policy(PanicPolicy::Discard);
+        let mut ctx = Vec::new();
+
+        let result = history.execute_with_children(tracked(1), &mut ctx, |children, ctx| {
+            children.execute(tracked(2), ctx);
+            children.execute(tracked(-3), ctx);
+        });
+        assert!(matches!(result, Err(HistoryError::Panicked { .. })));
+        assert_eq!(*log.borrow(), [(1, "discarded"), (2, "discarded")]);
+
+        assert!(history.try_execute_command(tracked(-4), &mut ctx).is_err());
+        assert_eq!(log.borrow()[2..], [(-4, "discarded")]);
+        assert_eq!(history.position(), 0);
+    }
+
+    #[test]
+    fn test_discarded_hook_when_execute_breaks_the_invariant() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut history = SimpleCommandHistory::new(10, true);
+        history.set_invariant(|ctx: &Vec<i32>| {
+            if ctx.iter().sum::<i32>() > 5 {
+                Err(String::from("sum above 5"))
+            } else {
+                Ok(())
+            }
+        });
+        let mut ctx = Vec::new();
+
+        let result = history.execute_with_children(
+            Tracked {
+                value: 4,
+                log: Rc::clone(&log),
+            },
+            &mut ctx,
+            |children, ctx| {
+                let child = Tracked {
+                    value: 3,
+                    log: Rc::clone(&log),
+                };
+                children.execute(child, ctx);
+            },
+        );
+        assert!(matches!(
+            result,
+            Err(HistoryError::InvariantViolated { .. })
+        ));
+        assert_eq!(*log.borrow(), [(4, "discarded"), (3, "discarded")]);
+        assert!(ctx.is_empty());
+    }
+
+    #[test]
+    fn test_invariant_reverts_the_breaking_step() {
+        let mut history = SimpleCommandHistory::new(10, true);
+        let mut ctx = RefCell::new(0);
+        history.set_invariant(|ctx: &RefCell<i32>| match *ctx.borrow() {
+            balance if balance < 0 => Err(format!("balance is {balance}")),
+            _ => Ok(()),
+        });
+        let violated = |violation: &str| {
+            Err(HistoryError::InvariantViolated {
+                description: String::from("Unknown command"),
+                violation: violation.to_owned(),
+            })
+        };
+
+        history.execute_command(TestCommand { value: 5 }, &mut ctx);
+        assert_eq!(
+            history.try_execute_command(TestCommand { value: -10 }, &mut ctx),
+            violated("balance is -5")
+        );
+        assert_eq!((history.position(), *ctx.get_mut()), (1, 5));
+
+        history.execute_command(TestCommand { value: -4 }, &mut ctx);
+        history.undo(&mut ctx);
+        *ctx.get_mut() = 3;
+        assert_eq!(history.try_redo(&mut ctx), violated("balance is -1"));
+        assert_eq!(history.try_undo(&mut ctx), violated("balance is -2"));
+        assert_eq!((history.position(), history.redo.len()), (1, 1));
+        assert_eq!(*ctx.get_mut(), 3);
+
+        history.clear_invariant();
+        history.undo(&mut ctx);
+        assert_eq!(*ctx.get_mut(), -2);
+    }
+}
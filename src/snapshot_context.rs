@@ -0,0 +1,283 @@
+use std::{
+    fmt,
+    ops::{Deref, DerefMut},
+    sync::Arc,
+    time::Instant,
+};
+
+use crate::{
+    shared_context::ContextLock,
+    sync::{Mutex, MutexGuard, RwLock},
+};
+
+/// A thread-safe shared context with copy-on-write updates, in the style of read-copy-update.
+///
+/// Readers get an immutable `Arc<T>` snapshot and can keep it for as long as they like, e.g. for
+/// a whole render pass, without blocking anyone. Writers work on a private copy and publish it
+/// atomically when done, so a reader never sees a half-applied change. Writers are serialized
+/// with each other.
+///
+/// Commands for [`ConcurrentCommandHistory`](crate::concurrent_command_history::ConcurrentCommandHistory)
+/// use [`write`](Self::write) or [`update`](Self::update), so every executed, undone or redone
+/// command becomes visible to readers as one new snapshot. Entries holding several commands, such
+/// as committed scopes and entries merged by compound compaction, publish one snapshot per
+/// command, so readers can see them half applied.
+///
+/// # Examples
+///
+/// ```
+/// use command_history::snapshot_context::SnapshotContext;
+///
+/// let context = SnapshotContext::new(vec![1, 2]);
+/// let before = context.load();
+///
+/// context.update(|value| value.push(3));
+///
+/// assert_eq!(*before, [1, 2]);
+/// assert_eq!(*context.load(), [1, 2, 3]);
+/// ```
+///
+/// # Type Parameters
+///
+/// * `T` - The type of the value to be shared. Writers need `T: Clone`.
+pub struct SnapshotContext<T> {
+    inner: Arc<Inner<T>>,
+}
+
+struct Inner<T> {
+    current: RwLock<Arc<T>>,
+    writer: Mutex<()>,
+}
+
+/// A private copy of the value handed out by [`SnapshotContext::write`].
+///
+/// The copy is published when the guard is released, if it was mutably dereferenced. Other
+/// writers wait until then.
+pub struct SnapshotWriteGuard<'a, T> {
+    context: &'a SnapshotContext<T>,
+    value: Option<T>,
+    changed: bool,
+    _writer: MutexGuard<'a, ()>,
+}
+
+impl<T> SnapshotContext<T> {
+    pub fn new(value: T) -> Self {
+        Self::from(Arc::new(value))
+    }
+
+    /// Returns the latest published snapshot.
+    #[must_use]
+    pub fn load(&self) -> Arc<T> {
+        Arc::clone(&self.inner.current.read())
+    }
+
+    /// Locks out other writers and returns a private copy of the latest snapshot to modify.
+    #[must_use]
+    pub fn write(&self) -> SnapshotWriteGuard<'_, T>
+    where
+        T: Clone,
+    {
+        let writer = self.inner.writer.lock();
+
+        SnapshotWriteGuard {
+            context: self,
+            value: Some(T::clone(&self.load())),
+            changed: false,
+            _writer: writer,
+        }
+    }
+
+    /// Modifies a copy of the value with `f` and publishes it.
+    pub fn update<F>(&self, f: F)
+    where
+        T: Clone,
+        F: FnOnce(&mut T),
+    {
+        let mut value = self.write();
+        f(&mut *value);
+    }
+
+    /// Publishes `value` as the new snapshot and returns the previous one.
+    pub fn replace(&self, value: impl Into<Arc<T>>) -> Arc<T> {
+        let _writer = self.inner.writer.lock();
+        self.publish(value.into())
+    }
+
+    /// Returns whether both contexts share the same underlying value.
+    #[must_use]
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+    }
+
+    fn publish(&self, value: Arc<T>) -> Arc<T> {
+        std::mem::replace(&mut *self.inner.current.write(), value)
+    }
+}
+
+impl<T> Deref for SnapshotWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+            .as_ref()
+            .expect("the copy is only taken when dropped")
+    }
+}
+
+impl<T> DerefMut for SnapshotWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.changed = true;
+        self.value
+            .as_mut()
+            .expect("the copy is only taken when dropped")
+    }
+}
+
+impl<T> Drop for SnapshotWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        if let Some(value) = self.value.take().filter(|_| self.changed) {
+            self.context.publish(Arc::new(value));
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for SnapshotWriteGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+/// Only writers are waited for, since readers never block.
+impl<T> ContextLock for SnapshotContext<T> {
+    fn lockable_by(&self, deadline: Instant) -> bool {
+        self.inner.writer.try_lock_until(deadline).is_some()
+    }
+}
+
+impl<T> Clone for SnapshotContext<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<T> Default for SnapshotContext<T>
+where
+    T: Default,
+{
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T> fmt::Debug for SnapshotContext<T>
+where
+    T: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SnapshotContext({:?})", *self.load())
+    }
+}
+
+impl<T> From<Arc<T>> for SnapshotContext<T> {
+    fn from(value: Arc<T>) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                current: RwLock::new(value),
+                writer: Mutex::new(()),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{num::NonZeroUsize, thread};
+
+    use super::*;
+    use crate::{
+        checkpoint::{CheckpointConfig, CheckpointInterval},
+        concurrent_command_history::ConcurrentCommandHistory,
+        traits::{command::Command, command_history::CommandHistory},
+    };
+
+    struct Append(u32);
+
+    impl Command for Append {
+        type Context = SnapshotContext<Vec<u32>>;
+
+        fn execute(&self, ctx: &Self::Context) {
+            ctx.update(|value| value.push(self.0));
+        }
+
+        fn undo(&self, ctx: &Self::Context) {
+            ctx.update(|value| {
+                value.pop();
+            });
+        }
+    }
+
+    #[test]
+    fn test_readers_keep_their_snapshot() {
+        let context = SnapshotContext::new(1);
+        let before = context.load();
+
+        let mut writer = context.write();
+        *writer += 1;
+        assert_eq!(*context.load(), 1);
+        drop(writer);
+
+        assert_eq!(*before, 1);
+        assert_eq!(*context.load(), 2);
+
+        let unchanged = context.load();
+        drop(context.write());
+        assert!(Arc::ptr_eq(&unchanged, &context.load()));
+        assert_eq!(*context.replace(5), 2);
+    }
+
+    #[test]
+    fn test_concurrent_writers_do_not_lose_updates() {
+        let context = SnapshotContext::new(0);
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let context = context.clone();
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        context.update(|value| *value += 1);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(*context.load(), 400);
+        assert_eq!(format!("{context:?}"), "SnapshotContext(400)");
+    }
+
+    #[test]
+    fn test_with_concurrent_history() {
+        let history = ConcurrentCommandHistory::new(NonZeroUsize::new(10).unwrap(), true);
+        history.enable_checkpoints(CheckpointConfig::new(
+            CheckpointInterval::Commands(NonZeroUsize::new(2).unwrap()),
+            1,
+        ));
+        let context = SnapshotContext::default();
+
+        for value in 0..4 {
+            history.execute_command(Append(value), &context);
+        }
+        let published = context.load();
+
+        history.undo(&context);
+        assert_eq!(*published, [0, 1, 2, 3]);
+        assert_eq!(*context.load(), [0, 1, 2]);
+
+        assert!(history.go_to(2, &context));
+        assert_eq!(*context.load(), [0, 1]);
+    }
+}
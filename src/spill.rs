@@ -0,0 +1,356 @@
+use std::{
+    borrow::Borrow,
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    marker::PhantomData,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    error::HistoryError,
+    history_entry::{EntryMetadata, EntryPayload, HistoryEntry},
+};
+
+/// A command that can be written to a history's spill file and read back.
+///
+/// The library frames, checksums and stores the bytes, implementations only need to turn a
+/// single command into bytes and back.
+///
+/// # Required Methods
+///
+/// * `encode(&self, out)`: Appends the serialized command to `out`.
+/// * `decode(bytes)`: Rebuilds a command from the bytes written by `encode`, or returns `None` if
+///   they are not a valid command.
+pub trait Spill: Sized {
+    fn encode(&self, out: &mut Vec<u8>);
+
+    fn decode(bytes: &[u8]) -> Option<Self>;
+}
+
+const PAYLOAD_COMMAND: u8 = 0;
+const PAYLOAD_COMPOUND: u8 = 1;
+
+/// The cold tier of a history: entries evicted from memory, appended to a file and popped back
+/// from its end, newest first.
+///
+/// Each record is a little-endian `u32` length, a `u32` checksum of the body and the body itself.
+/// The file is removed when the store is dropped.
+pub(crate) struct SpillStore<C> {
+    path: PathBuf,
+    offsets: Vec<u64>,
+    end: u64,
+    encode: fn(&C, &mut Vec<u8>),
+    decode: fn(&[u8]) -> Option<C>,
+    _command: PhantomData<fn() -> C>,
+}
+
+impl<C> SpillStore<C> {
+    pub(crate) fn create(path: PathBuf) -> Result<Self, HistoryError>
+    where
+        C: Spill,
+    {
+        File::create(&path).map_err(|error| HistoryError::spill_io(&path, &error))?;
+
+        Ok(Self {
+            path,
+            offsets: Vec::new(),
+            end: 0,
+            encode: C::encode,
+            decode: C::decode,
+            _command: PhantomData,
+        })
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    /// Appends `entry` to the file. Snapshot entries hold no commands and cannot be spilled.
+    pub(crate) fn push<E, S>(&mut self, entry: &HistoryEntry<E, S>) -> Result<(), HistoryError>
+    where
+        E: Borrow<C>,
+    {
+        let kind = match entry.payload() {
+            EntryPayload::Command(_) => PAYLOAD_COMMAND,
+            EntryPayload::Compound(_) => PAYLOAD_COMPOUND,
+            EntryPayload::Snapshot { .. } => {
+                return Err(HistoryError::SpillIo {
+                    path: self.path.clone(),
+                    message: "snapshot entries cannot be spilled".to_owned(),
+                })
+            }
+        };
+
+        let record = self
+            .record(entry, kind)
+            .ok_or_else(|| HistoryError::SpillIo {
+                path: self.path.clone(),
+                message: "records are limited to 4 GiB".to_owned(),
+            })?;
+
+        let io_error = |error: std::io::Error| HistoryError::spill_io(&self.path, &error);
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(&self.path)
+            .map_err(io_error)?;
+        if file.metadata().map_err(io_error)?.len() != self.end {
+            return Err(self.corrupted("file length changed outside of the history"));
+        }
+
+        file.write_all(&record).map_err(io_error)?;
+        self.offsets.push(self.end);
+        self.end += record.len() as u64;
+        Ok(())
+    }
+
+    /// Serializes `entry`, or returns `None` if a length does not fit the `u32` framing.
+    fn record<E, S>(&self, entry: &HistoryEntry<E, S>, kind: u8) -> Option<Vec<u8>>
+    where
+        E: Borrow<C>,
+    {
+        let mut body = Vec::new();
+        write_time(&mut body, entry.executed_at());
+        write_optional(&mut body, entry.author())?;
+        write_len(&mut body, entry.tags().len())?;
+        for tag in entry.tags() {
+            write_bytes(&mut body, tag.as_bytes())?;
+        }
+        write_optional(&mut body, entry.note())?;
+
+        body.push(kind);
+        write_len(&mut body, entry.commands().len())?;
+        let mut encoded = Vec::new();
+        for command in entry.commands() {
+            encoded.clear();
+            (self.encode)(command.borrow(), &mut encoded);
+            write_bytes(&mut body, &encoded)?;
+        }
+
+        let mut record = Vec::with_capacity(body.len() + 8);
+        write_len(&mut record, body.len())?;
+        record.extend_from_slice(&checksum(&body).to_le_bytes());
+        record.extend_from_slice(&body);
+        Some(record)
+    }
+
+    /// Reads back the most recently spilled entry and removes it from the file.
+    ///
+    /// On error the entry stays in the file, so the call can be retried once the file is back.
+    pub(crate) fn pop<E, S>(&mut self) -> Result<Option<HistoryEntry<E, S>>, HistoryError>
+    where
+        E: From<C>,
+    {
+        let Some(&offset) = self.offsets.last() else {
+            return Ok(None);
+        };
+
+        let io_error = |error: std::io::Error| HistoryError::spill_io(&self.path, &error);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&self.path)
+            .map_err(io_error)?;
+        if file.metadata().map_err(io_error)?.len() != self.end {
+            return Err(self.corrupted("file length changed outside of the history"));
+        }
+
+        let mut record = vec![0; usize::try_from(self.end - offset).unwrap_or(usize::MAX)];
+        file.seek(SeekFrom::Start(offset)).map_err(io_error)?;
+        file.read_exact(&mut record).map_err(io_error)?;
+
+        let entry = self
+            .parse(&record)
+            .ok_or_else(|| self.corrupted("record does not decode"))?;
+
+        file.set_len(offset).map_err(io_error)?;
+        self.offsets.pop();
+        self.end = offset;
+        Ok(Some(entry))
+    }
+
+    /// Forgets every spilled entry and empties the file.
+    pub(crate) fn clear(&mut self) {
+        self.offsets.clear();
+        self.end = 0;
+        let _ = File::create(&self.path);
+    }
+
+    fn parse<E, S>(&self, record: &[u8]) -> Option<HistoryEntry<E, S>>
+    where
+        E: From<C>,
+    {
+        let mut reader = Reader(record);
+        let len = reader.len()?;
+        let sum = reader.u32()?;
+        let body = reader.0;
+        if body.len() != len || checksum(body) != sum {
+            return None;
+        }
+
+        let mut reader = Reader(body);
+        let executed_at = reader.time()?;
+        let mut metadata = EntryMetadata::default();
+        if reader.flag()? {
+            metadata.author = Some(reader.string()?);
+        }
+        for _ in 0..reader.len()? {
+            metadata.tags.push(reader.string()?);
+        }
+        if reader.flag()? {
+            metadata.note = Some(reader.string()?);
+        }
+
+        let kind = reader.u8()?;
+        let mut commands = Vec::new();
+        for _ in 0..reader.len()? {
+            commands.push(E::from((self.decode)(reader.bytes()?)?));
+        }
+
+        let payload = match kind {
+            PAYLOAD_COMMAND if commands.len() == 1 => EntryPayload::Command(commands.pop()?),
+            PAYLOAD_COMPOUND => EntryPayload::Compound(commands),
+            _ => return None,
+        };
+
+        Some(HistoryEntry::from_payload(payload, metadata).with_executed_at(executed_at))
+    }
+
+    fn corrupted(&self, reason: &str) -> HistoryError {
+        HistoryError::SpillCorrupted {
+            path: self.path.clone(),
+            reason: reason.to_owned(),
+        }
+    }
+}
+
+impl<C> Drop for SpillStore<C> {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// FNV-1a, enough to tell a damaged record from an intact one.
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ u32::from(*byte)).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// Writes `len` as a `u32`, or returns `None` if it does not fit.
+fn write_len(out: &mut Vec<u8>, len: usize) -> Option<()> {
+    let len = u32::try_from(len).ok()?;
+    out.extend_from_slice(&len.to_le_bytes());
+    Some(())
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) -> Option<()> {
+    write_len(out, bytes.len())?;
+    out.extend_from_slice(bytes);
+    Some(())
+}
+
+fn write_optional(out: &mut Vec<u8>, value: Option<&str>) -> Option<()> {
+    if let Some(value) = value {
+        out.push(1);
+        write_bytes(out, value.as_bytes())
+    } else {
+        out.push(0);
+        Some(())
+    }
+}
+
+fn write_time(out: &mut Vec<u8>, time: SystemTime) {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    out.extend_from_slice(&since_epoch.as_secs().to_le_bytes());
+    out.extend_from_slice(&since_epoch.subsec_nanos().to_le_bytes());
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Option<&'a [u8]> {
+        if self.0.len() < count {
+            return None;
+        }
+
+        let (taken, rest) = self.0.split_at(count);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.take(4)?.try_into().ok().map(u32::from_le_bytes)
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.take(8)?.try_into().ok().map(u64::from_le_bytes)
+    }
+
+    fn len(&mut self) -> Option<usize> {
+        self.u32().and_then(|len| usize::try_from(len).ok())
+    }
+
+    fn bytes(&mut self) -> Option<&'a [u8]> {
+        let len = self.len()?;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Option<String> {
+        String::from_utf8(self.bytes()?.to_vec()).ok()
+    }
+
+    fn flag(&mut self) -> Option<bool> {
+        match self.u8()? {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        }
+    }
+
+    fn time(&mut self) -> Option<SystemTime> {
+        let secs = self.u64()?;
+        let nanos = self.u32()?;
+        if nanos >= 1_000_000_000 {
+            return None;
+        }
+        UNIX_EPOCH.checked_add(Duration::new(secs, nanos))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_oversized_lengths_are_refused() {
+        let mut out = Vec::new();
+        assert_eq!(write_len(&mut out, u32::MAX as usize), Some(()));
+        if usize::BITS > 32 {
+            assert_eq!(write_len(&mut out, u32::MAX as usize + 1), None);
+        }
+        assert_eq!(out.len(), 4);
+    }
+
+    #[test]
+    fn test_damaged_timestamps_do_not_panic() {
+        let mut bytes = u64::MAX.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&999_999_999_u32.to_le_bytes());
+        assert_eq!(Reader(&bytes).time(), None);
+
+        let mut bytes = 0_u64.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(Reader(&bytes).time(), None);
+
+        let mut bytes = 1_u64.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&5_u32.to_le_bytes());
+        assert_eq!(
+            Reader(&bytes).time(),
+            Some(UNIX_EPOCH + Duration::new(1, 5))
+        );
+    }
+}
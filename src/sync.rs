@@ -0,0 +1,445 @@
+use std::{
+    sync::{LockResult, PoisonError},
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "parking_lot")]
+pub use parking_lot::{
+    Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockUpgradableReadGuard, RwLockWriteGuard,
+};
+
+#[cfg(not(feature = "parking_lot"))]
+pub use self::std_backend::{Mutex, RwLock, RwLockUpgradableReadGuard};
+#[cfg(not(feature = "parking_lot"))]
+pub use std::sync::{MutexGuard, RwLockReadGuard, RwLockWriteGuard};
+
+/// What a lock of the `std` backend does when it was poisoned by a thread that panicked while
+/// holding it.
+///
+/// Every lock has its own policy, see
+/// [`SharedContext::set_poison_policy`](crate::shared_context::SharedContext::set_poison_policy).
+/// The `parking_lot` backend never poisons its locks, so the policy has no effect there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PoisonPolicy {
+    /// Panic on the locking thread as well. This is the default.
+    #[default]
+    Propagate,
+    /// Ignore the poisoning and hand out the lock as usual. Only suitable if every change to the
+    /// guarded values leaves them consistent, even when interrupted by a panic.
+    Recover,
+}
+
+/// Locks that carry their own [`PoisonPolicy`].
+pub(crate) trait PoisonPolicyExt {
+    fn set_poison_policy(&self, policy: PoisonPolicy);
+
+    fn poison_policy(&self) -> PoisonPolicy;
+}
+
+#[cfg(feature = "parking_lot")]
+impl<T: ?Sized> PoisonPolicyExt for Mutex<T> {
+    fn set_poison_policy(&self, _policy: PoisonPolicy) {}
+
+    fn poison_policy(&self) -> PoisonPolicy {
+        PoisonPolicy::Propagate
+    }
+}
+
+#[cfg(feature = "parking_lot")]
+impl<T: ?Sized> PoisonPolicyExt for RwLock<T> {
+    fn set_poison_policy(&self, _policy: PoisonPolicy) {}
+
+    fn poison_policy(&self) -> PoisonPolicy {
+        PoisonPolicy::Propagate
+    }
+}
+
+/// Applies `policy` to the result of a `std` lock.
+fn apply_policy<G>(result: LockResult<G>, policy: PoisonPolicy) -> G {
+    result.unwrap_or_else(|poisoned| match policy {
+        PoisonPolicy::Recover => poisoned.into_inner(),
+        PoisonPolicy::Propagate => {
+            panic!("a lock was poisoned by a thread that panicked while holding it")
+        }
+    })
+}
+
+/// Locks `mutex`, reporting poisoning to the caller instead of applying the [`PoisonPolicy`].
+#[cfg(feature = "parking_lot")]
+#[allow(clippy::unnecessary_wraps)] // Same signature as the `std` backend.
+pub(crate) fn lock_checked<T>(mutex: &Mutex<T>) -> LockResult<MutexGuard<'_, T>> {
+    Ok(mutex.lock())
+}
+
+/// Locks `mutex`, reporting poisoning to the caller instead of applying the [`PoisonPolicy`].
+#[cfg(not(feature = "parking_lot"))]
+pub(crate) fn lock_checked<T>(mutex: &Mutex<T>) -> LockResult<MutexGuard<'_, T>> {
+    mutex.inner.lock()
+}
+
+#[cfg(feature = "parking_lot")]
+pub(crate) fn is_poisoned<T>(_mutex: &Mutex<T>) -> bool {
+    false
+}
+
+#[cfg(not(feature = "parking_lot"))]
+pub(crate) fn is_poisoned<T>(mutex: &Mutex<T>) -> bool {
+    mutex.inner.is_poisoned()
+}
+
+#[cfg(feature = "parking_lot")]
+pub(crate) fn clear_poison<T>(_mutex: &Mutex<T>) {}
+
+#[cfg(not(feature = "parking_lot"))]
+pub(crate) fn clear_poison<T>(mutex: &Mutex<T>) {
+    mutex.inner.clear_poison();
+}
+
+/// Locks `mutex`, giving up once `deadline` has passed. `None` waits as long as it takes.
+pub(crate) fn lock_until<T>(
+    mutex: &Mutex<T>,
+    deadline: Option<Instant>,
+) -> Option<MutexGuard<'_, T>> {
+    match deadline {
+        Some(deadline) => mutex.try_lock_until(deadline),
+        None => Some(mutex.lock()),
+    }
+}
+
+/// Write-locks `lock`, giving up once `deadline` has passed. `None` waits as long as it takes.
+pub(crate) fn write_until<T>(
+    lock: &RwLock<T>,
+    deadline: Option<Instant>,
+) -> Option<RwLockWriteGuard<'_, T>> {
+    match deadline {
+        Some(deadline) => lock.try_write_until(deadline),
+        None => Some(lock.write()),
+    }
+}
+
+/// Returns the deadline `timeout` from now, or `None` if it lies too far in the future to be
+/// represented, which waits as long as it takes.
+pub(crate) fn deadline_after(timeout: Duration) -> Option<Instant> {
+    Instant::now().checked_add(timeout)
+}
+
+/// A condition variable that works with the [`MutexGuard`] of either backend.
+#[derive(Default)]
+pub(crate) struct Condvar {
+    #[cfg(feature = "parking_lot")]
+    inner: parking_lot::Condvar,
+    #[cfg(not(feature = "parking_lot"))]
+    inner: std::sync::Condvar,
+}
+
+impl Condvar {
+    /// Blocks until notified or until `deadline` has passed, and returns the guard together with
+    /// whether the deadline has passed. `policy` is the [`PoisonPolicy`] of the guarded mutex.
+    #[cfg_attr(feature = "parking_lot", allow(unused_variables))]
+    pub(crate) fn wait_until<'a, T>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        deadline: Option<Instant>,
+        policy: PoisonPolicy,
+    ) -> (MutexGuard<'a, T>, bool) {
+        #[cfg(feature = "parking_lot")]
+        {
+            let mut guard = guard;
+            let timed_out = if let Some(deadline) = deadline {
+                self.inner.wait_until(&mut guard, deadline).timed_out()
+            } else {
+                self.inner.wait(&mut guard);
+                false
+            };
+            (guard, timed_out)
+        }
+        #[cfg(not(feature = "parking_lot"))]
+        match deadline {
+            Some(deadline) => {
+                let timeout = deadline.saturating_duration_since(Instant::now());
+                let (guard, result) = apply_policy(self.inner.wait_timeout(guard, timeout), policy);
+                (guard, result.timed_out())
+            }
+            None => (apply_policy(self.inner.wait(guard), policy), false),
+        }
+    }
+
+    pub(crate) fn notify_all(&self) {
+        self.inner.notify_all();
+    }
+}
+
+/// Turns a poisoned lock result into one carrying a different guard, e.g. a wrapper around it.
+pub(crate) fn map_lock_result<G, H>(
+    result: LockResult<G>,
+    f: impl FnOnce(G) -> H,
+) -> LockResult<H> {
+    match result {
+        Ok(guard) => Ok(f(guard)),
+        Err(poisoned) => Err(PoisonError::new(f(poisoned.into_inner()))),
+    }
+}
+
+#[cfg(not(feature = "parking_lot"))]
+mod std_backend {
+    use std::{
+        fmt,
+        ops::Deref,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            MutexGuard, RwLockReadGuard, RwLockWriteGuard, TryLockError, TryLockResult,
+        },
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::{apply_policy, PoisonPolicy, PoisonPolicyExt};
+
+    /// How long the timed locks sleep between attempts, since `std` has no timed locking.
+    const RETRY_INTERVAL: Duration = Duration::from_micros(200);
+
+    /// Calls `attempt` until it succeeds or `deadline` has passed.
+    fn retry_until<G>(deadline: Instant, mut attempt: impl FnMut() -> Option<G>) -> Option<G> {
+        loop {
+            if let Some(guard) = attempt() {
+                return Some(guard);
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return None;
+            }
+            thread::sleep(remaining.min(RETRY_INTERVAL));
+        }
+    }
+
+    /// A `std::sync::Mutex` with the API of `parking_lot::Mutex`. Poisoning is handled according
+    /// to its own [`PoisonPolicy`], which propagates unless changed.
+    #[derive(Default)]
+    pub struct Mutex<T: ?Sized> {
+        recover: AtomicBool,
+        pub(super) inner: std::sync::Mutex<T>,
+    }
+
+    /// A `std::sync::RwLock` with the API of `parking_lot::RwLock`. Poisoning is handled
+    /// according to its own [`PoisonPolicy`], which propagates unless changed.
+    #[derive(Default)]
+    pub struct RwLock<T: ?Sized> {
+        recover: AtomicBool,
+        inner: std::sync::RwLock<T>,
+    }
+
+    fn try_apply_policy<G>(result: TryLockResult<G>, policy: PoisonPolicy) -> Option<G> {
+        match result {
+            Ok(guard) => Some(guard),
+            Err(TryLockError::Poisoned(poisoned)) => Some(apply_policy(Err(poisoned), policy)),
+            Err(TryLockError::WouldBlock) => None,
+        }
+    }
+
+    fn policy_of(recover: &AtomicBool) -> PoisonPolicy {
+        if recover.load(Ordering::Relaxed) {
+            PoisonPolicy::Recover
+        } else {
+            PoisonPolicy::Propagate
+        }
+    }
+
+    impl<T: ?Sized> PoisonPolicyExt for Mutex<T> {
+        fn set_poison_policy(&self, policy: PoisonPolicy) {
+            self.recover
+                .store(policy == PoisonPolicy::Recover, Ordering::Relaxed);
+        }
+
+        fn poison_policy(&self) -> PoisonPolicy {
+            policy_of(&self.recover)
+        }
+    }
+
+    impl<T: ?Sized> PoisonPolicyExt for RwLock<T> {
+        fn set_poison_policy(&self, policy: PoisonPolicy) {
+            self.recover
+                .store(policy == PoisonPolicy::Recover, Ordering::Relaxed);
+        }
+
+        fn poison_policy(&self) -> PoisonPolicy {
+            policy_of(&self.recover)
+        }
+    }
+
+    impl<T> Mutex<T> {
+        pub const fn new(value: T) -> Self {
+            Self {
+                recover: AtomicBool::new(false),
+                inner: std::sync::Mutex::new(value),
+            }
+        }
+
+        pub fn into_inner(self) -> T {
+            let policy = self.poison_policy();
+            apply_policy(self.inner.into_inner(), policy)
+        }
+    }
+
+    impl<T: ?Sized> Mutex<T> {
+        pub fn lock(&self) -> MutexGuard<'_, T> {
+            apply_policy(self.inner.lock(), self.poison_policy())
+        }
+
+        pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+            try_apply_policy(self.inner.try_lock(), self.poison_policy())
+        }
+
+        pub fn try_lock_until(&self, deadline: Instant) -> Option<MutexGuard<'_, T>> {
+            retry_until(deadline, || self.try_lock())
+        }
+
+        pub fn get_mut(&mut self) -> &mut T {
+            let policy = self.poison_policy();
+            apply_policy(self.inner.get_mut(), policy)
+        }
+    }
+
+    impl<T> RwLock<T> {
+        pub const fn new(value: T) -> Self {
+            Self {
+                recover: AtomicBool::new(false),
+                inner: std::sync::RwLock::new(value),
+            }
+        }
+
+        pub fn into_inner(self) -> T {
+            let policy = self.poison_policy();
+            apply_policy(self.inner.into_inner(), policy)
+        }
+    }
+
+    impl<T: ?Sized> RwLock<T> {
+        pub fn read(&self) -> RwLockReadGuard<'_, T> {
+            apply_policy(self.inner.read(), self.poison_policy())
+        }
+
+        pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+            apply_policy(self.inner.write(), self.poison_policy())
+        }
+
+        pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+            try_apply_policy(self.inner.try_read(), self.poison_policy())
+        }
+
+        pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+            try_apply_policy(self.inner.try_write(), self.poison_policy())
+        }
+
+        pub fn try_read_until(&self, deadline: Instant) -> Option<RwLockReadGuard<'_, T>> {
+            retry_until(deadline, || self.try_read())
+        }
+
+        pub fn try_write_until(&self, deadline: Instant) -> Option<RwLockWriteGuard<'_, T>> {
+            retry_until(deadline, || self.try_write())
+        }
+
+        pub fn upgradable_read(&self) -> RwLockUpgradableReadGuard<'_, T> {
+            RwLockUpgradableReadGuard(self.write())
+        }
+
+        pub fn try_upgradable_read(&self) -> Option<RwLockUpgradableReadGuard<'_, T>> {
+            self.try_write().map(RwLockUpgradableReadGuard)
+        }
+
+        pub fn get_mut(&mut self) -> &mut T {
+            let policy = self.poison_policy();
+            apply_policy(self.inner.get_mut(), policy)
+        }
+    }
+
+    /// Stands in for the upgradable read guard of `parking_lot`, which `std` has no counterpart
+    /// for. It holds the write lock from the start, so other readers wait until it is released
+    /// instead of only while it is upgraded.
+    #[must_use = "if unused the RwLock will immediately unlock"]
+    pub struct RwLockUpgradableReadGuard<'a, T: ?Sized>(RwLockWriteGuard<'a, T>);
+
+    impl<'a, T: ?Sized> RwLockUpgradableReadGuard<'a, T> {
+        pub fn upgrade(guard: Self) -> RwLockWriteGuard<'a, T> {
+            guard.0
+        }
+
+        /// Never fails, since the write lock is already held.
+        ///
+        /// # Errors
+        ///
+        /// Has the signature of the `parking_lot` guard, which fails while other readers exist.
+        pub fn try_upgrade(guard: Self) -> Result<RwLockWriteGuard<'a, T>, Self> {
+            Ok(guard.0)
+        }
+    }
+
+    impl<T: ?Sized> Deref for RwLockUpgradableReadGuard<'_, T> {
+        type Target = T;
+
+        fn deref(&self) -> &T {
+            &self.0
+        }
+    }
+
+    impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLockUpgradableReadGuard<'_, T> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            fmt::Debug::fmt(&**self, f)
+        }
+    }
+
+    impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            fmt::Debug::fmt(&self.inner, f)
+        }
+    }
+
+    impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLock<T> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            fmt::Debug::fmt(&self.inner, f)
+        }
+    }
+}
+
+#[cfg(all(test, not(feature = "parking_lot")))]
+mod tests {
+    use std::{panic, sync::Arc, thread};
+
+    use super::*;
+
+    #[test]
+    fn test_poison_policy() {
+        let mutex = Arc::new(Mutex::new(1));
+        let poisoner = Arc::clone(&mutex);
+        thread::spawn(move || {
+            let _guard = poisoner.lock();
+            panic!("poison the lock");
+        })
+        .join()
+        .unwrap_err();
+
+        assert!(is_poisoned(&mutex));
+        assert!(panic::catch_unwind(|| *mutex.lock()).is_err());
+        assert_eq!(*lock_checked(&mutex).unwrap_err().into_inner(), 1);
+
+        mutex.set_poison_policy(PoisonPolicy::Recover);
+        assert_eq!(*mutex.lock(), 1);
+        assert!(panic::catch_unwind(|| *poisoned_elsewhere().lock()).is_err());
+        mutex.set_poison_policy(PoisonPolicy::Propagate);
+
+        clear_poison(&mutex);
+        assert_eq!(*mutex.lock(), 1);
+    }
+
+    fn poisoned_elsewhere() -> Arc<Mutex<i32>> {
+        let mutex = Arc::new(Mutex::new(2));
+        let poisoner = Arc::clone(&mutex);
+        thread::spawn(move || {
+            let _guard = poisoner.lock();
+            panic!("poison another lock");
+        })
+        .join()
+        .unwrap_err();
+        mutex
+    }
+}
@@ -0,0 +1,235 @@
+use std::{
+    mem,
+    sync::{Arc, Weak},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    concurrent_command_history::{ConcurrentCommandHistory, ConcurrentEntry},
+    error::HistoryError,
+    sync::{self, Condvar, Mutex, PoisonPolicyExt},
+    traits::command::Command,
+};
+
+enum State<C: Command> {
+    /// Executed and waiting for confirmation.
+    Pending(ConcurrentEntry<C>, C::Context),
+    /// Reverted by the timer, with the outcome of the revert.
+    Expired(Result<(), HistoryError>),
+    /// Taken by the handle or the timer.
+    Settled,
+}
+
+type Shared<C> = Arc<Mutex<State<C>>>;
+
+/// The tentative command the timer waits for, if any, and whether the history is gone.
+struct Slot<C: Command> {
+    armed: Option<(Option<Instant>, Shared<C>)>,
+    closed: bool,
+}
+
+/// Reverts the pending tentative command of a history once its deadline passes.
+///
+/// A history starts one timer thread with its first tentative command, which serves every later
+/// one, since only one can be pending at a time. The thread only holds the history weakly and
+/// exits once it is dropped.
+pub(crate) struct Timer<C: Command> {
+    slot: Mutex<Slot<C>>,
+    changed: Condvar,
+}
+
+impl<C: Command + Send + Sync> Timer<C> {
+    pub(crate) fn spawn(history: Weak<ConcurrentCommandHistory<C>>) -> Arc<Self>
+    where
+        C: 'static,
+        C::Context: Send + Sync + 'static,
+    {
+        let timer = Arc::new(Self {
+            slot: Mutex::new(Slot {
+                armed: None,
+                closed: false,
+            }),
+            changed: Condvar::default(),
+        });
+
+        let thread_timer = Arc::clone(&timer);
+        thread::spawn(move || thread_timer.run(&history));
+        timer
+    }
+
+    /// Stops the timer thread.
+    pub(crate) fn close(&self) {
+        self.slot.lock().closed = true;
+        self.changed.notify_all();
+    }
+
+    fn arm(&self, deadline: Option<Instant>, shared: Shared<C>) {
+        self.slot.lock().armed = Some((deadline, shared));
+        self.changed.notify_all();
+    }
+
+    /// Stops waiting for `shared`, once it was confirmed or reverted through its handle.
+    fn disarm(&self, shared: &Shared<C>) {
+        let mut slot = self.slot.lock();
+        if slot
+            .armed
+            .as_ref()
+            .is_some_and(|(_, armed)| Arc::ptr_eq(armed, shared))
+        {
+            slot.armed = None;
+            drop(slot);
+            self.changed.notify_all();
+        }
+    }
+
+    fn run(&self, history: &Weak<ConcurrentCommandHistory<C>>) {
+        let mut slot = self.slot.lock();
+        loop {
+            if slot.closed {
+                return;
+            }
+
+            let deadline = slot.armed.as_ref().and_then(|(deadline, _)| *deadline);
+            if deadline.is_some_and(|deadline| deadline <= Instant::now()) {
+                let armed = slot.armed.take();
+                drop(slot);
+                if let (Some((_, shared)), Some(history)) = (armed, history.upgrade()) {
+                    expire(&history, &shared);
+                }
+                slot = self.slot.lock();
+                continue;
+            }
+
+            let policy = self.slot.poison_policy();
+            slot = self.changed.wait_until(slot, deadline, policy).0;
+        }
+    }
+}
+
+/// A command executed by
+/// [`ConcurrentCommandHistory::execute_tentative`], waiting to be confirmed or reverted.
+///
+/// The timer thread of the history reverts the command once the deadline passes. Dropping the
+/// handle reverts the command right away, unless it is dropped from inside a command or while
+/// panicking, in which case the deadline still applies.
+///
+/// The command is reverted on a clone of the context it was executed on, so the context has to
+/// be a handle to shared state, such as [`SharedContext`](crate::shared_context::SharedContext),
+/// [`SharedRwContext`](crate::shared_rw_context::SharedRwContext) or
+/// [`SnapshotContext`](crate::snapshot_context::SnapshotContext). Reverting a clone of a plain
+/// value would leave the context the command changed as it is.
+pub struct Tentative<C: Command + Send + Sync> {
+    history: Arc<ConcurrentCommandHistory<C>>,
+    timer: Arc<Timer<C>>,
+    shared: Shared<C>,
+    description: String,
+}
+
+impl<C: Command + Send + Sync> Tentative<C> {
+    pub(crate) fn start(
+        history: Arc<ConcurrentCommandHistory<C>>,
+        timer: Arc<Timer<C>>,
+        entry: ConcurrentEntry<C>,
+        ctx: C::Context,
+        timeout: Duration,
+    ) -> Self {
+        let description = ConcurrentCommandHistory::describe(&entry);
+        let shared = Arc::new(Mutex::new(State::Pending(entry, ctx)));
+        timer.arm(sync::deadline_after(timeout), Arc::clone(&shared));
+
+        Self {
+            history,
+            timer,
+            shared,
+            description,
+        }
+    }
+
+    /// Returns the description of the command.
+    #[must_use]
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+
+    /// Returns whether the command is still waiting for confirmation.
+    #[must_use]
+    pub fn is_pending(&self) -> bool {
+        matches!(*self.shared.lock(), State::Pending(..))
+    }
+
+    /// Records the command in the history, as its newest entry.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`HistoryError::Reentrant`] when called from inside a command, in which case the
+    /// command stays pending, and [`HistoryError::Expired`] if it was already reverted. A command
+    /// that was kept on expiry because reverting it broke the invariant counts as confirmed.
+    pub fn confirm(mut self) -> Result<(), HistoryError> {
+        if self.history.is_reentrant() {
+            return Err(HistoryError::Reentrant);
+        }
+
+        match self.take() {
+            State::Pending(entry, ctx) => {
+                self.history.confirm_tentative(entry, &ctx);
+                Ok(())
+            }
+            State::Expired(Err(HistoryError::InvariantViolated { .. })) => Ok(()),
+            State::Expired(_) | State::Settled => Err(HistoryError::Expired {
+                description: mem::take(&mut self.description),
+            }),
+        }
+    }
+
+    /// Undoes the command and discards it. Does nothing if it already expired.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`HistoryError::Reentrant`] when called from inside a command, in which case the
+    /// command stays pending, and [`HistoryError::Panicked`] if undoing it panicked, here or on
+    /// expiry, and the [`PanicPolicy`](crate::panic_policy::PanicPolicy) caught it.
+    ///
+    /// Returns [`HistoryError::InvariantViolated`] if undoing it broke the invariant, here or on
+    /// expiry. The command is redone and recorded in that case, as if it had been confirmed.
+    pub fn revert(self) -> Result<(), HistoryError> {
+        if self.history.is_reentrant() {
+            return Err(HistoryError::Reentrant);
+        }
+
+        match self.take() {
+            State::Pending(entry, ctx) => self.history.revert_tentative(entry, &ctx),
+            State::Expired(outcome) => outcome,
+            State::Settled => Ok(()),
+        }
+    }
+
+    /// Takes the state and stops the timer from waiting for it.
+    fn take(&self) -> State<C> {
+        let state = mem::replace(&mut *self.shared.lock(), State::Settled);
+        self.timer.disarm(&self.shared);
+        state
+    }
+}
+
+impl<C: Command + Send + Sync> Drop for Tentative<C> {
+    fn drop(&mut self) {
+        if thread::panicking() || self.history.is_reentrant() {
+            return;
+        }
+
+        if let State::Pending(entry, ctx) = self.take() {
+            // A failed revert is reported by the panic policy; there is no caller to return it to.
+            let _ = self.history.revert_tentative(entry, &ctx);
+        }
+    }
+}
+
+/// Reverts the command if it is still pending.
+fn expire<C: Command + Send + Sync>(history: &ConcurrentCommandHistory<C>, shared: &Shared<C>) {
+    // The state stays locked while reverting, so a concurrent confirm sees the outcome.
+    let mut state = shared.lock();
+    if let State::Pending(entry, ctx) = mem::replace(&mut *state, State::Settled) {
+        *state = State::Expired(history.revert_tentative(entry, &ctx));
+    }
+}
@@ -0,0 +1,540 @@
+//! Randomized checks of the undo and redo laws of user commands.
+//!
+//! Every command is expected to satisfy two laws: undoing it restores the context it was executed
+//! on, and redoing it afterwards restores the context it produced. [`LawCheck`] runs random
+//! sequences of commands through a history, checks both laws after every step as well as when
+//! walking the whole sequence back and forth, and shrinks a failing sequence to a minimal repro.
+//!
+//! Only available with the `testing` feature.
+//!
+//! # Examples
+//!
+//! ```
+//! use command_history::{prelude::*, testing::LawCheck};
+//!
+//! #[derive(Debug, Clone)]
+//! struct Push(i32);
+//!
+//! impl MutableCommand for Push {
+//!     type Context = Vec<i32>;
+//!
+//!     fn execute(&self, ctx: &mut Self::Context) {
+//!         ctx.push(self.0);
+//!     }
+//!
+//!     fn undo(&self, ctx: &mut Self::Context) {
+//!         ctx.pop();
+//!     }
+//! }
+//!
+//! LawCheck::new()
+//!     .check_simple(&Vec::new(), |rng| Push(rng.below(10) as i32))
+//!     .unwrap();
+//! ```
+
+use std::{fmt, num::NonZeroUsize};
+
+use crate::{
+    concurrent_command_history::ConcurrentCommandHistory,
+    shared_context::SharedContext,
+    shared_rw_context::SharedRwContext,
+    simple_command_history::SimpleCommandHistory,
+    snapshot_context::SnapshotContext,
+    traits::{
+        command::Command, command_history::CommandHistory, mutable_command::MutableCommand,
+        mutable_command_history::MutableCommandHistory,
+    },
+};
+
+/// A small deterministic random number generator handed to command generators, so a failing
+/// case can be reproduced from its seed.
+#[derive(Debug, Clone)]
+pub struct TestRng {
+    state: u64,
+}
+
+impl TestRng {
+    #[must_use]
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    /// Returns the next random number, using the `SplitMix64` algorithm.
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a number in `0..bound`, or 0 if `bound` is 0.
+    #[allow(clippy::cast_possible_truncation)] // The result is below `bound`.
+    pub fn below(&mut self, bound: usize) -> usize {
+        if bound == 0 {
+            0
+        } else {
+            (self.next_u64() % bound as u64) as usize
+        }
+    }
+
+    pub fn coin(&mut self) -> bool {
+        self.next_u64() & 1 == 1
+    }
+
+    /// Returns a random element of `items`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `items` is empty.
+    pub fn choose<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        assert!(!items.is_empty(), "cannot choose from an empty slice");
+        &items[self.below(items.len())]
+    }
+}
+
+/// The law a command sequence broke.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Law {
+    /// Undoing a command did not restore the context it was executed on.
+    UndoRestoresPrevious,
+    /// Redoing a command did not restore the context it produced when it was executed.
+    RedoRestoresNext,
+}
+
+impl fmt::Display for Law {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::UndoRestoresPrevious => "undo restores the previous state",
+            Self::RedoRestoresNext => "redo restores the next state",
+        })
+    }
+}
+
+/// A minimal command sequence that breaks a [`Law`], as found by [`LawCheck`].
+///
+/// # Fields
+///
+/// * `seed` - Reproduces the case with `LawCheck::new().seed(seed).cases(1)`, before shrinking.
+/// * `commands` - The shrunk sequence, in execution order.
+/// * `step` - The index of the command in `commands` whose undo or redo broke the law.
+/// * `law` - The broken law.
+/// * `expected` - The state the law asked for.
+/// * `actual` - The state the context was in instead.
+#[derive(Debug, Clone)]
+pub struct LawViolation<C, S> {
+    pub seed: u64,
+    pub commands: Vec<C>,
+    pub step: usize,
+    pub law: Law,
+    pub expected: S,
+    pub actual: S,
+}
+
+impl<C: fmt::Debug, S: fmt::Debug> fmt::Display for LawViolation<C, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "law \"{}\" broken at step {} of {:?} (seed {}): expected {:?}, got {:?}",
+            self.law, self.step, self.commands, self.seed, self.expected, self.actual
+        )
+    }
+}
+
+impl<C: fmt::Debug, S: fmt::Debug> std::error::Error for LawViolation<C, S> {}
+
+/// A shared context whose state [`LawCheck::check_concurrent`] can create, copy and compare.
+pub trait Observable {
+    type State: Clone + PartialEq + fmt::Debug;
+
+    /// Creates a context holding `state`.
+    fn from_state(state: Self::State) -> Self;
+
+    /// Returns a copy of the current state.
+    fn observe(&self) -> Self::State;
+}
+
+impl<T: Clone + PartialEq + fmt::Debug> Observable for SharedContext<T> {
+    type State = T;
+
+    fn from_state(state: T) -> Self {
+        SharedContext::new(state)
+    }
+
+    fn observe(&self) -> T {
+        self.lock().clone()
+    }
+}
+
+impl<T: Clone + PartialEq + fmt::Debug> Observable for SharedRwContext<T> {
+    type State = T;
+
+    fn from_state(state: T) -> Self {
+        SharedRwContext::new(state)
+    }
+
+    fn observe(&self) -> T {
+        self.read().clone()
+    }
+}
+
+impl<T: Clone + PartialEq + fmt::Debug> Observable for SnapshotContext<T> {
+    type State = T;
+
+    fn from_state(state: T) -> Self {
+        SnapshotContext::new(state)
+    }
+
+    fn observe(&self) -> T {
+        (*self.load()).clone()
+    }
+}
+
+/// Where a sequence first broke a law, before it is turned into a [`LawViolation`].
+struct Broken<S> {
+    step: usize,
+    law: Law,
+    expected: S,
+    actual: S,
+}
+
+/// Runs randomized command sequences through a history and checks the undo and redo laws.
+///
+/// Each case generates between one and `max_len` commands. After every command the history
+/// undoes and redoes it, and after the whole sequence it undoes everything and redoes it again,
+/// comparing the context with the recorded states each time. The first failing case is shrunk by
+/// dropping commands for as long as the rest still fails.
+///
+/// # Fields
+///
+/// * `cases` - How many sequences are generated. Defaults to 100.
+/// * `max_len` - The maximum length of a sequence. Defaults to 20.
+/// * `seed` - The seed of the first case, later cases use the following seeds. Defaults to 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LawCheck {
+    pub cases: usize,
+    pub max_len: usize,
+    pub seed: u64,
+}
+
+impl Default for LawCheck {
+    fn default() -> Self {
+        Self {
+            cases: 100,
+            max_len: 20,
+            seed: 0,
+        }
+    }
+}
+
+impl LawCheck {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[must_use]
+    pub fn cases(mut self, cases: usize) -> Self {
+        self.cases = cases;
+        self
+    }
+
+    #[must_use]
+    pub fn max_len(mut self, max_len: usize) -> Self {
+        self.max_len = max_len;
+        self
+    }
+
+    #[must_use]
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Checks the laws for sequences made by `generate`, run through a [`SimpleCommandHistory`]
+    /// starting from `initial`.
+    ///
+    /// # Errors
+    ///
+    /// Returns the shrunk [`LawViolation`] of the first failing case.
+    pub fn check_simple<C, G>(
+        &self,
+        initial: &C::Context,
+        generate: G,
+    ) -> Result<(), LawViolation<C, C::Context>>
+    where
+        C: MutableCommand + Clone + fmt::Debug,
+        C::Context: Clone + PartialEq + fmt::Debug,
+        G: FnMut(&mut TestRng) -> C,
+    {
+        self.check(generate, |commands| run_simple(initial, commands))
+    }
+
+    /// Checks the laws for sequences made by `generate`, run through a
+    /// [`ConcurrentCommandHistory`] on a fresh context holding `initial`.
+    ///
+    /// # Errors
+    ///
+    /// Returns the shrunk [`LawViolation`] of the first failing case.
+    pub fn check_concurrent<C, G>(
+        &self,
+        initial: &<C::Context as Observable>::State,
+        generate: G,
+    ) -> Result<(), LawViolation<C, <C::Context as Observable>::State>>
+    where
+        C: Command + Send + Sync + Clone + fmt::Debug,
+        C::Context: Observable,
+        G: FnMut(&mut TestRng) -> C,
+    {
+        self.check(generate, |commands| run_concurrent(initial, commands))
+    }
+
+    fn check<C, S, G, R>(&self, mut generate: G, run: R) -> Result<(), LawViolation<C, S>>
+    where
+        C: Clone,
+        G: FnMut(&mut TestRng) -> C,
+        R: Fn(&[C]) -> Result<(), Broken<S>>,
+    {
+        for case in 0..self.cases {
+            let seed = self.seed.wrapping_add(case as u64);
+            let mut rng = TestRng::new(seed);
+            let len = 1 + rng.below(self.max_len.max(1));
+            let commands: Vec<C> = (0..len).map(|_| generate(&mut rng)).collect();
+
+            if let Err(broken) = run(&commands) {
+                let (commands, broken) = shrink(commands, broken, &run);
+                return Err(LawViolation {
+                    seed,
+                    commands,
+                    step: broken.step,
+                    law: broken.law,
+                    expected: broken.expected,
+                    actual: broken.actual,
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Drops chunks of `commands`, halving the chunk size down to single commands, for as long as
+/// the remaining sequence still breaks a law.
+fn shrink<C, S, R>(mut commands: Vec<C>, mut broken: Broken<S>, run: R) -> (Vec<C>, Broken<S>)
+where
+    C: Clone,
+    R: Fn(&[C]) -> Result<(), Broken<S>>,
+{
+    let mut chunk = commands.len() / 2;
+    while chunk > 0 {
+        let mut start = 0;
+        while start < commands.len() && commands.len() > 1 {
+            let end = (start + chunk).min(commands.len());
+            let candidate: Vec<C> = commands[..start]
+                .iter()
+                .chain(&commands[end..])
+                .cloned()
+                .collect();
+
+            match run(&candidate) {
+                Err(still_broken) if !candidate.is_empty() => {
+                    commands = candidate;
+                    broken = still_broken;
+                }
+                _ => start += chunk,
+            }
+        }
+        chunk /= 2;
+    }
+
+    (commands, broken)
+}
+
+/// Compares `actual` with `expected` and reports `law` at `step` if they differ.
+fn expect<S>(step: usize, law: Law, expected: &S, actual: S) -> Result<(), Broken<S>>
+where
+    S: Clone + PartialEq,
+{
+    if *expected == actual {
+        Ok(())
+    } else {
+        Err(Broken {
+            step,
+            law,
+            expected: expected.clone(),
+            actual,
+        })
+    }
+}
+
+fn run_simple<C>(initial: &C::Context, commands: &[C]) -> Result<(), Broken<C::Context>>
+where
+    C: MutableCommand + Clone,
+    C::Context: Clone + PartialEq,
+{
+    let mut history = SimpleCommandHistory::new(commands.len(), true);
+    let mut ctx = initial.clone();
+    let mut states = vec![ctx.clone()];
+
+    for (step, command) in commands.iter().enumerate() {
+        history.execute_command(command.clone(), &mut ctx);
+        let after = ctx.clone();
+
+        history.undo(&mut ctx);
+        expect(step, Law::UndoRestoresPrevious, &states[step], ctx.clone())?;
+        history.redo(&mut ctx);
+        expect(step, Law::RedoRestoresNext, &after, ctx.clone())?;
+
+        states.push(after);
+    }
+
+    for step in (0..commands.len()).rev() {
+        history.undo(&mut ctx);
+        expect(step, Law::UndoRestoresPrevious, &states[step], ctx.clone())?;
+    }
+    for step in 0..commands.len() {
+        history.redo(&mut ctx);
+        expect(step, Law::RedoRestoresNext, &states[step + 1], ctx.clone())?;
+    }
+
+    Ok(())
+}
+
+fn run_concurrent<C>(
+    initial: &<C::Context as Observable>::State,
+    commands: &[C],
+) -> Result<(), Broken<<C::Context as Observable>::State>>
+where
+    C: Command + Send + Sync + Clone,
+    C::Context: Observable,
+{
+    let limit = NonZeroUsize::new(commands.len()).unwrap_or(NonZeroUsize::MIN);
+    let history = ConcurrentCommandHistory::new(limit, true);
+    let ctx = C::Context::from_state(initial.clone());
+    let mut states = vec![ctx.observe()];
+
+    for (step, command) in commands.iter().enumerate() {
+        history.execute_command(command.clone(), &ctx);
+        let after = ctx.observe();
+
+        history.undo(&ctx);
+        expect(
+            step,
+            Law::UndoRestoresPrevious,
+            &states[step],
+            ctx.observe(),
+        )?;
+        history.redo(&ctx);
+        expect(step, Law::RedoRestoresNext, &after, ctx.observe())?;
+
+        states.push(after);
+    }
+
+    for step in (0..commands.len()).rev() {
+        history.undo(&ctx);
+        expect(
+            step,
+            Law::UndoRestoresPrevious,
+            &states[step],
+            ctx.observe(),
+        )?;
+    }
+    for step in 0..commands.len() {
+        history.redo(&ctx);
+        expect(
+            step,
+            Law::RedoRestoresNext,
+            &states[step + 1],
+            ctx.observe(),
+        )?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Edit {
+        Push(i32),
+        /// Clears the list but only restores its last element when undone.
+        Clear,
+    }
+
+    impl MutableCommand for Edit {
+        type Context = Vec<i32>;
+
+        fn execute(&self, ctx: &mut Self::Context) {
+            match self {
+                Edit::Push(value) => ctx.push(*value),
+                Edit::Clear => ctx.clear(),
+            }
+        }
+
+        fn undo(&self, ctx: &mut Self::Context) {
+            match self {
+                Edit::Push(_) => {
+                    ctx.pop();
+                }
+                Edit::Clear => ctx.push(0),
+            }
+        }
+    }
+
+    impl Command for Edit {
+        type Context = SharedContext<Vec<i32>>;
+
+        fn execute(&self, ctx: &Self::Context) {
+            MutableCommand::execute(self, &mut ctx.lock());
+        }
+
+        fn undo(&self, ctx: &Self::Context) {
+            MutableCommand::undo(self, &mut ctx.lock());
+        }
+    }
+
+    fn push(rng: &mut TestRng) -> Edit {
+        Edit::Push(i32::try_from(rng.below(100)).unwrap())
+    }
+
+    fn push_or_clear(rng: &mut TestRng) -> Edit {
+        if rng.below(4) == 0 {
+            Edit::Clear
+        } else {
+            push(rng)
+        }
+    }
+
+    #[test]
+    fn test_lawful_commands_pass() {
+        LawCheck::new().check_simple(&Vec::new(), push).unwrap();
+        LawCheck::new().check_concurrent(&Vec::new(), push).unwrap();
+    }
+
+    #[test]
+    fn test_violations_are_shrunk() {
+        let violation = LawCheck::new()
+            .check_simple(&Vec::new(), push_or_clear)
+            .unwrap_err();
+        assert_eq!(violation.law, Law::UndoRestoresPrevious);
+        assert_eq!((violation.commands, violation.step), (vec![Edit::Clear], 0));
+        assert_eq!((violation.expected, violation.actual), (vec![], vec![0]));
+
+        let again = LawCheck::new()
+            .seed(violation.seed)
+            .cases(1)
+            .check_concurrent(&Vec::new(), push_or_clear)
+            .unwrap_err();
+        assert_eq!(again.commands, [Edit::Clear]);
+    }
+
+    #[test]
+    fn test_rng_is_deterministic() {
+        let mut first = TestRng::new(7);
+        let mut second = TestRng::new(7);
+        assert!((0..10).all(|_| first.next_u64() == second.next_u64()));
+        assert!((0..100).all(|_| first.below(3) < 3));
+    }
+}
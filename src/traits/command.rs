@@ -18,6 +18,8 @@ use std::borrow::Cow;
 ///
 /// * `redo(&self, ctx: &Self::Context)`: Redoes the command by calling `execute`. This method can be overridden if needed.
 /// * `description(&self) -> Cow<str>`: Returns a description of the command. The default implementation returns "Unknown command".
+/// * `cost(&self) -> usize`: Returns the relative cost of the command. The default implementation returns 1.
+/// * `on_evicted(&self)`, `on_discarded(&self)`, `on_finalized(&self)`: Called when a history drops the command for good, e.g. to release external resources. The default implementations do nothing.
 ///
 /// # Example
 ///
@@ -81,4 +83,31 @@ pub trait Command {
     fn description(&self) -> Cow<'_, str> {
         Cow::Borrowed("Unknown command")
     }
+
+    /// Returns the relative cost of keeping this command in a history. The default implementation returns 1.
+    ///
+    /// Histories use the cost to decide when to take checkpoints, so expensive commands should report a higher value.
+    ///
+    /// # Returns
+    ///
+    /// The cost of the command in arbitrary units.
+    fn cost(&self) -> usize {
+        1
+    }
+
+    /// Called once a history dropped the command to stay within its history limit or retention
+    /// policy, so it can never be undone or redone again. The default implementation does nothing.
+    ///
+    /// Commands written to a spill file are not evicted yet, since they can still be loaded back.
+    fn on_evicted(&self) {}
+
+    /// Called once a history dropped the command for any other reason than its limits: a new
+    /// command cleared the redo stack, the command's scope was aborted, the command or its entry
+    /// panicked while executed or broke the invariant, or the panic policy discarded it. The
+    /// default implementation does nothing.
+    fn on_discarded(&self) {}
+
+    /// Called for every command still held by a history when the history is dropped. The default
+    /// implementation does nothing.
+    fn on_finalized(&self) {}
 }